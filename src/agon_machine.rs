@@ -1,35 +1,339 @@
 use ez80::*;
 use std::collections::HashMap;
 use std::io::{ Seek, SeekFrom, Read, Write };
-use crate::{ mos, debugger, prt_timer, gpio, uart, spi_sdcard };
+use crate::{ mos, debugger, prt_timer, gpio, uart, rtc, spi_sdcard, intel_hex, keyboard };
+use crate::vdp_responder::VdpAutoResponder;
 use std::sync::{ Arc, Mutex };
 use rand::Rng;
-use chrono::{ Datelike, Timelike };
+use chrono::{ Datelike, Timelike, TimeZone };
 
 const ROM_SIZE: usize = 0x20000; // 128 KiB flash
 const EXTERNAL_RAM_SIZE: usize = 0x80000; // 512 KiB
 const ONCHIP_RAM_SIZE: u32 = 0x2000; // 8KiB
+// for the execution digest (64-bit FNV-1a)
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+// in the hostfs root, holding the FAT attributes host files can't
+const FAT_ATTRIB_FILE: &str = ".agon-fat-attributes.json";
+// in unlimited CPU mode, how often start() checks the wall clock. a power of 2
+const WALL_TIME_CHECK_INSTRUCTIONS: u32 = 0x10000;
+// unpolled MachineEvents kept. beyond this the oldest are dropped
+const EVENT_QUEUE_LEN: usize = 1024;
+
+// set_emulator_signature: b"AgEm", then major and minor crate version,
+// at the otherwise unused ports 0xff00-0xff05
+const EMULATOR_SIGNATURE_PORT: u16 = 0xff00;
+const EMULATOR_SIGNATURE_MAGIC: &[u8; 4] = b"AgEm";
 
 pub enum RamInit {
     Zero,
     Random
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    PrtTimer(u8),
+    Uart0Rx,
+    Uart0Tx,
+    Gpio { port: gpio::GpioPort, pin: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterruptSource {
+    pub interrupt: Interrupt,
+    pub vector: u32,
+    pub enabled: bool,
+    // 0 is the highest priority
+    pub priority: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// As MOS soft reset: jump to 0 in ADL mode, leaving all other state as is
+    Warm,
+    /// eZ80 hardware reset: CPU registers, memory map, timers and hostfs
+    /// state return to power-on values. RAM keeps its contents, and the
+    /// UARTs are left for MOS to reinitialise.
+    Cold,
+    /// Power off and on (Console8 power button long-press): as Cold, but
+    /// RAM is reinitialised as by RamInit, and the UARTs and RTC reset too,
+    /// the RTC to host time
+    PowerCycle,
+}
+
+/// Things that happened in the machine, for front-ends to react to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineEvent {
+    Reset(ResetKind),
+    /// The guest has spun within `pc_range` for `cycles` CPU cycles
+    /// without writing to memory or I/O ports, eg polling a status register
+    BusyWait { pc_range: std::ops::RangeInclusive<u32>, cycles: u64 },
+    /// The guest wrote to `addr`, which held an instruction it had executed
+    SelfModifyingCode { addr: u32 },
+    /// The set_triple_fault_detection limit of consecutive instructions
+    /// accessing unmapped memory was reached, at `pc`
+    TripleFault { pc: u32 },
+    /// UART0 transmitted `byte`. Reported by run_events() only
+    Uart0Tx(u8),
+    /// The instruction at `pc` accessed unmapped memory at `addr`.
+    /// Reported by run_events() only
+    MemOutOfBounds { pc: u32, addr: u32 },
+    /// The CPU executed HALT, at `pc`. Reported by run_events() only
+    Halted { pc: u32 },
+    /// The instruction at `pc`, or an interrupt taken there, moved SP to
+    /// `sp`, outside set_stack_bounds()
+    StackOutOfBounds { sp: u32, pc: u32 },
+    /// The instruction at `pc` switched ADL mode, eg a .LIL/.SIS-suffixed
+    /// jump or call, or a return to a mixed-mode caller. Reported while
+    /// set_adl_mode_tracing() is on. `from`/`to` are true for ADL mode
+    AdlModeChanged { from: bool, to: bool, pc: u32 },
+}
+
+/// Called with the PC and the bytes there before each instruction, by
+/// set_fetch_hook(). Returns bytes to execute in their place this once
+pub type FetchHook = Box<dyn FnMut(u32, &[u8]) -> Option<Vec<u8>>>;
+
+/// Settings for set_busy_wait_detection()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusyWaitDetection {
+    // the PC must stay within this many bytes
+    pub max_span: u32,
+    // for this many cycles, to count as a busy-wait
+    pub cycles: u64,
+}
+
+/// What to do on a triple fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripleFaultAction {
+    /// Stop: start() and call_function() return StopReason::TripleFault,
+    /// and boot_to_prompt() BootError::TripleFault
+    Halt,
+    /// Cold reset, as ResetKind::Cold
+    Reset,
+}
+
+/// Requests a reset of the machine it came from (see
+/// AgonMachine::reset_handle()), on any thread
+#[derive(Debug, Clone)]
+pub struct ResetHandle(Arc<std::sync::atomic::AtomicU8>);
+
+impl ResetHandle {
+    /// As AgonMachine::request_reset()
+    pub fn request(&self, kind: ResetKind) {
+        self.0.store(Self::encode(kind), std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn encode(kind: ResetKind) -> u8 {
+        match kind {
+            ResetKind::Warm => 1,
+            ResetKind::Cold => 2,
+            ResetKind::PowerCycle => 3,
+        }
+    }
+
+    fn decode(request: u8) -> Option<ResetKind> {
+        match request {
+            1 => Some(ResetKind::Warm),
+            2 => Some(ResetKind::Cold),
+            3 => Some(ResetKind::PowerCycle),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccessKind {
+    Read,
+    Write,
+}
+
+/// A guest memory access, as kept by the recent accesses log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    // PC of the instruction that made the access
+    pub pc: u32,
+    pub addr: u32,
+    pub value: u8,
+    pub access: MemAccessKind,
+}
+
+/// Why start() returned, or call_function() didn't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Ran for longer than the set_max_wall_time limit
+    WallClockTimeout,
+    /// Triple fault, with TripleFaultAction::Halt
+    TripleFault,
+    /// The routine run by call_function() didn't return within its
+    /// instruction limit
+    InstructionLimit,
+}
+
+/// Why boot_to_prompt (or exec_command) didn't reach the MOS prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootError {
+    /// MOS.bin couldn't be loaded
+    LoadMos(String),
+    /// No prompt within the cycle cap. Holds what MOS printed
+    Timeout { output: String },
+    /// Triple fault, with TripleFaultAction::Halt. Holds what MOS printed
+    TripleFault { output: String },
+}
+
+impl std::fmt::Display for BootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BootError::LoadMos(e) => write!(f, "{}", e),
+            BootError::Timeout { output } => write!(f, "no MOS prompt. Output was: {:?}", output),
+            BootError::TripleFault { output } => write!(f, "triple fault. Output was: {:?}", output),
+        }
+    }
+}
+
+/// Register values to call a routine with (or returned from it), for
+/// call_function. Routines are called in ADL mode, so all register
+/// pairs are 24-bit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegisterSetup {
+    pub a: u8,
+    pub f: u8,
+    pub bc: u32,
+    pub de: u32,
+    pub hl: u32,
+    pub ix: u32,
+    pub iy: u32,
+    pub sp: u32,
+}
+
+impl RegisterSetup {
+    fn apply(&self, reg: &mut Registers) {
+        reg.set8(Reg8::A, self.a);
+        reg.set8(Reg8::F, self.f);
+        reg.set24(Reg16::BC, self.bc);
+        reg.set24(Reg16::DE, self.de);
+        reg.set24(Reg16::HL, self.hl);
+        reg.set24(Reg16::IX, self.ix);
+        reg.set24(Reg16::IY, self.iy);
+        reg.set24(Reg16::SP, self.sp);
+    }
+
+    fn from_registers(reg: &Registers) -> RegisterSetup {
+        RegisterSetup {
+            a: reg.get8(Reg8::A),
+            f: reg.get8(Reg8::F),
+            bc: reg.get24(Reg16::BC),
+            de: reg.get24(Reg16::DE),
+            hl: reg.get24(Reg16::HL),
+            ix: reg.get24(Reg16::IX),
+            iy: reg.get24(Reg16::IY),
+            sp: reg.get24(Reg16::SP),
+        }
+    }
+}
+
+/// Host-side view of a file or directory on the hostfs sdcard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuestFileInfo {
+    pub size: u64,
+    pub is_dir: bool,
+    // FILINFO fattrib, fdate and ftime, as f_stat would report them
+    pub attrib: u8,
+    pub fdate: u16,
+    pub ftime: u16,
+    // None where the host filesystem doesn't track these
+    pub modified: Option<std::time::SystemTime>,
+    pub accessed: Option<std::time::SystemTime>,
+}
+
 pub struct AgonMachine {
     mem_external: [u8; EXTERNAL_RAM_SIZE], // 512k external RAM
     mem_rom: [u8; ROM_SIZE], // 128K ROM
     mem_internal: [u8; ONCHIP_RAM_SIZE as usize],  // 8K SRAM on the EZ80F92
     uart0: uart::Uart,
     uart1: uart::Uart,
+    rtc: rtc::Rtc,
     spi_sdcard: spi_sdcard::SpiSdcard,
     // map from MOS fatfs FIL struct ptr to rust File handle
-    open_files: HashMap<u32, std::fs::File>,
+    open_files: HashMap<u32, OpenFile>,
+    file_locking: bool,
     open_dirs: HashMap<u32, std::fs::ReadDir>,
+    max_open_dirs: Option<usize>,
     enable_hostfs: bool,
     mos_map: mos::MosMap,
+    mos_log: Option<(mos::MosLogMode, mos::MosLogSink)>,
+    mos_call_stats: HashMap<mos::MosCall, mos::CallStat>,
+    mos_call_log: Option<Vec<mos::MosCallRecord>>,
+    f_gets_config: mos::FGetsConfig,
+    // as FatFS's FF_USE_LFN. without, created names must be 8.3
+    long_file_names: bool,
+    mos_flash_signatures: Vec<mos::FlashSignature>,
+    update_access_time: bool,
+    // fsync host files after every f_write
+    write_through: bool,
+    // cycles to accumulate before ticking peripherals
+    tick_granularity: u32,
+    pending_ticks: u32,
+    sdcard_inserted: bool,
+    sdcard_write_protect: bool,
+    // GPIO inputs wired to the card-detect and write-protect switches
+    sdcard_detect_pin: Option<(gpio::GpioPort, u8)>,
+    sdcard_write_protect_pin: Option<(gpio::GpioPort, u8)>,
+    // keyed on upper-cased command name
+    star_commands: HashMap<String, mos::StarCommandHandler>,
     hostfs_root_dir: std::path::PathBuf,
+    // when set, hostfs_root_dir is its scratch copy
+    hostfs_overlay: Option<HostfsOverlay>,
     mos_current_dir: MosPath,
+    // reused by mos_path_arg
+    path_buffers: Vec<Vec<u8>>,
     soft_reset: Arc<std::sync::atomic::AtomicBool>,
+    // a ResetHandle's request, as ResetHandle::encode, or 0 for none
+    pending_reset: Arc<std::sync::atomic::AtomicU8>,
+    // left at the MOS prompt by boot_to_prompt or exec_command, and not
+    // reset since
+    at_mos_prompt: bool,
+    events: std::collections::VecDeque<MachineEvent>,
+    // dropped from a full events queue
+    events_dropped: u64,
+    // (max PC span in bytes, cycles before reporting)
+    busy_wait_detection: Option<BusyWaitDetection>,
+    busy_wait: Option<BusyWait>,
+    // set by any memory or port write during the current instruction
+    guest_wrote: bool,
+    // FNV-1a hash of PC and registers after each instruction, when enabled
+    execution_digest: Option<u64>,
+    // bitmap of executed instruction bytes, when detecting self-modifying code
+    code_bytes: Option<Vec<u64>>,
+    fetch_hook: Option<FetchHook>,
+    // (PC, bytes) the fetch hook gave for the executing instruction, read
+    // in place of memory. None once the instruction writes over one
+    fetch_replacement: Option<(u32, Vec<Option<u8>>)>,
+    // PC of the instruction executing, and how many bytes from it were read
+    fetch_pc: std::cell::Cell<Option<u32>>,
+    fetch_len: std::cell::Cell<u32>,
+    // the most recent memory accesses, up to access_log_len, when enabled
+    access_log: Option<std::cell::RefCell<std::collections::VecDeque<MemAccess>>>,
+    access_log_len: usize,
+    // hidden, system and archive bits set by f_chmod, by path relative to
+    // hostfs_root_dir. loaded from FAT_ATTRIB_FILE when first needed
+    fat_attributes: Option<HashMap<String, u8>>,
+    // (consecutive faulting instructions allowed, action after that)
+    triple_fault_detection: Option<(u32, TripleFaultAction)>,
+    consecutive_faults: u32,
+    // (low, high) SP must stay within, and whether it is outside now
+    stack_bounds: Option<(u32, u32)>,
+    stack_out_of_bounds: bool,
+    adl_mode_tracing: bool,
+    // set by unmapped memory accesses, for triple fault detection
+    instruction_faulted: std::cell::Cell<bool>,
+    // guest-visible signature at EMULATOR_SIGNATURE_PORT
+    emulator_signature: bool,
+    // halted by TripleFaultAction::Halt, until a cold reset
+    triple_fault_halt: bool,
+    // real time start() may run for
+    max_wall_time: Option<std::time::Duration>,
+    // patched address -> the bytes it replaced
+    patches: std::collections::BTreeMap<u32, Vec<u8>>,
     clockspeed_hz: u64,
     prt_timers: [prt_timer::PrtTimer; 6],
     gpios: Arc<Mutex<gpio::GpioSet>>,
@@ -42,17 +346,166 @@ pub struct AgonMachine {
     flash_addr_u: u8,
     cs0_lbr: u8,
     cs0_ubr: u8,
+    cs0_ctl: u8,
+    // charge external RAM accesses the CS0_CTL wait states
+    memory_wait_states: bool,
+    // CS1-3 LBR, UBR, CTL. nothing is wired to these chip selects
+    cs1_3_regs: [u8; 9],
 
     // last_pc and mem_out_of_bounds are used by the debugger
     pub last_pc: u32,
     pub mem_out_of_bounds: std::cell::Cell<Option<u32>>, // address
+    // the first access to hit a watchpoint, until the debugger clears it
+    pub watchpoint_hit: std::cell::Cell<Option<MemAccess>>,
+    watchpoints: Vec<debugger::Watchpoint>,
     pub paused: bool,
     pub cycle_counter: std::cell::Cell<u32>
 }
 
+// candidate busy-wait loop: the PCs seen so far and cycles spent there
+struct BusyWait {
+    lo: u32,
+    hi: u32,
+    cycles: u64,
+    reported: bool,
+}
+
+struct OpenFile {
+    file: std::fs::File,
+    // canonical, so different guest paths to the same file compare equal
+    host_path: std::path::PathBuf,
+    writable: bool,
+    // FA_* flags it was opened with
+    mode: u32,
+}
+
+// a scratch copy of the sdcard directory, in the host temp directory,
+// for hostfs to change instead. removed on drop
+struct HostfsOverlay {
+    // the real sdcard directory
+    base: std::path::PathBuf,
+    scratch: std::path::PathBuf,
+}
+
+const HOSTFS_OVERLAY_PREFIX: &str = ".agon-overlay-";
+
+impl HostfsOverlay {
+    fn new(base: &std::path::Path) -> std::io::Result<Self> {
+        static COUNT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        static STALE_REMOVED: std::sync::Once = std::sync::Once::new();
+        STALE_REMOVED.call_once(HostfsOverlay::remove_stale);
+
+        let n = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let scratch = std::env::temp_dir().join(format!("{}{}-{}", HOSTFS_OVERLAY_PREFIX, std::process::id(), n));
+        std::fs::create_dir(&scratch)?;
+        // if the copy fails part way, dropping the overlay removes it
+        let overlay = HostfsOverlay { base: base.to_path_buf(), scratch };
+        copy_tree(&overlay.base, &overlay.scratch)?;
+        Ok(overlay)
+    }
+
+    // remove the scratch copies left by emulators that exited without
+    // dropping their overlay. best effort
+    fn remove_stale() {
+        let Ok(dir) = std::fs::read_dir(std::env::temp_dir()) else { return };
+        for entry in dir.flatten() {
+            let name = entry.file_name();
+            let Some(pid) = name.to_str()
+                .and_then(|name| name.strip_prefix(HOSTFS_OVERLAY_PREFIX))
+                .and_then(|rest| rest.split('-').next())
+                .and_then(|pid| pid.parse::<u32>().ok()) else { continue };
+            if pid != std::process::id() && !HostfsOverlay::owner_may_be_running(pid, &entry) {
+                let _ = std::fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn owner_may_be_running(pid: u32, _entry: &std::fs::DirEntry) -> bool {
+        std::path::Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    // no portable liveness check, so only copies a day old count as stale
+    #[cfg(not(target_os = "linux"))]
+    fn owner_may_be_running(_pid: u32, entry: &std::fs::DirEntry) -> bool {
+        let age = entry.metadata().and_then(|m| m.modified()).ok()
+            .and_then(|modified| modified.elapsed().ok());
+        !age.is_some_and(|age| age > std::time::Duration::from_secs(24 * 60 * 60))
+    }
+}
+
+impl Drop for HostfsOverlay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.scratch);
+    }
+}
+
 // a path relative to the hostfs_root_dir
 pub struct MosPath(std::path::PathBuf);
 
+// MOS prompts with the current directory and '*', eg "/ *"
+fn is_mos_prompt(output: &[u8]) -> bool {
+    let line = output.rsplit(|b| *b == b'\n' || *b == b'\r').next().unwrap_or(&[]);
+    line == b"*" || line.ends_with(b" *")
+}
+
+
+// FatFS (date, time) encoding of the host mtime, in local time
+fn fat_date_time(metadata: &std::fs::Metadata) -> (u16, u16) {
+    let mut fil_date = 0u16;
+    let mut fil_time = 0u16;
+
+    let raw_mtime = filetime::FileTime::from_last_modification_time(metadata);
+    if let Some(utc_mtime) = chrono::DateTime::from_timestamp(raw_mtime.unix_seconds(), 0) {
+        let local_mtime = utc_mtime.with_timezone(&chrono::Local::now().timezone());
+        fil_date |= ((local_mtime.year() - 1980) << 9) as u16;
+        fil_date |= ((local_mtime.month()) << 5) as u16;
+        fil_date |= local_mtime.day() as u16;
+        fil_time |= (local_mtime.hour() << 11) as u16;
+        fil_time |= (local_mtime.minute() << 5) as u16;
+        // 2 second resolution
+        fil_time |= (local_mtime.second() / 2) as u16;
+    }
+    (fil_date, fil_time)
+}
+
+// the host time for a FatFS (date, time), taken as local time
+fn fat_date_time_to_file_time(fdate: u16, ftime: u16) -> Option<filetime::FileTime> {
+    let local = chrono::Local.with_ymd_and_hms(
+        1980 + (fdate >> 9) as i32, ((fdate >> 5) & 0xf) as u32, (fdate & 0x1f) as u32,
+        (ftime >> 11) as u32, ((ftime >> 5) & 0x3f) as u32, (ftime & 0x1f) as u32 * 2
+    ).earliest()?;
+    Some(filetime::FileTime::from_unix_time(local.timestamp(), 0))
+}
+
+// CPU cycles per RTC second. unlimited CPU mode (u64::MAX) still counts
+// emulated time at the Agon's 18.432MHz
+fn rtc_cycles_per_second(clockspeed_hz: u64) -> u64 {
+    if clockspeed_hz == u64::MAX { 18_432_000 } else { clockspeed_hz }
+}
+
+// copy the host directory `src` to `dest`, recursively, keeping mtimes.
+// symlinks to files are copied as files; others are skipped, so a link
+// loop can't recurse forever
+fn copy_tree(src: &std::path::Path, dest: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let to = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &to)?;
+        } else if file_type.is_symlink() && !std::fs::metadata(entry.path()).is_ok_and(|m| m.is_file()) {
+            continue;
+        } else {
+            std::fs::copy(entry.path(), &to)?;
+            filetime::set_file_mtime(&to, filetime::FileTime::from_last_modification_time(&std::fs::metadata(entry.path())?))?;
+        }
+    }
+    // after the contents, which would otherwise update it
+    filetime::set_file_mtime(dest, filetime::FileTime::from_last_modification_time(&std::fs::metadata(src)?))
+}
+
 impl Machine for AgonMachine {
     #[inline]
     fn use_cycles(&self, cycles: u32) {
@@ -60,33 +513,68 @@ impl Machine for AgonMachine {
     }
 
     fn peek(&self, address: u32) -> u8 {
-        if let Some(onchip_ram_addr) = self.get_internal_ram_address(address) {
+        if let Some(pc) = self.fetch_pc.get() {
+            self.note_fetch(pc, address);
+        }
+        let replaced = if self.fetch_replacement.is_some() { self.fetch_replacement_at(address) } else { None };
+        let value = if let Some(onchip_ram_addr) = self.get_internal_ram_address(address) {
             self.use_cycles(1);
             self.mem_internal[onchip_ram_addr as usize]
         } else if let Some(rom_addr) = self.get_rom_address(address) {
             self.use_cycles(2);
             self.mem_rom[rom_addr as usize]
         } else if let Some(ram_addr) = self.get_external_ram_address(address) {
-            self.use_cycles(1);
+            self.use_cycles(1 + self.external_ram_wait_states());
             self.mem_external[ram_addr as usize]
         } else {
             self.use_cycles(1);
-            self.mem_out_of_bounds.set(Some(address));
+            if replaced.is_none() {
+                self.mem_out_of_bounds.set(Some(address));
+                self.instruction_faulted.set(true);
+            }
             0xf5
+        };
+        let value = replaced.unwrap_or(value);
+        if self.access_log.is_some() {
+            self.log_access(address, value, MemAccessKind::Read);
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, MemAccessKind::Read);
         }
+        value
     }
 
     fn poke(&mut self, address: u32, value: u8) {
-        self.use_cycles(1);
-
+        self.guest_wrote = true;
+        if let Some((pc, bytes)) = &mut self.fetch_replacement {
+            // from now on the instruction reads what it wrote
+            if let Some(b) = bytes.get_mut((address.wrapping_sub(*pc) & 0xffffff) as usize) {
+                *b = None;
+            }
+        }
+        // only the CPU modifies code: hostfs and loaders write between instructions
+        if self.code_bytes.is_some() && self.fetch_pc.get().is_some() {
+            self.check_code_write(address);
+        }
+        if self.access_log.is_some() {
+            self.log_access(address, value, MemAccessKind::Write);
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, MemAccessKind::Write);
+        }
         if let Some(onchip_ram_addr) = self.get_internal_ram_address(address) {
+            self.use_cycles(1);
             self.mem_internal[onchip_ram_addr as usize] = value;
         } else if let Some(rom_addr) = self.get_rom_address(address) {
+            self.use_cycles(1);
             self.mem_rom[rom_addr as usize] = value;
         } else if let Some(ram_addr) = self.get_external_ram_address(address) {
+            self.use_cycles(1 + self.external_ram_wait_states());
             self.mem_external[ram_addr as usize] = value;
         } else {
+            self.use_cycles(1);
             self.mem_out_of_bounds.set(Some(address));
+            self.instruction_faulted.set(true);
         }
     }
 
@@ -142,6 +630,12 @@ impl Machine for AgonMachine {
             0xa4 => { let gpios = self.gpios.lock().unwrap(); gpios.d.get_alt1() }
             0xa5 => { let gpios = self.gpios.lock().unwrap(); gpios.d.get_alt2() }
 
+            // chip selects
+            0xa8 => self.cs0_lbr,
+            0xa9 => self.cs0_ubr,
+            0xaa => self.cs0_ctl,
+            0xab..=0xb3 => self.cs1_3_regs[(address - 0xab) as usize],
+
             0xb4 => {
                 if self.onchip_mem_enable { 0x80 } else { 0 }
             }
@@ -168,20 +662,8 @@ impl Machine for AgonMachine {
                 self.spi_sdcard.send_byte().unwrap_or(0xff)
             }
 
-            0xc0 => {
-                if self.uart0.is_access_brg_registers() {
-                    self.uart0.brg_div as u8
-                } else {
-                    self.uart0.receive_byte()
-                }
-            }
-            0xc1 => {
-                if self.uart0.is_access_brg_registers() {
-                    (self.uart0.brg_div >> 8) as u8
-                } else {
-                    self.uart0.ier
-                }
-            }
+            0xc0 => self.uart0.read_rbr_or_brg_low(),
+            0xc1 => self.uart0.read_ier_or_brg_high(),
             0xc2 => {
                 // UART0_IIR
                 if self.uart0.ier & 0x02 != 0 {    
@@ -198,20 +680,8 @@ impl Machine for AgonMachine {
             0xc7 => self.uart0.spr,
 
             /* uart1 is kindof useless in the emulator, but ... */
-            0xd0 => {
-                if self.uart1.is_access_brg_registers() {
-                    self.uart1.brg_div as u8
-                } else {
-                    self.uart1.receive_byte()
-                }
-            }
-            0xd1 => {
-                if self.uart1.is_access_brg_registers() {
-                    (self.uart1.brg_div >> 8) as u8
-                } else {
-                    self.uart1.ier
-                }
-            }
+            0xd0 => self.uart1.read_rbr_or_brg_low(),
+            0xd1 => self.uart1.read_ier_or_brg_high(),
             0xd2 => {
                 // UART1_IIR
                 if self.uart1.ier & 0x02 != 0 {    
@@ -227,8 +697,18 @@ impl Machine for AgonMachine {
             0xd6 => self.uart1.read_modem_status_register(),
             0xd7 => self.uart1.spr,
 
+            0xe0..=0xed => self.rtc.read((address - 0xe0) as u8),
+
             0xf7 => self.flash_addr_u,
 
+            EMULATOR_SIGNATURE_PORT..=0xff05 if self.emulator_signature => {
+                match (address - EMULATOR_SIGNATURE_PORT) as usize {
+                    i @ 0..=3 => EMULATOR_SIGNATURE_MAGIC[i],
+                    4 => env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                    _ => env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                }
+            }
+
             _ => {
                 //println!("IN({:02X})", address);
                 0
@@ -237,6 +717,7 @@ impl Machine for AgonMachine {
     }
     fn port_out(&mut self, address: u16, value: u8) {
         self.use_cycles(1);
+        self.guest_wrote = true;
         match address {
             0x80 => self.prt_timers[0].write_ctl(value),
             0x81 => self.prt_timers[0].write_reload_low(value),
@@ -280,16 +761,9 @@ impl Machine for AgonMachine {
             // chip selects
             0xa8 => self.cs0_lbr = value,
             0xa9 => self.cs0_ubr = value,
-            0xaa => {} // CS0_CTL
-            0xab => {} // CS1_LBR
-            0xac => {} // CS1_UBR
-            0xad => {} // CS1_CTL
-            0xae => {} // CS2_LBR
-            0xaf => {} // CS2_UBR
-            0xb0 => {} // CS2_CTL
-            0xb1 => {} // CS3_LBR
-            0xb2 => {} // CS3_UBR
-            0xb3 => {} // CS3_CTL
+            0xaa => self.cs0_ctl = value,
+            // CS1_LBR, CS1_UBR, CS1_CTL .. CS3_CTL
+            0xab..=0xb3 => self.cs1_3_regs[(address - 0xab) as usize] = value,
 
             0xb4 => {
                 self.onchip_mem_enable = value & 0x80 != 0;
@@ -316,49 +790,21 @@ impl Machine for AgonMachine {
             }
 
             /* UART0_REG_THR - send data to VDP */
-            0xc0 => {
-                if self.uart0.is_access_brg_registers() {
-                    self.uart0.brg_div &= 0xff00;
-                    self.uart0.brg_div |= value as u16;
-                } else {
-                    self.uart0.send_byte(value);
-                }
-            }
-            0xc1 => {
-                if self.uart0.is_access_brg_registers() {
-                    self.uart0.brg_div &= 0xff;
-                    self.uart0.brg_div |= (value as u16) << 8;
-                } else {
-                    //println!("setting uart ier: 0x{:x}", value);
-                    self.uart0.ier = value;
-                }
-            }
+            0xc0 => self.uart0.write_thr_or_brg_low(value),
+            0xc1 => self.uart0.write_ier_or_brg_high(value),
             0xc2 => self.uart0.write_fctl(value),
             0xc3 => self.uart0.lctl = value,
             0xc7 => self.uart0.spr = value,
 
             /* uart1 is kindof useless in the emulator, but ... */
-            0xd0 => {
-                if self.uart1.is_access_brg_registers() {
-                    self.uart1.brg_div &= 0xff00;
-                    self.uart1.brg_div |= value as u16;
-                } else {
-                    self.uart1.send_byte(value);
-                }
-            }
-            0xd1 => {
-                if self.uart1.is_access_brg_registers() {
-                    self.uart1.brg_div &= 0xff;
-                    self.uart1.brg_div |= (value as u16) << 8;
-                } else {
-                    //println!("setting uart ier: 0x{:x}", value);
-                    self.uart1.ier = value;
-                }
-            }
+            0xd0 => self.uart1.write_thr_or_brg_low(value),
+            0xd1 => self.uart1.write_ier_or_brg_high(value),
             0xd2 => self.uart1.write_fctl(value),
             0xd3 => self.uart1.lctl = value,
             0xd7 => self.uart1.spr = value,
 
+            0xe0..=0xed => self.rtc.write((address - 0xe0) as u8, value),
+
             0xf7 => self.flash_addr_u = value,
 
             _ => {
@@ -386,14 +832,60 @@ impl AgonMachine {
             mem_internal: [0; ONCHIP_RAM_SIZE as usize],
             uart0: uart::Uart::new(config.uart0_link),
             uart1: uart::Uart::new(config.uart1_link),
+            rtc: rtc::Rtc::new(rtc_cycles_per_second(config.clockspeed_hz)),
             spi_sdcard: spi_sdcard::SpiSdcard::new(),
             open_files: HashMap::new(),
+            file_locking: false,
             open_dirs: HashMap::new(),
+            max_open_dirs: None,
             enable_hostfs: true,
             mos_map: mos::MosMap::default(),
+            mos_log: None,
+            mos_call_stats: HashMap::new(),
+            mos_call_log: None,
+            f_gets_config: mos::FGetsConfig::default(),
+            long_file_names: true,
+            mos_flash_signatures: vec![],
+            update_access_time: false,
+            write_through: false,
+            tick_granularity: 1,
+            pending_ticks: 0,
+            busy_wait_detection: None,
+            busy_wait: None,
+            guest_wrote: false,
+            execution_digest: None,
+            patches: std::collections::BTreeMap::new(),
+            code_bytes: None,
+            fetch_hook: None,
+            fetch_replacement: None,
+            access_log: None,
+            max_wall_time: None,
+            triple_fault_detection: None,
+            consecutive_faults: 0,
+            stack_bounds: None,
+            stack_out_of_bounds: false,
+            adl_mode_tracing: false,
+            instruction_faulted: std::cell::Cell::new(false),
+            triple_fault_halt: false,
+            emulator_signature: false,
+            fat_attributes: None,
+            access_log_len: 0,
+            fetch_pc: std::cell::Cell::new(None),
+            fetch_len: std::cell::Cell::new(0),
+            sdcard_inserted: true,
+            sdcard_write_protect: false,
+            sdcard_detect_pin: None,
+            sdcard_write_protect_pin: None,
+            star_commands: HashMap::new(),
             hostfs_root_dir: std::env::current_dir().unwrap(),
+            hostfs_overlay: None,
             mos_current_dir: MosPath(std::path::PathBuf::new()),
+            path_buffers: vec![],
             soft_reset: config.soft_reset,
+            pending_reset: Arc::new(std::sync::atomic::AtomicU8::new(0)),
+            at_mos_prompt: false,
+            events: std::collections::VecDeque::new(),
+            events_dropped: 0,
             clockspeed_hz: config.clockspeed_hz,
             prt_timers: [
                 prt_timer::PrtTimer::new(),
@@ -407,6 +899,8 @@ impl AgonMachine {
             ram_init: config.ram_init,
             last_pc: 0,
             mem_out_of_bounds: std::cell::Cell::new(None),
+            watchpoint_hit: std::cell::Cell::new(None),
+            watchpoints: vec![],
             cycle_counter: std::cell::Cell::new(0),
             paused: false,
             mos_bin: config.mos_bin,
@@ -414,7 +908,11 @@ impl AgonMachine {
             onchip_mem_segment: 0xff,
             flash_addr_u: 0,
             cs0_lbr: 0,
-            cs0_ubr: 0xff
+            cs0_ubr: 0xff,
+            // reset value: 7 wait states, chip select enabled
+            cs0_ctl: 0xe8,
+            memory_wait_states: false,
+            cs1_3_regs: [0; 9],
         }
     }
 
@@ -431,6 +929,23 @@ impl AgonMachine {
         if offset < ONCHIP_RAM_SIZE { Some(offset) } else { None }
     }
 
+    /**
+     * Charge each external RAM access the wait states programmed in
+     * CS0_CTL, as the real eZ80 inserts them. Off by default, so timing
+     * is unchanged from before the register was modelled: its reset value
+     * asks for 7 wait states, which would slow everything until MOS
+     * reprograms CS0.
+     */
+    pub fn set_memory_wait_states(&mut self, enable: bool) {
+        self.memory_wait_states = enable;
+    }
+
+    // CS0_CTL bits 7-5: number of wait states inserted on each access
+    #[inline]
+    fn external_ram_wait_states(&self) -> u32 {
+        if self.memory_wait_states { (self.cs0_ctl >> 5) as u32 } else { 0 }
+    }
+
     #[inline]
     fn get_external_ram_address(&self, address: u32) -> Option<u32> {
         if address >> 16 >= self.cs0_lbr as u32 && address >> 16 <= self.cs0_ubr as u32 {
@@ -441,8 +956,14 @@ impl AgonMachine {
         }
     }
 
+    /**
+     * Serve hostfs from the host directory `path`. An sdcard overlay is
+     * discarded, and must be enabled again for the new directory.
+     */
     pub fn set_sdcard_directory(&mut self, path: std::path::PathBuf) {
+        self.hostfs_overlay = None;
         self.hostfs_root_dir = path;
+        self.fat_attributes = None;
     }
 
     pub fn set_sdcard_image(&mut self, file: Option<std::fs::File>) {
@@ -450,127 +971,652 @@ impl AgonMachine {
         self.spi_sdcard.set_image_file(file);
     }
 
-    fn load_mos(&mut self) {
-        let code = match std::fs::read(&self.mos_bin) {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("Error opening {}: {:?}", self.mos_bin.display(), e);
-                std::process::exit(-1);
-            }
+    /**
+     * Keep SD card changes out of the real SD card. Image writes are kept
+     * in memory. Hostfs writes, creates, deletes and renames go to a
+     * scratch copy of the sdcard directory, made in the host temp
+     * directory when enabled. Disabling (or dropping the machine)
+     * discards the changes; files the guest still has open stay open on
+     * the discarded copy. Copies left by an emulator that didn't exit
+     * cleanly are removed when the next overlay is made. Errors if the
+     * hostfs copy can't be made.
+     */
+    pub fn set_sdcard_overlay(&mut self, enable: bool) -> std::io::Result<()> {
+        self.spi_sdcard.set_overlay(enable);
+        let base = match self.hostfs_overlay.take() {
+            Some(overlay) => overlay.base.clone(),
+            None => self.hostfs_root_dir.clone()
         };
-        
-        for (i, e) in code.iter().enumerate() {
-            self.mem_rom[i] = *e;
+        self.hostfs_root_dir = base;
+        self.fat_attributes = None;
+        if enable {
+            let overlay = HostfsOverlay::new(&self.hostfs_root_dir)?;
+            self.hostfs_root_dir = overlay.scratch.clone();
+            self.hostfs_overlay = Some(overlay);
         }
+        Ok(())
+    }
 
-        let mos_map = self.mos_bin.with_extension("map");
 
-        match crate::symbol_map::read_zds_map_file(mos_map.to_str().unwrap()) {
-            Ok(map) => {
-                match mos::MosMap::from_symbol_map(map) {
-                    Ok(mos_map) => {
-                        self.mos_map = mos_map;
-                    }
-                    Err(e) => {
-                        println!("Error reading {}. hostfs integration disabled: {}", mos_map.display(), e);
-                        self.enable_hostfs = false;
-                    }
-                }
-            }
-            Err(e) => {
-                println!("Error reading {}. hostfs integration disabled: {}", mos_map.display(), e);
-                self.enable_hostfs = false;
-            }
+    /**
+     * Handle the MOS star command `name` (case-insensitive, without the
+     * '*') on the host, instead of letting MOS run it. Requires _mos_exec
+     * in the MOS symbol map. Passing None removes the handler.
+     */
+    pub fn set_star_command(&mut self, name: &str, handler: Option<mos::StarCommandHandler>) {
+        match handler {
+            Some(handler) => { self.star_commands.insert(name.to_ascii_uppercase(), handler); }
+            None => { self.star_commands.remove(&name.to_ascii_uppercase()); }
         }
     }
 
-    fn hostfs_mos_f_getlabel(&mut self, cpu: &mut Cpu) {
-        let mut buf = self._peek24(cpu.state.sp() + 6);
-        let label = "hostfs";
-        for b in label.bytes() {
-            self.poke(buf, b);
-            buf += 1;
-        }
-        self.poke(buf, 0);
+    /**
+     * At entry to mos_exec(char *buffer, ...). Returns true if the command
+     * was handled on the host, in which case mos_exec has been returned from.
+     */
+    fn host_star_command(&mut self, cpu: &mut Cpu) -> bool {
+        let line = {
+            let ptr = self._peek24(cpu.state.sp() + 3);
+            String::from_utf8_lossy(&mos::get_mos_path_string(self, ptr)).into_owned()
+        };
+        let Some((name, args)) = mos::parse_star_command(&line) else { return false };
+        let Some(handler) = self.star_commands.get_mut(&name.to_ascii_uppercase()) else { return false };
 
-        // success
-        cpu.state.reg.set24(Reg16::HL, 0); // success
+        let result = handler(args);
+        cpu.state.reg.set24(Reg16::HL, result);
+        Environment::new(&mut cpu.state, self).subroutine_return();
+        true
+    }
 
-        let mut env = Environment::new(&mut cpu.state, self);
-        env.subroutine_return();
+    /**
+     * True if the guest can't currently write to the UART0 transmit fifo
+     * without overflowing it. Front-ends bridging UART0 can use this to
+     * tell when the host side is not keeping up.
+     */
+    pub fn uart0_tx_fifo_full(&self) -> bool {
+        self.uart0.tx_fifo_full()
     }
 
-    fn hostfs_mos_f_getcwd(&mut self, cpu: &mut Cpu) {
-        let mut buf = self._peek24(cpu.state.sp() + 3);
-        let cwd = self.mos_current_dir.0.clone();
-        self.poke(buf, 47); // leading slash
-        buf += 1;
-        for b in cwd.to_str().unwrap_or("").bytes() {
-            self.poke(buf, b);
-            buf += 1;
-        }
-        self.poke(buf, 0);
+    /**
+     * Number of bytes written to UART0 while its transmit fifo was full,
+     * and not taken by the link's on_tx_overflow.
+     */
+    pub fn uart0_tx_dropped(&self) -> u64 {
+        self.uart0.tx_dropped()
+    }
 
-        // success
-        cpu.state.reg.set24(Reg16::HL, 0); // success
+    /**
+     * Number of bytes UART0 lost to its link's send() panicking. The
+     * panic is caught, so a failing host bridge doesn't stop the emulator.
+     */
+    pub fn uart0_tx_send_failures(&self) -> u64 {
+        self.uart0.tx_send_failures()
+    }
 
-        let mut env = Environment::new(&mut cpu.state, self);
-        env.subroutine_return();
+    /**
+     * UART0 (`uart` 0, ports 0xc0-0xc7) or UART1 (1, ports 0xd0-0xd7)
+     * registers as (name, offset, value, description), as Uart::register_map.
+     */
+    pub fn uart_register_map(&self, uart: u8) -> Vec<(&'static str, u8, u8, &'static str)> {
+        if uart == 0 { self.uart0.register_map() } else { self.uart1.register_map() }
     }
 
-    fn hostfs_mos_f_close(&mut self, cpu: &mut Cpu) {
-        let fptr = self._peek24(cpu.state.sp() + 3);
-        //eprintln!("f_close(${:x})", fptr);
+    pub fn set_f_gets_config(&mut self, config: mos::FGetsConfig) {
+        self.f_gets_config = config;
+    }
 
-        // closes on Drop
-        self.open_files.remove(&fptr);
+    /**
+     * Match a MOS whose FatFS is built without long file name support:
+     * creating files and directories (or renaming to) names that aren't
+     * 8.3 fails with FR_INVALID_NAME. On by default.
+     */
+    pub fn set_long_file_names(&mut self, enable: bool) {
+        self.long_file_names = enable;
+    }
 
-        // success
-        cpu.state.reg.set24(Reg16::HL, 0);
+    // whether the last component of `mos_path` may be created
+    fn creatable_name(&self, mos_path: &str) -> bool {
+        self.long_file_names || mos::is_valid_sfn(mos_path.rsplit(['/', '\\']).next().unwrap_or(""))
+    }
 
-        Environment::new(&mut cpu.state, self).subroutine_return();
+    /**
+     * Append everything MOS transmits on UART0 to the host file at `path`,
+     * whatever the serial link does with it.
+     */
+    pub fn log_uart_tx_to_file(&mut self, path: &std::path::Path) -> std::io::Result<()> {
+        let file = std::fs::File::options().create(true).append(true).open(path)?;
+        self.uart0.set_tx_log(Some(file));
+        Ok(())
     }
 
-    fn hostfs_mos_f_gets(&mut self, cpu: &mut Cpu) {
-        let buf = self._peek24(cpu.state.sp() + 3);
-        let max_len = self._peek24(cpu.state.sp() + 6);
-        let fptr = self._peek24(cpu.state.sp() + 9);
-        //eprintln!("f_gets(buf: ${:x}, len: ${:x}, fptr: ${:x})", buf, max_len, fptr);
+    /**
+     * Set what the guest reads from an empty UART receive buffer, on
+     * both UARTs. The default is zero.
+     */
+    pub fn set_uart_empty_rx_read(&mut self, mode: uart::EmptyRxRead) {
+        self.uart0.set_empty_rx_read(mode);
+        self.uart1.set_empty_rx_read(mode);
+    }
 
-        let fresult = 'outer: {
-            if let Some(mut f) = self.open_files.get(&fptr) {
-                let mut line = vec![];
-                let mut host_buf = vec![0; 1];
-                for _ in 0..max_len {
-                    let n_read = match f.read(host_buf.as_mut_slice()) {
-                        Ok(n_read) => n_read,
-                        Err(_) => break 'outer mos::FR_DISK_ERR
-                    };
+    /**
+     * Have UART0 report `kind` of errors in its LSR with the next byte
+     * it receives, for testing the guest serial driver's error handling.
+     */
+    pub fn inject_malformed_frame(&mut self, kind: uart::FrameErrors) {
+        self.uart0.inject_malformed_frame(kind);
+    }
 
-                    if n_read == 0 {
-                        break 'outer mos::FR_DISK_ERR // EOF
-                    }
-                    line.push(host_buf[0]);
+    /**
+     * Let guest programs detect the emulator: ports 0xff00-0xff03 read
+     * "AgEm", and 0xff04 and 0xff05 the emulator's major and minor
+     * version. Off by default, when they read 0 like other unused ports.
+     */
+    pub fn set_emulator_signature(&mut self, enable: bool) {
+        self.emulator_signature = enable;
+    }
 
-                    if host_buf[0] == 10 || host_buf[0] == 0 { break; }
-                }
-                // no f.tell()...
-                let fpos = match f.seek(SeekFrom::Current(0)) {
-                    Ok(n) => n,
-                    Err(_) => break 'outer mos::FR_DISK_ERR
-                };
+    /**
+     * Treat `limit` consecutive instructions that access unmapped memory
+     * (eg executing it, which would otherwise run away) as a triple fault:
+     * report MachineEvent::TripleFault and take `action`. None disables.
+     */
+    pub fn set_triple_fault_detection(&mut self, detect: Option<(u32, TripleFaultAction)>) {
+        self.triple_fault_detection = detect;
+        self.consecutive_faults = 0;
+    }
 
-                // save file position to FIL.fptr U32
-                self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32);
-                let mut buf2 = buf;
-                for b in line {
-                    self.poke(buf2, b);
-                    buf2 += 1;
-                }
-                self.poke(buf2, 0);
+    /**
+     * Report MachineEvent::StackOutOfBounds when an instruction (a push,
+     * pop, call, ret or anything else changing SP) moves SP outside
+     * `low..=high`, catching overflow and underflow wherever the stack is.
+     * In Z80 mode SP is MBASE:SPS; switches between SPL and SPS aren't
+     * checked. Reported once per excursion, until SP is back in range.
+     */
+    pub fn set_stack_bounds(&mut self, low: u32, high: u32) {
+        self.stack_bounds = Some((low, high));
+        self.stack_out_of_bounds = false;
+    }
 
-                mos::FR_OK
-            } else {
+    /// Stop checking SP against set_stack_bounds()
+    pub fn clear_stack_bounds(&mut self) {
+        self.stack_bounds = None;
+    }
+
+    /// Report each switch between ADL and Z80 mode as MachineEvent::AdlModeChanged
+    pub fn set_adl_mode_tracing(&mut self, enable: bool) {
+        self.adl_mode_tracing = enable;
+    }
+
+    // the address SP points at: SPL in ADL mode, MBASE:SPS in Z80 mode
+    fn stack_address(cpu: &Cpu) -> (bool, u32) {
+        let adl = cpu.state.reg.adl;
+        (adl, if adl { cpu.state.reg.get24(Reg16::SP) } else { cpu.state.reg.get16_mbase(Reg16::SP) })
+    }
+
+    fn check_stack_bounds(&mut self, pc: u32, before: (bool, u32), after: (bool, u32), low: u32, high: u32) {
+        // a mode switch swaps which stack pointer is in use, rather than moving it
+        if after == before || after.0 != before.0 { return }
+        let sp = after.1;
+        let outside = sp < low || sp > high;
+        if outside && !self.stack_out_of_bounds {
+            self.push_event(MachineEvent::StackOutOfBounds { sp, pc });
+        }
+        self.stack_out_of_bounds = outside;
+    }
+
+    fn track_faults(&mut self, pc: u32, limit: u32, action: TripleFaultAction) {
+        if !self.instruction_faulted.get() {
+            self.consecutive_faults = 0;
+            return;
+        }
+        self.consecutive_faults += 1;
+        if self.consecutive_faults >= limit {
+            self.consecutive_faults = 0;
+            self.push_event(MachineEvent::TripleFault { pc });
+            match action {
+                TripleFaultAction::Halt => self.triple_fault_halt = true,
+                TripleFaultAction::Reset => self.request_reset(ResetKind::Cold),
+            }
+        }
+    }
+
+    /**
+     * Stop start() once it has run for `max` of real time, however busy
+     * the guest is. For hosted and CI use, where a runaway guest mustn't
+     * run forever.
+     */
+    pub fn set_max_wall_time(&mut self, max: Option<std::time::Duration>) {
+        self.max_wall_time = max;
+    }
+
+    /**
+     * Cap the cycles each byte takes to send on both UARTs, however slow
+     * a baud rate the guest sets. The default is the time for 300 baud,
+     * DEFAULT_MAX_TRANSMIT_COOLDOWN.
+     */
+    pub fn set_uart_max_transmit_cooldown(&mut self, cycles: i32) {
+        self.uart0.set_max_transmit_cooldown(cycles);
+        self.uart1.set_max_transmit_cooldown(cycles);
+    }
+
+    /**
+     * Limit the number of directories the guest may have open at once
+     * (None for no limit). f_opendir beyond the limit fails with
+     * FR_TOO_MANY_OPEN_FILES, as a guest that never calls f_closedir
+     * otherwise leaks host handles.
+     */
+    pub fn set_max_open_dirs(&mut self, max: Option<usize>) {
+        self.max_open_dirs = max;
+    }
+
+    /**
+     * Enforce FatFS file locking: opening a file that is already open
+     * fails with FR_LOCKED, unless all opens are read-only. Files are
+     * compared by their canonical host path, so different guest paths to
+     * the same file are still treated as the same file.
+     */
+    pub fn set_file_locking(&mut self, enable: bool) {
+        self.file_locking = enable;
+    }
+
+    /**
+     * Whether f_open and f_read update the host file's last access time.
+     * Off by default, since many hosts mount with noatime or relatime
+     * and tooling rarely cares.
+     */
+    pub fn set_update_access_time(&mut self, update: bool) {
+        self.update_access_time = update;
+    }
+
+    /**
+     * Make every f_write and f_putc fsync the host file before returning, so the
+     * file on disk is always exactly as the guest last wrote it, even if
+     * the emulator is killed. Slow, and off by default.
+     */
+    pub fn set_write_through(&mut self, enable: bool) {
+        self.write_through = enable;
+    }
+
+    /**
+     * Stat a path on the hostfs sdcard, resolved as MOS would resolve it
+     * (relative to the MOS current directory, case-insensitively).
+     * Unlike f_stat's FILINFO, this reports the host access time.
+     */
+    pub fn stat_guest_path(&mut self, path: &str) -> std::io::Result<GuestFileInfo> {
+        let path = self.host_path_from_mos_path_join(path);
+        let metadata = std::fs::metadata(&path)?;
+        let (fdate, ftime) = fat_date_time(&metadata);
+        Ok(GuestFileInfo {
+            size: metadata.len(),
+            is_dir: metadata.is_dir(),
+            attrib: self.fat_attrib(&path, &metadata),
+            fdate,
+            ftime,
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+        })
+    }
+
+    /**
+     * Copy the host directory tree `host_dir` into the hostfs sdcard at
+     * `guest_root` (resolved as stat_guest_path() does), keeping its
+     * structure and modification times, to set up test sdcard layouts.
+     * Existing files of the same names are overwritten.
+     */
+    pub fn preload_tree(&mut self, guest_root: &str, host_dir: &std::path::Path) -> std::io::Result<()> {
+        let dest = self.host_path_from_mos_path_join(guest_root);
+        copy_tree(host_dir, &dest)
+    }
+
+    /**
+     * Serialize the open-file table (guest FIL address, path relative to
+     * the sdcard directory, file position and open mode) as JSON, for
+     * import_open_files() to restore, possibly against a different
+     * sdcard directory holding the same files.
+     */
+    pub fn export_open_files(&self) -> std::io::Result<String> {
+        let root = std::fs::canonicalize(&self.hostfs_root_dir)?;
+        let mut fptrs: Vec<&u32> = self.open_files.keys().collect();
+        fptrs.sort();
+
+        let mut entries = vec![];
+        for fptr in fptrs {
+            let open_file = &self.open_files[fptr];
+            let path = open_file.host_path.strip_prefix(&root).ok()
+                .and_then(|p| p.to_str())
+                .ok_or_else(|| std::io::Error::other(format!("{} is outside the sdcard directory", open_file.host_path.display())))?;
+            entries.push(serde_json::json!({
+                "fil": *fptr,
+                "path": path,
+                "position": (&open_file.file).stream_position()?,
+                "mode": open_file.mode,
+            }));
+        }
+        Ok(serde_json::Value::Array(entries).to_string())
+    }
+
+    /**
+     * Replace the open-file table with one from export_open_files(),
+     * re-opening each file under the current sdcard directory and seeking
+     * to its saved position. On error the table is left unchanged.
+     */
+    pub fn import_open_files(&mut self, json: &str) -> std::io::Result<()> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed open file table");
+        let entries: serde_json::Value = serde_json::from_str(json).map_err(|_| invalid())?;
+
+        let mut open_files = HashMap::new();
+        for entry in entries.as_array().ok_or_else(invalid)? {
+            let fptr = entry["fil"].as_u64().ok_or_else(invalid)? as u32;
+            let path = entry["path"].as_str().ok_or_else(invalid)?;
+            let position = entry["position"].as_u64().ok_or_else(invalid)?;
+            let mode = entry["mode"].as_u64().ok_or_else(invalid)? as u32;
+
+            let host_path = std::fs::canonicalize(self.hostfs_root_dir.join(path))?;
+            let mut file = std::fs::File::options()
+                .read(true)
+                .write(mode & mos::FA_WRITE != 0)
+                .open(&host_path)?;
+            file.seek(SeekFrom::Start(position))?;
+            let writable = mode & (mos::FA_WRITE | mos::FA_CREATE_NEW | mos::FA_CREATE_ALWAYS) != 0;
+            open_files.insert(fptr, OpenFile { file, host_path, writable, mode });
+        }
+        self.open_files = open_files;
+        Ok(())
+    }
+
+    /**
+     * Up to `len` bytes from the current position of the hostfs file open
+     * on the guest FIL at `fil`, without moving the position: the guest's
+     * next f_read returns the same bytes. MOS has no such call; this is
+     * for host-side tests and inspection.
+     */
+    pub fn peek_file(&mut self, fil: u32, len: usize) -> std::io::Result<Vec<u8>> {
+        let open_file = self.open_files.get_mut(&fil)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no file open on that FIL"))?;
+        let position = open_file.file.stream_position()?;
+        let mut bytes = Vec::with_capacity(len);
+        let result = (&mut open_file.file).take(len as u64).read_to_end(&mut bytes);
+        // restored even if the read failed part way
+        open_file.file.seek(SeekFrom::Start(position))?;
+        result.map(|_| bytes)
+    }
+
+    fn touch_access_time(&self, f: &std::fs::File) {
+        if self.update_access_time {
+            // best effort: the guest doesn't care if this fails
+            let _ = filetime::set_file_handle_times(f, Some(filetime::FileTime::now()), None);
+        }
+    }
+
+    /**
+     * Wire the sdcard socket's card-detect and write-protect switches to
+     * GPIO input pins (port, pin 0..=7), or None to leave them unconnected.
+     * Card-detect reads low when a card is inserted, and write-protect
+     * reads high when the card is protected. Fails, changing nothing, for
+     * a pin outside 0..=7.
+     */
+    pub fn set_sdcard_switch_pins(&mut self, detect: Option<(gpio::GpioPort, u8)>, write_protect: Option<(gpio::GpioPort, u8)>) -> Result<(), String> {
+        for (_, pin) in detect.iter().chain(write_protect.iter()) {
+            if *pin > 7 {
+                return Err(format!("no GPIO pin {}: pins are 0 to 7", pin));
+            }
+        }
+        self.sdcard_detect_pin = detect;
+        self.sdcard_write_protect_pin = write_protect;
+        self.update_sdcard_switch_pins();
+        Ok(())
+    }
+
+    pub fn set_sdcard_inserted(&mut self, inserted: bool) {
+        self.sdcard_inserted = inserted;
+        self.update_sdcard_switch_pins();
+    }
+
+    /**
+     * With write-protect on, the whole card is read-only, whatever its
+     * files' attributes: hostfs calls that would modify the sdcard fail
+     * with FR_WRITE_PROTECTED, and an sdcard image rejects block writes
+     * as a locked card does.
+     */
+    pub fn set_sdcard_write_protect(&mut self, write_protect: bool) {
+        self.sdcard_write_protect = write_protect;
+        self.spi_sdcard.set_write_protect(write_protect);
+        self.update_sdcard_switch_pins();
+    }
+
+    fn update_sdcard_switch_pins(&mut self) {
+        let mut gpios = self.gpios.lock().unwrap();
+        if let Some((port, pin)) = self.sdcard_detect_pin {
+            gpios.port_mut(port).set_input_pin(pin, !self.sdcard_inserted);
+        }
+        if let Some((port, pin)) = self.sdcard_write_protect_pin {
+            gpios.port_mut(port).set_input_pin(pin, self.sdcard_write_protect);
+        }
+    }
+
+    /**
+     * Record every intercepted MOS fatfs call, with its arguments and
+     * result, for mos_call_log(). Disabling discards the record.
+     */
+    pub fn set_mos_call_recording(&mut self, enable: bool) {
+        self.mos_call_log = if enable { Some(vec![]) } else { None };
+    }
+
+    /// The calls recorded since set_mos_call_recording(true), in order
+    pub fn mos_call_log(&self) -> &[mos::MosCallRecord] {
+        self.mos_call_log.as_deref().unwrap_or(&[])
+    }
+
+    /**
+     * Write mos_call_log() to `path` as a JSON array of
+     * `{"call", "args", "path", "fresult"}` objects, the call by fatfs name.
+     */
+    pub fn write_mos_call_log(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let entries = self.mos_call_log().iter().map(|r| serde_json::json!({
+            "call": r.call.name(),
+            "args": r.args,
+            "path": r.path,
+            "fresult": r.fresult,
+        })).collect();
+        std::fs::write(path, serde_json::Value::Array(entries).to_string())
+    }
+
+    /**
+     * Counts and cycle costs of the MOS fatfs calls intercepted so far, by
+     * call, for profiling where a guest spends its time in MOS.
+     */
+    pub fn mos_call_stats(&self) -> HashMap<mos::MosCall, mos::CallStat> {
+        self.mos_call_stats.clone()
+    }
+
+    /**
+     * Report intercepted MOS fatfs calls to `log`, either all of them or
+     * only those that don't return FR_OK.
+     */
+    pub fn set_mos_log(&mut self, log: Option<(mos::MosLogMode, mos::MosLogSink)>) {
+        self.mos_log = log;
+    }
+
+    /**
+     * Load a raw binary image into memory at `address`.
+     */
+    pub fn load_binary(&mut self, path: &std::path::Path, address: u32) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("IO error reading '{}': {}", path.display(), e))?;
+        z80_mem_tools::memcpy_to_z80(self, address, &data);
+        Ok(())
+    }
+
+    /**
+     * Load a program, deciding from its content whether it is Intel HEX
+     * (which carries its own load addresses) or a raw binary, which is
+     * loaded at `default_addr`.
+     */
+    pub fn load_auto(&mut self, path: &std::path::Path, default_addr: u32) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("IO error reading '{}': {}", path.display(), e))?;
+
+        if intel_hex::looks_like_intel_hex(&data) {
+            // looks_like_intel_hex checked it is utf8 and parses ok
+            let chunks = intel_hex::parse_intel_hex(std::str::from_utf8(&data).unwrap())?;
+            for chunk in chunks {
+                z80_mem_tools::memcpy_to_z80(self, chunk.address, &chunk.data);
+            }
+        } else {
+            z80_mem_tools::memcpy_to_z80(self, default_addr, &data);
+        }
+        Ok(())
+    }
+
+    /**
+     * EXPERIMENTAL: if the MOS .map file can't be read, locate the fatfs
+     * functions by scanning the loaded flash for these signatures instead.
+     * See MosMap::from_flash_signatures.
+     */
+    pub fn set_mos_flash_signatures(&mut self, signatures: Vec<mos::FlashSignature>) {
+        self.mos_flash_signatures = signatures;
+    }
+
+    pub(crate) fn mos_flash_signatures(&self) -> &[mos::FlashSignature] {
+        &self.mos_flash_signatures
+    }
+
+    // the whole flash, as at 0..ROM_SIZE with FLASH_ADDR_U 0
+    pub(crate) fn flash(&self) -> &[u8] {
+        &self.mem_rom
+    }
+
+    fn load_mos(&mut self) -> Result<(), String> {
+        let code = std::fs::read(&self.mos_bin)
+            .map_err(|e| format!("Error opening {}: {:?}", self.mos_bin.display(), e))?;
+        
+        for (i, e) in code.iter().enumerate() {
+            self.mem_rom[i] = *e;
+        }
+
+        let mos_map = self.mos_bin.with_extension("map");
+
+        let e = match crate::symbol_map::read_zds_map_file(mos_map.to_str().unwrap()) {
+            Ok(map) => {
+                match mos::MosMap::from_symbol_map(map) {
+                    Ok(mos_map) => {
+                        self.mos_map = mos_map;
+                        return Ok(());
+                    }
+                    Err(e) => e.to_string()
+                }
+            }
+            Err(e) => e
+        };
+
+        if !self.mos_flash_signatures.is_empty() {
+            if let Some(scanned_map) = mos::MosMap::from_flash_scan(self) {
+                println!("Error reading {} ({}). Using fatfs addresses found by flash scan", mos_map.display(), e);
+                self.mos_map = scanned_map;
+                return Ok(());
+            }
+        }
+        println!("Error reading {}. hostfs integration disabled: {}", mos_map.display(), e);
+        self.enable_hostfs = false;
+        Ok(())
+    }
+
+    fn hostfs_mos_f_getlabel(&mut self, cpu: &mut Cpu) {
+        let mut buf = self._peek24(cpu.state.sp() + 6);
+        let label = "hostfs";
+        for b in label.bytes() {
+            self.poke(buf, b);
+            buf += 1;
+        }
+        self.poke(buf, 0);
+
+        // success
+        mos::set_fresult(cpu, 0); // success
+
+        let mut env = Environment::new(&mut cpu.state, self);
+        env.subroutine_return();
+    }
+
+    fn hostfs_mos_f_getcwd(&mut self, cpu: &mut Cpu) {
+        let mut buf = self._peek24(cpu.state.sp() + 3);
+        let cwd = self.mos_current_dir.0.clone();
+        self.poke(buf, 47); // leading slash
+        buf += 1;
+        for b in cwd.to_str().unwrap_or("").bytes() {
+            self.poke(buf, b);
+            buf += 1;
+        }
+        self.poke(buf, 0);
+
+        // success
+        mos::set_fresult(cpu, 0); // success
+
+        let mut env = Environment::new(&mut cpu.state, self);
+        env.subroutine_return();
+    }
+
+    fn hostfs_mos_f_close(&mut self, cpu: &mut Cpu) {
+        let fptr = self._peek24(cpu.state.sp() + 3);
+        //eprintln!("f_close(${:x})", fptr);
+
+        // closes on Drop
+        self.open_files.remove(&fptr);
+
+        // success
+        mos::set_fresult(cpu, 0);
+
+        Environment::new(&mut cpu.state, self).subroutine_return();
+    }
+
+    fn hostfs_mos_f_gets(&mut self, cpu: &mut Cpu) {
+        let buf = self._peek24(cpu.state.sp() + 3);
+        let max_len = self._peek24(cpu.state.sp() + 6);
+        let fptr = self._peek24(cpu.state.sp() + 9);
+        //eprintln!("f_gets(buf: ${:x}, len: ${:x}, fptr: ${:x})", buf, max_len, fptr);
+
+        let config = self.f_gets_config;
+        let fresult = 'outer: {
+            // room for at least the NUL terminator
+            if max_len == 0 { break 'outer mos::FR_DISK_ERR }
+
+            if let Some(mut f) = self.open_files.get(&fptr).map(|o| &o.file) {
+                let mut line = vec![];
+                let mut host_buf = vec![0; 1];
+                let mut consumed = false;
+                while line.len() < max_len as usize - 1 {
+                    let n_read = match f.read(host_buf.as_mut_slice()) {
+                        Ok(n_read) => n_read,
+                        Err(_) => break 'outer mos::FR_DISK_ERR
+                    };
+
+                    if n_read == 0 {
+                        // EOF. as FatFS, a partial last line is still returned
+                        if !consumed { break 'outer mos::FR_DISK_ERR }
+                        break;
+                    }
+                    consumed = true;
+
+                    let b = host_buf[0];
+                    if b == b'\r' && config.strip_cr { continue }
+                    if b == 0 && config.stop_at_nul { break }
+                    line.push(b);
+                    if b == b'\n' { break }
+                }
+                // no f.tell()...
+                let fpos = match f.seek(SeekFrom::Current(0)) {
+                    Ok(n) => n,
+                    Err(_) => break 'outer mos::FR_DISK_ERR
+                };
+
+                // save file position to FIL.fptr U32
+                self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32);
+                let mut buf2 = buf;
+                for b in line {
+                    self.poke(buf2, b);
+                    buf2 += 1;
+                }
+                self.poke(buf2, 0);
+
+                mos::FR_OK
+            } else {
                 mos::FR_DISK_ERR
             }
         };
@@ -584,23 +1630,36 @@ impl AgonMachine {
         env.subroutine_return();
     }
 
+    /**
+     * Save the file position to FIL.fptr after writing, growing FIL.obj.objsize
+     * if the write went past the end of the file.
+     */
+    fn set_fil_fptr_after_write(&mut self, fptr: u32, fpos: u32) {
+        self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos);
+        if fpos > self._peek24(fptr + mos::FIL_MEMBER_OBJSIZE) {
+            self._poke24(fptr + mos::FIL_MEMBER_OBJSIZE, fpos);
+        }
+    }
+
     fn hostfs_mos_f_putc(&mut self, cpu: &mut Cpu) {
         let ch = self._peek24(cpu.state.sp() + 3);
         let fptr = self._peek24(cpu.state.sp() + 6);
 
         let fresult = 'outer: {
-            if let Some(mut f) = self.open_files.get(&fptr) {
+            if let Some(mut f) = self.open_files.get(&fptr).map(|o| &o.file) {
                 if f.write(&[ch as u8]).is_err() {
                     break 'outer mos::FR_DISK_ERR;
                 }
+                if self.write_through && f.sync_data().is_err() {
+                    break 'outer mos::FR_DISK_ERR
+                }
 
                 // no f.tell()...
                 let fpos = match f.seek(SeekFrom::Current(0)) {
                     Ok(n) => n,
                     Err(_) => break 'outer mos::FR_DISK_ERR
                 };
-                // save file position to FIL.fptr
-                self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32);
+                self.set_fil_fptr_after_write(fptr, fpos as u32);
 
                 mos::FR_OK
             } else {
@@ -608,7 +1667,7 @@ impl AgonMachine {
             }
         };
 
-        cpu.state.reg.set24(Reg16::HL, fresult);
+        mos::set_fresult(cpu, fresult);
         let mut env = Environment::new(&mut cpu.state, self);
         env.subroutine_return();
     }
@@ -621,19 +1680,21 @@ impl AgonMachine {
         //eprintln!("f_write(${:x}, ${:x}, {}, ${:x})", fptr, buf, num, num_written_ptr);
 
         let fresult = 'outer: {
-            if let Some(mut f) = self.open_files.get(&fptr) {
+            if let Some(mut f) = self.open_files.get(&fptr).map(|o| &o.file) {
                 for i in 0..num {
                     let byte = self.peek(buf + i);
                     if f.write(&[byte]).is_err() { break 'outer mos::FR_DISK_ERR }
                 }
+                if self.write_through && f.sync_data().is_err() {
+                    break 'outer mos::FR_DISK_ERR
+                }
 
                 // no f.tell()...
                 let fpos = match f.seek(SeekFrom::Current(0)) {
                     Ok(n) => n,
                     Err(_) => break 'outer mos::FR_DISK_ERR
                 };
-                // save file position to FIL.fptr
-                self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32);
+                self.set_fil_fptr_after_write(fptr, fpos as u32);
 
                 // inform caller that all bytes were written
                 self._poke24(num_written_ptr, num);
@@ -644,47 +1705,68 @@ impl AgonMachine {
             }
         };
 
-        cpu.state.reg.set24(Reg16::HL, fresult);
+        mos::set_fresult(cpu, fresult);
         let mut env = Environment::new(&mut cpu.state, self);
         env.subroutine_return();
     }
 
+    /**
+     * Copy up to `len` bytes from `f` into guest memory at `buf`, as they
+     * are read. Returns the number of bytes delivered, and the error (if
+     * any) that cut the transfer short.
+     */
+    fn read_to_guest<R: Read>(&mut self, f: &mut R, mut buf: u32, len: u32) -> (u32, Option<std::io::Error>) {
+        let mut host_buf = [0u8; 512];
+        let mut num_bytes_read: u32 = 0;
+
+        while num_bytes_read < len {
+            let chunk_len = std::cmp::min(len - num_bytes_read, host_buf.len() as u32) as usize;
+            match f.read(&mut host_buf[..chunk_len]) {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    for b in &host_buf[..n] {
+                        self.poke(buf, *b);
+                        buf += 1;
+                    }
+                    num_bytes_read += n as u32;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return (num_bytes_read, Some(e))
+            }
+        }
+        (num_bytes_read, None)
+    }
+
     fn hostfs_mos_f_read(&mut self, cpu: &mut Cpu) {
         let fptr = self._peek24(cpu.state.sp() + 3);
-        let mut buf = self._peek24(cpu.state.sp() + 6);
+        let buf = self._peek24(cpu.state.sp() + 6);
         let len = self._peek24(cpu.state.sp() + 9);
         let bytes_read_ptr = self._peek24(cpu.state.sp() + 12);
         //eprintln!("f_read(${:x}, ${:x}, ${:x}, ${:x})", fptr, buf, len, bytes_read_ptr);
 
         let fresult = 'outer: {
-            if let Some(mut f) = self.open_files.get(&fptr) {
-                let mut host_buf: Vec<u8> = vec![0; len as usize];
-                if let Ok(num_bytes_read) = f.read(host_buf.as_mut_slice()) {
-                    // no f.tell()...
-                    let fpos = match f.seek(SeekFrom::Current(0)) {
-                        Ok(fpos) => fpos,
-                        Err(_) => break 'outer mos::FR_DISK_ERR
-                    };
-                    // copy to agon ram 
-                    for b in host_buf {
-                        self.poke(buf, b);
-                        buf += 1;
-                    }
-                    // save file position to FIL.fptr
-                    self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32);
-                    // save num bytes read
-                    self._poke24(bytes_read_ptr, num_bytes_read as u32);
-
-                    mos::FR_OK
-                } else {
-                    mos::FR_DISK_ERR
-                }
-            } else {
-                mos::FR_DISK_ERR
+            // taken out of open_files for the duration, so we can poke guest memory as we read
+            let Some(mut open_file) = self.open_files.remove(&fptr) else {
+                break 'outer mos::FR_DISK_ERR
+            };
+            let (num_bytes_read, err) = self.read_to_guest(&mut open_file.file, buf, len);
+            self.touch_access_time(&open_file.file);
+            // no f.tell()...
+            let fpos = open_file.file.seek(SeekFrom::Current(0));
+            self.open_files.insert(fptr, open_file);
+
+            // save num bytes read, including any partial transfer before an error
+            self._poke24(bytes_read_ptr, num_bytes_read);
+            // save file position to FIL.fptr
+            match fpos {
+                Ok(fpos) => self._poke24(fptr + mos::FIL_MEMBER_FPTR, fpos as u32),
+                Err(_) => break 'outer mos::FR_DISK_ERR
             }
+
+            if err.is_some() { mos::FR_DISK_ERR } else { mos::FR_OK }
         };
 
-        cpu.state.reg.set24(Reg16::HL, fresult);
+        mos::set_fresult(cpu, fresult);
         let mut env = Environment::new(&mut cpu.state, self);
         env.subroutine_return();
     }
@@ -695,7 +1777,7 @@ impl AgonMachine {
         self.open_dirs.remove(&dir_ptr);
 
         // success
-        cpu.state.reg.set24(Reg16::HL, 0); // success
+        mos::set_fresult(cpu, 0); // success
 
         let mut env = Environment::new(&mut cpu.state, self);
         env.subroutine_return();
@@ -713,25 +1795,11 @@ impl AgonMachine {
         self._poke24(z80_filinfo_ptr + mos::FILINFO_MEMBER_FSIZE_U32, metadata.len() as u32);
         self.poke(z80_filinfo_ptr + mos::FILINFO_MEMBER_FSIZE_U32 + 3, (metadata.len() >> 24) as u8);
 
-        // is directory?
-        if metadata.is_dir() {
-            self.poke(z80_filinfo_ptr + mos::FILINFO_MEMBER_FATTRIB_U8, 0x10 /* AM_DIR */);
-        }
+        let attrib = self.fat_attrib(path, metadata);
+        self.poke(z80_filinfo_ptr + mos::FILINFO_MEMBER_FATTRIB_U8, attrib);
 
         // set fdate, ftime
-        let mut fil_date = 0u16;
-        let mut fil_time = 0u16;
-
-        let raw_mtime = filetime::FileTime::from_last_modification_time(&metadata);
-        if let Some(utc_mtime) = chrono::DateTime::from_timestamp(raw_mtime.unix_seconds(), 0) {
-            let local_mtime = utc_mtime.with_timezone(&chrono::Local::now().timezone());
-            fil_date |= ((local_mtime.year() - 1980) << 9) as u16;
-            fil_date |= ((local_mtime.month()) << 5) as u16;
-            fil_date |= local_mtime.day() as u16;
-            fil_time |= (local_mtime.hour() << 11) as u16;
-            fil_time |= (local_mtime.minute() << 5) as u16;
-            fil_time |= local_mtime.second() as u16;
-        }
+        let (fil_date, fil_time) = fat_date_time(metadata);
 
         self.poke(z80_filinfo_ptr + mos::FILINFO_MEMBER_FDATE_U16, fil_date as u8);
         self.poke(z80_filinfo_ptr + mos::FILINFO_MEMBER_FDATE_U16 + 1, (fil_date >> 8) as u8);
@@ -749,25 +1817,26 @@ impl AgonMachine {
         match self.open_dirs.get_mut(&dir_ptr) {
             Some(dir) => {
 
-                match dir.next() {
+                // the attribute store is not part of the guest's filesystem
+                match dir.find(|e| !e.as_ref().is_ok_and(|e| e.file_name() == FAT_ATTRIB_FILE)) {
                     Some(Ok(dir_entry)) => {
                         let path = dir_entry.path();
                         if let Ok(metadata) = std::fs::metadata(&path) {
                             self.hostfs_set_filinfo_from_metadata(file_info_ptr, &path, &metadata);
 
                             // success
-                            cpu.state.reg.set24(Reg16::HL, 0);
+                            mos::set_fresult(cpu, 0);
                         } else {
                             // hm. why might std::fs::metadata fail?
                             z80_mem_tools::memcpy_to_z80(
                                 self, file_info_ptr + mos::FILINFO_MEMBER_FNAME_256BYTES,
                                 "<error reading file metadata>".as_bytes()
                             );
-                            cpu.state.reg.set24(Reg16::HL, 0);
+                            mos::set_fresult(cpu, 0);
                         }
                     }
                     Some(Err(_)) => {
-                        cpu.state.reg.set24(Reg16::HL, 1); // error
+                        mos::set_fresult(cpu, 1); // error
                     }
                     None => {
                         // directory has been read to the end.
@@ -775,12 +1844,12 @@ impl AgonMachine {
                         // the directory end has been reached
 
                         // success
-                        cpu.state.reg.set24(Reg16::HL, 0);
+                        mos::set_fresult(cpu, 0);
                     }
                 }
             }
             None => {
-                cpu.state.reg.set24(Reg16::HL, 1); // error
+                mos::set_fresult(cpu, 1); // error
             }
         }
 
@@ -788,116 +1857,298 @@ impl AgonMachine {
     }
 
     fn hostfs_mos_f_mkdir(&mut self, cpu: &mut Cpu) {
-        let dir_name = unsafe {
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, self._peek24(cpu.state.sp() + 3)))
-        };
+        let dir_name = self.mos_path_arg(self._peek24(cpu.state.sp() + 3));
         //eprintln!("f_mkdir(\"{}\")", dir_name);
+        let host_path = self.host_path_from_mos_path_join(&dir_name);
+        if self.refuse_fat_attrib_file(mos::MosCall::FMkdir, cpu, &host_path) {
+            self.recycle_path_arg(dir_name);
+            return
+        }
+        if !self.creatable_name(&dir_name) {
+            mos::set_fresult(cpu, mos::FR_INVALID_NAME);
+            self.recycle_path_arg(dir_name);
+            Environment::new(&mut cpu.state, self).subroutine_return();
+            return
+        }
 
-        match std::fs::create_dir(self.host_path_from_mos_path_join(&dir_name)) {
+        match std::fs::create_dir(host_path) {
             Ok(_) => {
                 // success
-                cpu.state.reg.set24(Reg16::HL, 0);
+                mos::set_fresult(cpu, 0);
             }
             Err(_) => {
                 // error
-                cpu.state.reg.set24(Reg16::HL, 1);
+                mos::set_fresult(cpu, 1);
             }
         }
+        self.recycle_path_arg(dir_name);
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
     fn hostfs_mos_f_rename(&mut self, cpu: &mut Cpu) {
-        let old_name = unsafe {
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, self._peek24(cpu.state.sp() + 3)))
-        };
-        let new_name = unsafe {
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, self._peek24(cpu.state.sp() + 6)))
-        };
+        let old_name = self.mos_path_arg(self._peek24(cpu.state.sp() + 3));
+        let new_name = self.mos_path_arg(self._peek24(cpu.state.sp() + 6));
         //eprintln!("f_rename(\"{}\", \"{}\")", old_name, new_name);
+        let old_path = self.mos_path_join(&old_name);
+        let new_path = self.mos_path_join(&new_name);
+        let old_host_path = self.mos_path_to_host_path(&old_path);
+        let new_host_path = self.mos_path_to_host_path(&new_path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FRename, cpu, &old_host_path) ||
+           self.refuse_fat_attrib_file(mos::MosCall::FRename, cpu, &new_host_path) {
+            self.recycle_path_arg(old_name);
+            self.recycle_path_arg(new_name);
+            return
+        }
+        if !self.creatable_name(&new_name) {
+            mos::set_fresult(cpu, mos::FR_INVALID_NAME);
+            self.recycle_path_arg(old_name);
+            self.recycle_path_arg(new_name);
+            Environment::new(&mut cpu.state, self).subroutine_return();
+            return
+        }
 
-        match std::fs::rename(self.host_path_from_mos_path_join(&old_name),
-                              self.host_path_from_mos_path_join(&new_name)) {
+        match std::fs::rename(&old_host_path, &new_host_path) {
             Ok(_) => {
+                self.rename_fat_attributes(&old_host_path, Some(&new_host_path));
+                // follow the current directory if it (or a parent) moved
+                if let Ok(rest) = self.mos_current_dir.0.strip_prefix(&old_path.0) {
+                    self.mos_current_dir = MosPath(new_path.0.join(rest));
+                }
                 // success
-                cpu.state.reg.set24(Reg16::HL, 0);
+                mos::set_fresult(cpu, 0);
             }
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound => {
-                        cpu.state.reg.set24(Reg16::HL, 4);
+                        mos::set_fresult(cpu, mos::FR_NO_FILE);
                     }
                     _ => {
-                        cpu.state.reg.set24(Reg16::HL, 1);
+                        mos::set_fresult(cpu, 1);
                     }
                 }
             }
         }
+        self.recycle_path_arg(old_name);
+        self.recycle_path_arg(new_name);
+        Environment::new(&mut cpu.state, self).subroutine_return();
+    }
+
+    // key into fat_attributes
+    fn fat_attrib_key(&self, host_path: &std::path::Path) -> String {
+        let rel = host_path.strip_prefix(&self.hostfs_root_dir).unwrap_or(host_path);
+        rel.to_string_lossy().replace('\\', "/").to_ascii_lowercase()
+    }
+
+    fn fat_attributes(&mut self) -> &mut HashMap<String, u8> {
+        let root = &self.hostfs_root_dir;
+        self.fat_attributes.get_or_insert_with(|| {
+            let json = std::fs::read_to_string(root.join(FAT_ATTRIB_FILE)).unwrap_or_default();
+            let entries: serde_json::Value = serde_json::from_str(&json).unwrap_or(serde_json::Value::Null);
+            entries.as_array().map(|entries| entries.iter().filter_map(|e| {
+                Some((e["path"].as_str()?.to_string(), e["attrib"].as_u64()? as u8))
+            }).collect()).unwrap_or_default()
+        })
+    }
+
+    fn save_fat_attributes(&mut self) -> std::io::Result<()> {
+        let mut entries: Vec<(&String, &u8)> = self.fat_attributes.iter().flatten().collect();
+        entries.sort();
+        let json = serde_json::Value::Array(entries.iter().map(|(path, attrib)| serde_json::json!({
+            "path": path,
+            "attrib": **attrib,
+        })).collect());
+        std::fs::write(self.hostfs_root_dir.join(FAT_ATTRIB_FILE), json.to_string())
+    }
+
+    // move the stored attributes of `from` (and anything under it, for a
+    // directory) to `to`, or forget them if None. best effort: the file
+    // itself has already moved
+    fn rename_fat_attributes(&mut self, from: &std::path::Path, to: Option<&std::path::Path>) {
+        let from = self.fat_attrib_key(from);
+        let to = to.map(|to| self.fat_attrib_key(to));
+        let under = |key: &str, dir: &str| key == dir || key.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/'));
+
+        let attributes = self.fat_attributes();
+        let before = attributes.len();
+        let moved: Vec<(String, u8)> = attributes.iter()
+            .filter(|(key, _)| under(key, &from))
+            .map(|(key, attrib)| (key.clone(), *attrib))
+            .collect();
+        // whatever was at the destination was replaced
+        if let Some(to) = &to {
+            attributes.retain(|key, _| !under(key, to));
+        }
+        for (key, attrib) in &moved {
+            attributes.remove(key);
+            if let Some(to) = &to {
+                attributes.insert(format!("{}{}", to, &key[from.len()..]), *attrib);
+            }
+        }
+        if !moved.is_empty() || attributes.len() != before {
+            let _ = self.save_fat_attributes();
+        }
+    }
+
+    // FatFS attributes of a hostfs file: AM_RDO from the host permissions,
+    // and others kept by f_chmod
+    fn fat_attrib(&mut self, host_path: &std::path::Path, metadata: &std::fs::Metadata) -> u8 {
+        let key = self.fat_attrib_key(host_path);
+        let stored = self.fat_attributes().get(&key).copied().unwrap_or(0);
+        stored |
+            if metadata.is_dir() { mos::AM_DIR } else { 0 } |
+            if metadata.permissions().readonly() { mos::AM_RDO } else { 0 }
+    }
+
+    fn hostfs_mos_f_chmod(&mut self, cpu: &mut Cpu) {
+        //fr = f_chmod(path, attr, mask);
+        let path = unsafe {
+            String::from_utf8_unchecked(mos::get_mos_path_string(self, self._peek24(cpu.state.sp() + 3)))
+        };
+        let attr = self._peek24(cpu.state.sp() + 6) as u8;
+        let mask = self._peek24(cpu.state.sp() + 9) as u8 & (mos::AM_RDO | mos::AM_HID | mos::AM_SYS | mos::AM_ARC);
+        let host_path = self.host_path_from_mos_path_join(&path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FChmod, cpu, &host_path) { return }
+
+        let fresult = 'outer: {
+            let metadata = match std::fs::metadata(&host_path) {
+                Ok(m) => m,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break 'outer mos::FR_NO_FILE,
+                Err(_) => break 'outer mos::FR_DISK_ERR
+            };
+
+            if mask & mos::AM_RDO != 0 {
+                let read_only = attr & mos::AM_RDO != 0;
+                let mut perms = metadata.permissions();
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    // clearing read-only makes it writable by the owner only
+                    let mode = perms.mode();
+                    perms.set_mode(if read_only { mode & !0o222 } else { mode | 0o200 });
+                }
+                #[cfg(not(unix))]
+                perms.set_readonly(read_only);
+                if std::fs::set_permissions(&host_path, perms).is_err() {
+                    break 'outer mos::FR_DENIED
+                }
+            }
+
+            let mask = mask & !mos::AM_RDO;
+            if mask != 0 {
+                let key = self.fat_attrib_key(&host_path);
+                let attributes = self.fat_attributes();
+                let new = (attributes.get(&key).copied().unwrap_or(0) & !mask) | (attr & mask);
+                if new == 0 { attributes.remove(&key); } else { attributes.insert(key, new); }
+                if self.save_fat_attributes().is_err() {
+                    break 'outer mos::FR_DISK_ERR
+                }
+            }
+            mos::FR_OK
+        };
+
+        mos::set_fresult(cpu, fresult);
+        Environment::new(&mut cpu.state, self).subroutine_return();
+    }
+
+    fn hostfs_mos_f_utime(&mut self, cpu: &mut Cpu) {
+        //fr = f_utime(path, &fno);
+        let path = unsafe {
+            String::from_utf8_unchecked(mos::get_mos_path_string(self, self._peek24(cpu.state.sp() + 3)))
+        };
+        let filinfo_ptr = self._peek24(cpu.state.sp() + 6);
+        let peek16 = |machine: &Self, address: u32| machine.peek(address) as u16 | (machine.peek(address + 1) as u16) << 8;
+        let fdate = peek16(self, filinfo_ptr + mos::FILINFO_MEMBER_FDATE_U16);
+        let ftime = peek16(self, filinfo_ptr + mos::FILINFO_MEMBER_FTIME_U16);
+        let host_path = self.host_path_from_mos_path_join(&path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FUtime, cpu, &host_path) { return }
+
+        let fresult = match fat_date_time_to_file_time(fdate, ftime) {
+            // FatFS doesn't validate the date, but the host can't store a bad one
+            None => mos::FR_INVALID_PARAMETER,
+            Some(mtime) => match filetime::set_file_mtime(&host_path, mtime) {
+                Ok(_) => mos::FR_OK,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => mos::FR_NO_FILE,
+                Err(_) => mos::FR_DISK_ERR
+            }
+        };
+
+        mos::set_fresult(cpu, fresult);
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
     fn hostfs_mos_f_chdir(&mut self, cpu: &mut Cpu) {
         let cd_to_ptr = self._peek24(cpu.state.sp() + 3);
-        let cd_to = unsafe {
-            // MOS filenames may not be valid utf-8
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, cd_to_ptr))
-        };
+        let cd_to = self.mos_path_arg(cd_to_ptr);
         //eprintln!("f_chdir({})", cd_to);
 
         let new_path = self.mos_path_join(&cd_to);
+        let host_path = self.mos_path_to_host_path(&new_path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FChdir, cpu, &host_path) {
+            self.recycle_path_arg(cd_to);
+            return
+        }
 
-        match std::fs::metadata(self.mos_path_to_host_path(&new_path)) {
+        match std::fs::metadata(host_path) {
             Ok(metadata) => {
                 if metadata.is_dir() {
                     //eprintln!("setting path to {:?}", &new_path);
                     self.mos_current_dir = new_path;
-                    cpu.state.reg.set24(Reg16::HL, 0);
+                    mos::set_fresult(cpu, 0);
                 } else {
-                    cpu.state.reg.set24(Reg16::HL, 1);
+                    mos::set_fresult(cpu, 1);
                 }
             }
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound => {
-                        cpu.state.reg.set24(Reg16::HL, 4);
+                        mos::set_fresult(cpu, mos::FR_NO_FILE);
                     }
                     _ => {
-                        cpu.state.reg.set24(Reg16::HL, 1);
+                        mos::set_fresult(cpu, 1);
                     }
                 }
             }
         }
+        self.recycle_path_arg(cd_to);
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
     fn hostfs_mos_f_mount(&mut self, cpu: &mut Cpu) {
         // always success. hostfs is mounted
-        cpu.state.reg.set24(Reg16::HL, 0); // ok
+        mos::set_fresult(cpu, 0); // ok
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
     fn hostfs_mos_f_unlink(&mut self, cpu: &mut Cpu) {
         let filename_ptr = self._peek24(cpu.state.sp() + 3);
-        let filename = unsafe {
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, filename_ptr))
-        };
+        let filename = self.mos_path_arg(filename_ptr);
         //eprintln!("f_unlink(\"{}\")", filename);
 
-        match std::fs::remove_file(self.host_path_from_mos_path_join(&filename)) {
+        let path = self.host_path_from_mos_path_join(&filename);
+        if self.refuse_fat_attrib_file(mos::MosCall::FUnlink, cpu, &path) {
+            self.recycle_path_arg(filename);
+            return
+        }
+        // like fatfs, remove files or empty directories
+        let is_dir = std::fs::symlink_metadata(&path).map(|m| m.is_dir()).unwrap_or(false);
+        let result = if is_dir { std::fs::remove_dir(&path) } else { std::fs::remove_file(&path) };
+
+        let fresult = match result {
             Ok(()) => {
-                cpu.state.reg.set24(Reg16::HL, 0); // ok
+                self.rename_fat_attributes(&path, None);
+                mos::FR_OK
             }
-            Err(e) => {
-                match e.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        cpu.state.reg.set24(Reg16::HL, 4);
-                    }
-                    _ => {
-                        cpu.state.reg.set24(Reg16::HL, 1);
-                    }
-                }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => mos::FR_NO_FILE,
+                std::io::ErrorKind::PermissionDenied => mos::FR_DENIED,
+                // directory not empty
+                _ if is_dir => mos::FR_DENIED,
+                _ => mos::FR_DISK_ERR
             }
         };
 
+        mos::set_fresult(cpu, fresult);
+        self.recycle_path_arg(filename);
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
@@ -905,37 +2156,62 @@ impl AgonMachine {
         //fr = f_opendir(&dir, path);
         let dir_ptr = self._peek24(cpu.state.sp() + 3);
         let path_ptr = self._peek24(cpu.state.sp() + 6);
-        let path = unsafe {
-            // MOS filenames may not be valid utf-8
-            String::from_utf8_unchecked(mos::get_mos_path_string(self, path_ptr))
-        };
+        let path = self.mos_path_arg(path_ptr);
         //eprintln!("f_opendir(${:x}, \"{}\")", dir_ptr, path.trim_end());
+        let host_path = self.host_path_from_mos_path_join(&path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FOpendir, cpu, &host_path) {
+            self.recycle_path_arg(path);
+            return
+        }
+
+        // re-opening a DIR that is already open reuses its handle
+        if let Some(max) = self.max_open_dirs {
+            if !self.open_dirs.contains_key(&dir_ptr) && self.open_dirs.len() >= max {
+                mos::set_fresult(cpu, mos::FR_TOO_MANY_OPEN_FILES);
+                self.recycle_path_arg(path);
+                Environment::new(&mut cpu.state, self).subroutine_return();
+                return
+            }
+        }
 
-        match std::fs::read_dir(self.host_path_from_mos_path_join(&path)) {
+        match std::fs::read_dir(host_path) {
             Ok(dir) => {
                 // XXX should clear the DIR struct in z80 ram
                 
                 // store in map of z80 DIR ptr to rust ReadDir
                 self.open_dirs.insert(dir_ptr, dir);
-                cpu.state.reg.set24(Reg16::HL, 0); // ok
+                mos::set_fresult(cpu, 0); // ok
             }
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound => {
-                        cpu.state.reg.set24(Reg16::HL, 4);
+                        mos::set_fresult(cpu, mos::FR_NO_FILE);
                     }
                     _ => {
-                        cpu.state.reg.set24(Reg16::HL, 1);
+                        mos::set_fresult(cpu, 1);
                     }
                 }
             }
         }
 
-        cpu.state.reg.set24(Reg16::HL, 0); // ok
+        self.recycle_path_arg(path);
         let mut env = Environment::new(&mut cpu.state, self);
         env.subroutine_return();
     }
 
+    // a MOS path argument, read into a buffer from path_buffers so hooks
+    // needn't allocate for it. returned to the pool by recycle_path_arg
+    fn mos_path_arg(&mut self, ptr: u32) -> String {
+        let mut buf = self.path_buffers.pop().unwrap_or_default();
+        mos::get_mos_path_string_into(self, ptr, &mut buf);
+        // MOS filenames may not be valid utf-8
+        unsafe { String::from_utf8_unchecked(buf) }
+    }
+
+    fn recycle_path_arg(&mut self, path: String) {
+        self.path_buffers.push(path.into_bytes());
+    }
+
     fn mos_path_to_host_path(&mut self, path: &MosPath) -> std::path::PathBuf {
         self.hostfs_root_dir.join(&path.0)
     }
@@ -944,12 +2220,15 @@ impl AgonMachine {
      * Return a new MosPath, `new_fragments` joined to mos_current_dir
      */
     fn mos_path_join(&mut self, new_fragments: &str) -> MosPath {
+        // accept DOS-style backslash separators as well as '/'
+        let new_fragments = new_fragments.replace('\\', "/");
+
         let mut full_path = match new_fragments.get(0..1) {
-            Some("/") | Some("\\") => std::path::PathBuf::new(),
+            Some("/") => std::path::PathBuf::new(),
             _ => self.mos_current_dir.0.clone()
         };
 
-        for fragment in new_fragments.split(|c| c == '/' || c == '\\') {
+        for fragment in new_fragments.split('/') {
             match fragment {
                 "" | "." => {}
                 ".." => {
@@ -998,7 +2277,7 @@ impl AgonMachine {
         let fptr = self._peek24(cpu.state.sp() + 3);
 
         // truncate from current file position cursor
-        match self.open_files.get(&fptr) {
+        match self.open_files.get(&fptr).map(|o| &o.file) {
             Some(mut f) => {
                 match f.seek(SeekFrom::Current(0)) {
                     Ok(pos) => {
@@ -1008,20 +2287,20 @@ impl AgonMachine {
                                 self._poke24(fptr + mos::FIL_MEMBER_OBJSIZE, pos as u32);
 
                                 // success
-                                cpu.state.reg.set24(Reg16::HL, 0);
+                                mos::set_fresult(cpu, 0);
                             }
                             Err(_) => {
-                                cpu.state.reg.set24(Reg16::HL, 1); // error
+                                mos::set_fresult(cpu, 1); // error
                             }
                         }
                     }
                     Err(_) => {
-                        cpu.state.reg.set24(Reg16::HL, 1); // error
+                        mos::set_fresult(cpu, 1); // error
                     }
                 }
             }
             None => {
-                cpu.state.reg.set24(Reg16::HL, 1); // error
+                mos::set_fresult(cpu, 1); // error
             }
         }
         Environment::new(&mut cpu.state, self).subroutine_return();
@@ -1033,37 +2312,36 @@ impl AgonMachine {
 
         //eprintln!("f_lseek(${:x}, {})", fptr, offset);
 
-        match self.open_files.get(&fptr) {
+        match self.open_files.get(&fptr).map(|o| &o.file) {
             Some(mut f) => {
                 match f.seek(SeekFrom::Start(offset as u64)) {
                     Ok(pos) => {
                         // save file position to FIL.fptr
                         self._poke24(fptr + mos::FIL_MEMBER_FPTR, pos as u32);
                         // success
-                        cpu.state.reg.set24(Reg16::HL, 0);
+                        mos::set_fresult(cpu, 0);
                     }
                     Err(_) => {
-                        cpu.state.reg.set24(Reg16::HL, 1); // error
+                        mos::set_fresult(cpu, 1); // error
                     }
                 }
             }
             None => {
-                cpu.state.reg.set24(Reg16::HL, 1); // error
+                mos::set_fresult(cpu, 1); // error
             }
         }
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
     fn hostfs_mos_f_stat(&mut self, cpu: &mut Cpu) {
-        let path_str = {
-            let ptr = self._peek24(cpu.state.sp() + 3);
-            unsafe {
-                String::from_utf8_unchecked(mos::get_mos_path_string(self, ptr))
-            }
-        };
+        let path_str = self.mos_path_arg(self._peek24(cpu.state.sp() + 3));
         let filinfo_ptr = self._peek24(cpu.state.sp() + 6);
         let path = self.host_path_from_mos_path_join(&path_str);
         //eprintln!("f_stat(\"{}\", ${:x})", path_str, filinfo_ptr);
+        if self.refuse_fat_attrib_file(mos::MosCall::FStat, cpu, &path) {
+            self.recycle_path_arg(path_str);
+            return
+        }
 
         match std::fs::metadata(&path) {
             Ok(metadata) => {
@@ -1073,48 +2351,79 @@ impl AgonMachine {
                 self.hostfs_set_filinfo_from_metadata(filinfo_ptr, &path, &metadata);
 
                 // success
-                cpu.state.reg.set24(Reg16::HL, 0);
+                mos::set_fresult(cpu, 0);
             }
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound => {
-                        cpu.state.reg.set24(Reg16::HL, 4);
+                        mos::set_fresult(cpu, mos::FR_NO_FILE);
                     }
                     _ => {
-                        cpu.state.reg.set24(Reg16::HL, 1);
+                        mos::set_fresult(cpu, 1);
                     }
                 }
             }
         }
 
+        self.recycle_path_arg(path_str);
         Environment::new(&mut cpu.state, self).subroutine_return();
     }
 
+    /**
+     * FatFS file sharing rule: a file may be open any number of times for
+     * reading, but opening it for writing excludes all other opens.
+     * `fptr` is the FIL being opened, which doesn't conflict with itself.
+     */
+    fn is_file_locked(&self, fptr: u32, host_path: &std::path::Path, writable: bool) -> bool {
+        let Ok(host_path) = std::fs::canonicalize(host_path) else {
+            // doesn't exist yet, so can't be open
+            return false
+        };
+        self.open_files.iter().any(|(other_fptr, other)| {
+            *other_fptr != fptr && other.host_path == host_path && (writable || other.writable)
+        })
+    }
+
     fn hostfs_mos_f_open(&mut self, cpu: &mut Cpu) {
         let fptr = self._peek24(cpu.state.sp() + 3);
-        let filename = {
-            let ptr = self._peek24(cpu.state.sp() + 6);
-            // MOS filenames may not be valid utf-8
-            unsafe {
-                String::from_utf8_unchecked(mos::get_mos_path_string(self, ptr))
-            }
-        };
+        let filename = self.mos_path_arg(self._peek24(cpu.state.sp() + 6));
         let path = self.mos_path_join(&filename);
         let mode = self._peek24(cpu.state.sp() + 9);
-        if let Ok(metadata) = std::fs::metadata(self.mos_path_to_host_path(&path)) {
-            if !metadata.is_file() {
-                cpu.state.reg.set24(Reg16::HL, 4);
+        let host_path = self.mos_path_to_host_path(&path);
+        if self.refuse_fat_attrib_file(mos::MosCall::FOpen, cpu, &host_path) {
+            self.recycle_path_arg(filename);
+            return
+        }
+        match std::fs::metadata(&host_path) {
+            Ok(metadata) => if !metadata.is_file() {
+                mos::set_fresult(cpu, mos::FR_NO_FILE);
+                self.recycle_path_arg(filename);
+                Environment::new(&mut cpu.state, self).subroutine_return();
+                return
+            }
+            Err(_) => if mode & (mos::FA_CREATE_NEW | mos::FA_CREATE_ALWAYS) != 0 && !self.creatable_name(&filename) {
+                mos::set_fresult(cpu, mos::FR_INVALID_NAME);
+                self.recycle_path_arg(filename);
                 Environment::new(&mut cpu.state, self).subroutine_return();
                 return
             }
         }
         //eprintln!("f_open(${:x}, \"{}\", {})", fptr, &filename, mode);
+        let writable = mode & (mos::FA_WRITE | mos::FA_CREATE_NEW | mos::FA_CREATE_ALWAYS) != 0;
+        if self.file_locking && self.is_file_locked(fptr, &host_path, writable) {
+            mos::set_fresult(cpu, mos::FR_LOCKED);
+            self.recycle_path_arg(filename);
+            Environment::new(&mut cpu.state, self).subroutine_return();
+            return
+        }
+        // always readable, so FA_READ | FA_WRITE reads and updates in place
+        // through the one file position
         match std::fs::File::options()
             .read(true)
             .write(mode & mos::FA_WRITE != 0)
             .create((mode & mos::FA_CREATE_NEW != 0) || (mode & mos::FA_CREATE_ALWAYS != 0))
             .truncate(mode & mos::FA_CREATE_ALWAYS != 0)
-            .open(self.mos_path_to_host_path(&path)) {
+            .open(&host_path) {
             Ok(mut f) => {
                 // wipe the FIL structure
                 z80_mem_tools::memset(self, fptr, 0, mos::SIZEOF_MOS_FIL_STRUCT);
@@ -1129,50 +2438,135 @@ impl AgonMachine {
                 // store file len in fatfs FIL structure
                 self._poke24(fptr + mos::FIL_MEMBER_OBJSIZE, file_len as u32);
                 
+                self.touch_access_time(&f);
+
                 // store mapping from MOS *FIL to rust File
-                self.open_files.insert(fptr, f);
+                let host_path = std::fs::canonicalize(&host_path).unwrap_or(host_path);
+                self.open_files.insert(fptr, OpenFile { file: f, host_path, writable, mode });
 
-                cpu.state.reg.set24(Reg16::HL, 0); // ok
+                mos::set_fresult(cpu, 0); // ok
             }
             Err(e) => {
                 match e.kind() {
-                    std::io::ErrorKind::NotFound => cpu.state.reg.set24(Reg16::HL, 4),
-                    _ => cpu.state.reg.set24(Reg16::HL, 1)
+                    std::io::ErrorKind::NotFound => mos::set_fresult(cpu, mos::FR_NO_FILE),
+                    _ => mos::set_fresult(cpu, 1)
                 }
             }
         }
+        self.recycle_path_arg(filename);
+        Environment::new(&mut cpu.state, self).subroutine_return();
+    }
+
+    fn mos_call_modifies_sdcard(&self, call: mos::MosCall, cpu: &Cpu) -> bool {
+        match call {
+            mos::MosCall::FChmod | mos::MosCall::FMkdir | mos::MosCall::FPutc | mos::MosCall::FRename |
+            mos::MosCall::FTruncate | mos::MosCall::FUnlink | mos::MosCall::FUtime | mos::MosCall::FWrite => true,
+            mos::MosCall::FOpen => {
+                let mode = self._peek24(cpu.state.sp() + 9);
+                mode & (mos::FA_WRITE | mos::FA_CREATE_NEW | mos::FA_CREATE_ALWAYS) != 0
+            }
+            _ => false
+        }
+    }
+
+    // the FAT_ATTRIB_FILE sidecar is hidden from f_readdir, so not there to
+    // the guest at all. if `host_path` names it, fails `call` accordingly
+    // and returns from it
+    fn refuse_fat_attrib_file(&mut self, call: mos::MosCall, cpu: &mut Cpu, host_path: &std::path::Path) -> bool {
+        if !host_path.file_name().is_some_and(|name| name.eq_ignore_ascii_case(FAT_ATTRIB_FILE)) {
+            return false
+        }
+        let fresult = if self.mos_call_modifies_sdcard(call, cpu) { mos::FR_DENIED } else { mos::FR_NO_FILE };
+        mos::set_fresult(cpu, fresult);
         Environment::new(&mut cpu.state, self).subroutine_return();
+        true
+    }
+
+    fn hostfs_mos_call(&mut self, call: mos::MosCall, cpu: &mut Cpu) {
+        let logging = self.mos_log.is_some() || self.mos_call_log.is_some();
+        // read the arguments before the call pops the stack frame
+        let args: Vec<u32> = match self.mos_call_log {
+            Some(_) => (0..call.arg_count()).map(|i| self._peek24(cpu.state.sp() + 3 + 3*i)).collect(),
+            None => vec![]
+        };
+        let path = match (logging, call.path_arg()) {
+            (true, Some(arg)) => {
+                let ptr = self._peek24(cpu.state.sp() + 3 + 3*arg);
+                Some(String::from_utf8_lossy(&mos::get_mos_path_string(self, ptr)).into_owned())
+            }
+            _ => None
+        };
+
+        let cycles_before = self.cycle_counter.get();
+        if self.sdcard_write_protect && self.mos_call_modifies_sdcard(call, cpu) {
+            mos::set_fresult(cpu, mos::FR_WRITE_PROTECTED);
+            Environment::new(&mut cpu.state, self).subroutine_return();
+        } else {
+            match call {
+                mos::MosCall::FChdir => self.hostfs_mos_f_chdir(cpu),
+                mos::MosCall::FChmod => self.hostfs_mos_f_chmod(cpu),
+                mos::MosCall::FClose => self.hostfs_mos_f_close(cpu),
+                mos::MosCall::FClosedir => self.hostfs_mos_f_closedir(cpu),
+                mos::MosCall::FGetcwd => self.hostfs_mos_f_getcwd(cpu),
+                mos::MosCall::FGetlabel => self.hostfs_mos_f_getlabel(cpu),
+                mos::MosCall::FGets => self.hostfs_mos_f_gets(cpu),
+                mos::MosCall::FLseek => self.hostfs_mos_f_lseek(cpu),
+                mos::MosCall::FMkdir => self.hostfs_mos_f_mkdir(cpu),
+                mos::MosCall::FMount => self.hostfs_mos_f_mount(cpu),
+                mos::MosCall::FOpen => self.hostfs_mos_f_open(cpu),
+                mos::MosCall::FOpendir => self.hostfs_mos_f_opendir(cpu),
+                mos::MosCall::FPutc => self.hostfs_mos_f_putc(cpu),
+                mos::MosCall::FRead => self.hostfs_mos_f_read(cpu),
+                mos::MosCall::FReaddir => self.hostfs_mos_f_readdir(cpu),
+                mos::MosCall::FRename => self.hostfs_mos_f_rename(cpu),
+                mos::MosCall::FStat => self.hostfs_mos_f_stat(cpu),
+                mos::MosCall::FTruncate => self.hostfs_mos_f_truncate(cpu),
+                mos::MosCall::FUnlink => self.hostfs_mos_f_unlink(cpu),
+                mos::MosCall::FUtime => self.hostfs_mos_f_utime(cpu),
+                mos::MosCall::FWrite => self.hostfs_mos_f_write(cpu),
+            }
+        }
+
+        let stat = self.mos_call_stats.entry(call).or_default();
+        stat.count += 1;
+        stat.cycles += self.cycle_counter.get().wrapping_sub(cycles_before) as u64;
+
+        if logging {
+            let fresult = match call {
+                // f_gets returns a pointer to the line read, or NULL on error
+                mos::MosCall::FGets => if cpu.state.reg.get24(Reg16::HL) == 0 { mos::FR_DISK_ERR } else { mos::FR_OK },
+                _ => mos::get_fresult(cpu)
+            };
+            if let Some((mode, sink)) = self.mos_log.as_mut() {
+                if *mode == mos::MosLogMode::All || fresult != mos::FR_OK {
+                    sink(&mos::MosLogRecord { call, path: path.clone(), fresult });
+                }
+            }
+            if let Some(log) = self.mos_call_log.as_mut() {
+                log.push(mos::MosCallRecord { call, args, path, fresult });
+            }
+        }
     }
 
     #[inline]
     // returns cycles elapsed
     pub fn execute_instruction(&mut self, cpu: &mut Cpu) -> u32 {
+        // hostfs hooks charge their guest memory accesses to this too
+        self.cycle_counter.set(0);
         let pc = cpu.state.pc();
         // remember PC before instruction executes. the debugger uses this
         // when out-of-bounds memory accesses happen (since they can't be
         // trapped mid-execution)
         self.last_pc = pc;
 
+        if !self.star_commands.is_empty() && self.mos_map.mos_exec == Some(pc) && self.flash_addr_u == 0 {
+            self.host_star_command(cpu);
+        }
+
         if self.enable_hostfs && pc < 0x20000 && self.flash_addr_u == 0 {
-            if pc == self.mos_map.f_close { self.hostfs_mos_f_close(cpu); }
-            if pc == self.mos_map.f_gets { self.hostfs_mos_f_gets(cpu); }
-            if pc == self.mos_map.f_read { self.hostfs_mos_f_read(cpu); }
-            if pc == self.mos_map.f_open { self.hostfs_mos_f_open(cpu); }
-            if pc == self.mos_map.f_write { self.hostfs_mos_f_write(cpu); }
-            if pc == self.mos_map.f_chdir { self.hostfs_mos_f_chdir(cpu); }
-            if pc == self.mos_map.f_closedir { self.hostfs_mos_f_closedir(cpu); }
-            if pc == self.mos_map.f_getlabel { self.hostfs_mos_f_getlabel(cpu); }
-            if pc == self.mos_map.f_getcwd { self.hostfs_mos_f_getcwd(cpu); }
-            if pc == self.mos_map.f_lseek { self.hostfs_mos_f_lseek(cpu); }
-            if pc == self.mos_map.f_mkdir { self.hostfs_mos_f_mkdir(cpu); }
-            if pc == self.mos_map.f_mount { self.hostfs_mos_f_mount(cpu); }
-            if pc == self.mos_map.f_opendir { self.hostfs_mos_f_opendir(cpu); }
-            if pc == self.mos_map.f_putc { self.hostfs_mos_f_putc(cpu); }
-            if pc == self.mos_map.f_readdir { self.hostfs_mos_f_readdir(cpu); }
-            if pc == self.mos_map.f_rename { self.hostfs_mos_f_rename(cpu); }
-            if pc == self.mos_map.f_stat { self.hostfs_mos_f_stat(cpu); }
-            if pc == self.mos_map.f_unlink { self.hostfs_mos_f_unlink(cpu); }
-            if pc == self.mos_map.f_truncate { self.hostfs_mos_f_truncate(cpu); }
+            if let Some(call) = self.mos_map.call_at(pc) {
+                self.hostfs_mos_call(call, cpu);
+            }
             // never referenced in MOS
             //if pc == self.mos_map._f_puts { eprintln!("Un-trapped fatfs call: f_puts"); }
             //if pc == self.mos_map._f_setlabel { eprintln!("Un-trapped fatfs call: f_setlabel"); }
@@ -1182,17 +2576,51 @@ impl AgonMachine {
             //if pc == self.mos_map._f_sync { eprintln!("Un-trapped fatfs call: f_sync"); }
             //if pc == self.mos_map._f_truncate { eprintln!("Un-trapped fatfs call: f_truncate"); }
         }
+        // the hooks return from the call they replaced, so the instruction
+        // executed is at the return address
+        let pc = cpu.state.pc();
+        self.last_pc = pc;
 
-        self.cycle_counter.set(0);
+        self.guest_wrote = false;
+        if self.code_bytes.is_some() {
+            self.fetch_pc.set(Some(pc));
+            self.fetch_len.set(0);
+        }
+        if self.fetch_hook.is_some() {
+            self.apply_fetch_hook(pc);
+        }
+        self.instruction_faulted.set(false);
+        let sp_before = Self::stack_address(cpu);
+        let adl_before = cpu.state.reg.adl;
         cpu.execute_instruction(self);
+        if self.adl_mode_tracing && cpu.state.reg.adl != adl_before {
+            self.push_event(MachineEvent::AdlModeChanged { from: adl_before, to: cpu.state.reg.adl, pc });
+        }
+        self.fetch_replacement = None;
         let cycles_elapsed = self.cycle_counter.get();
+        if let Some((limit, action)) = self.triple_fault_detection {
+            self.track_faults(pc, limit, action);
+        }
+        if let Some((low, high)) = self.stack_bounds {
+            self.check_stack_bounds(pc, sp_before, Self::stack_address(cpu), low, high);
+        }
+        if self.fetch_pc.take().is_some() {
+            self.mark_code_bytes(pc, self.fetch_len.get().max(1));
+        }
         //println!("{:2} cycles, {:?}", cycles_elapsed, ez80::disassembler::disassemble(self, cpu, None, pc, pc+1));
 
-        for t in &mut self.prt_timers {
-            t.apply_ticks(cycles_elapsed as u16);
+        self.pending_ticks += cycles_elapsed;
+        if self.pending_ticks >= self.tick_granularity {
+            self.apply_pending_ticks();
+        }
+
+        if let Some(detect) = self.busy_wait_detection {
+            self.track_busy_wait(pc, cycles_elapsed, detect);
         }
 
-        self.uart0.apply_ticks(cycles_elapsed as i32);
+        if let Some(digest) = self.execution_digest {
+            self.execution_digest = Some(Self::fold_execution_digest(digest, cpu));
+        }
 
         cycles_elapsed
     }
@@ -1200,10 +2628,9 @@ impl AgonMachine {
     #[inline]
     pub fn fire_gpio_interrupts(&mut self, cpu: &mut Cpu, vector_base: u32, interrupts_due: u8) -> bool {
         if interrupts_due != 0 {
-            let mut env = Environment::new(&mut cpu.state, self);
             for pin in 0..=7 {
                 if interrupts_due & (1<<pin) != 0 {
-                    env.interrupt(vector_base + 2*pin);
+                    self.interrupt(cpu, vector_base + 2*pin);
                     return true;
                 }
             }
@@ -1211,115 +2638,2780 @@ impl AgonMachine {
         false
     }
 
-    #[inline]
-    pub fn do_interrupts(&mut self, cpu: &mut Cpu) {
-        // Not an interrupt. Is a soft-reset pending?
-        if cpu.state.instructions_executed & 0xff == 0 {
-            // perform a soft reset if requested
-            if self.soft_reset.load(std::sync::atomic::Ordering::Relaxed) {
-                // MOS soft reset code always runs from ADL mode.
-                // and set_pc(0) will actually set pc := (mb<<16 + 0) in non-adl mode
-                cpu.state.reg.adl = true;
-                cpu.state.reg.madl = true;
-                cpu.state.set_pc(0);
-                self.soft_reset.store(false, std::sync::atomic::Ordering::Relaxed);
-                return;
+    /**
+     * Call the routine at `address` in ADL mode, with registers (including
+     * the stack pointer) set from `regs`, and run until it returns. A
+     * sentinel return address is pushed on the stack, so the routine must
+     * return with the stack balanced. Interrupts are not serviced.
+     *
+     * Returns the registers on return, or StopReason::InstructionLimit if
+     * the routine didn't return within `max_instructions` (or
+     * StopReason::TripleFault, as start()).
+     */
+    pub fn call_function(&mut self, cpu: &mut Cpu, address: u32, regs: RegisterSetup, max_instructions: u64) -> Result<RegisterSetup, StopReason> {
+        // never executed: call_function stops before fetching from here
+        const SENTINEL: u32 = 0xfffffe;
+
+        cpu.state.reg.adl = true;
+        cpu.state.reg.madl = true;
+        regs.apply(&mut cpu.state.reg);
+        let sp = regs.sp.wrapping_sub(3) & 0xffffff;
+        self._poke24(sp, SENTINEL);
+        cpu.state.reg.set24(Reg16::SP, sp);
+        cpu.state.set_pc(address);
+
+        let mut instructions = 0;
+        while cpu.state.pc() != SENTINEL {
+            if instructions == max_instructions {
+                return Err(StopReason::InstructionLimit);
+            }
+            self.execute_instruction(cpu);
+            if self.triple_fault_halt {
+                return Err(StopReason::TripleFault);
             }
+            instructions += 1;
         }
+        Ok(RegisterSetup::from_registers(&cpu.state.reg))
+    }
 
-        if cpu.state.instructions_executed & 0x3f == 0 && cpu.state.reg.get_iff1() {
-            // Interrupts in priority order
-            for i in 0..self.prt_timers.len() {
-                if self.prt_timers[i].irq_due() {
-                    Environment::new(&mut cpu.state, self).interrupt(0xa + 2*(i as u32));
-                    return;
-                }
-            }
+    /**
+     * Machine state as JSON, for lightweight polling by external tools:
+     * CPU registers, peripheral registers and summary stats (no memory).
+     */
+    pub fn state_json(&self, cpu: &Cpu) -> String {
+        let reg = &cpu.state.reg;
+        let uart_json = |uart: &uart::Uart| serde_json::json!({
+            "ier": uart.ier,
+            "fctl": uart.fctl(),
+            "lctl": uart.lctl,
+            "brg_div": uart.brg_div,
+            "spr": uart.spr,
+            "tx_dropped": uart.tx_dropped(),
+        });
+        let gpio_json = |gpio: &gpio::Gpio| serde_json::json!({
+            "dr": gpio.get_dr(),
+            "ddr": gpio.get_ddr(),
+            "alt1": gpio.get_alt1(),
+            "alt2": gpio.get_alt2(),
+        });
+        let prt_timers: Vec<serde_json::Value> = self.prt_timers.iter().map(|t| serde_json::json!({
+            "ctl": t.ctl(),
+            "reload": t.reload(),
+            "counter": t.counter(),
+        })).collect();
+        let gpios = self.gpios.lock().unwrap();
+
+        serde_json::json!({
+            "registers": {
+                "pc": cpu.state.pc(),
+                "a": reg.get8(Reg8::A),
+                "f": reg.get8(Reg8::F),
+                "bc": reg.get24(Reg16::BC),
+                "de": reg.get24(Reg16::DE),
+                "hl": reg.get24(Reg16::HL),
+                "ix": reg.get24(Reg16::IX),
+                "iy": reg.get24(Reg16::IY),
+                "sp": reg.get24(Reg16::SP),
+                "adl": reg.adl,
+                "madl": reg.madl,
+                "iff1": reg.get_iff1(),
+            },
+            "peripherals": {
+                "uart0": uart_json(&self.uart0),
+                "uart1": uart_json(&self.uart1),
+                "prt_timers": prt_timers,
+                "gpio_b": gpio_json(&gpios.b),
+                "gpio_c": gpio_json(&gpios.c),
+                "gpio_d": gpio_json(&gpios.d),
+                "memory_map": {
+                    "flash_addr_u": self.flash_addr_u,
+                    "onchip_mem_enable": self.onchip_mem_enable,
+                    "onchip_mem_segment": self.onchip_mem_segment,
+                    "cs0_lbr": self.cs0_lbr,
+                    "cs0_ubr": self.cs0_ubr,
+                    "cs0_ctl": self.cs0_ctl,
+                },
+            },
+            "stats": {
+                "instructions_executed": cpu.state.instructions_executed,
+                "paused": self.paused,
+                "hostfs_enabled": self.enable_hostfs,
+                "open_files": self.open_files.len(),
+                "open_dirs": self.open_dirs.len(),
+            },
+        }).to_string()
+    }
 
-            // fire uart interrupt
-            if self.uart0.is_rx_interrupt_enabled() && self.uart0.maybe_fill_rx_buf() != None || // character(s) received
-               self.uart0.ier & 0x02 != 0 { // character(s) to send
-                let mut env = Environment::new(&mut cpu.state, self);
-                //println!("uart interrupt!");
-                env.interrupt(0x18); // uart0_handler
-                return;
-            }
-            // fire gpio interrupts
-            {
-                let (b_int, c_int, d_int) = {
-                    let mut gpios = self.gpios.lock().unwrap();
-                    let b_int = gpios.b.get_interrupt_due();
-                    let c_int = gpios.c.get_interrupt_due();
-                    let d_int = gpios.d.get_interrupt_due();
-                    (b_int, c_int, d_int)
-                };
+    /**
+     * Tick the PRT timers and UART0 at most once per `cycles` CPU cycles,
+     * rather than after every instruction (the default, cycles = 1).
+     * Coarser ticking is faster but less accurate: timer interrupts and
+     * UART baud timing are only as precise as the granularity, so
+     * firmware sees several bytes sent, or timer steps, at once.
+     */
+    pub fn set_tick_granularity(&mut self, cycles: u32) {
+        self.apply_pending_ticks();
+        self.tick_granularity = cycles.max(1);
+    }
 
-                if self.fire_gpio_interrupts(cpu, 0x30, b_int) {
-                    return;
-                }
-                if self.fire_gpio_interrupts(cpu, 0x40, c_int) {
-                    return;
-                }
-                if self.fire_gpio_interrupts(cpu, 0x50, d_int) {
-                    return;
-                }
+    /**
+     * Report tight busy-wait loops as MachineEvent::BusyWait: emitted once
+     * the PC has stayed within `max_span` bytes for `cycles` cycles, with
+     * no memory or port writes. A loop is reported once,
+     * until execution leaves it. None disables detection (the default).
+     */
+    pub fn set_busy_wait_detection(&mut self, detect: Option<BusyWaitDetection>) {
+        self.busy_wait_detection = detect;
+        self.busy_wait = None;
+    }
+
+    /**
+     * Overwrite memory at `address` with `bytes`, remembering what was
+     * there for revert_patch(). Flash can be patched too. Patches may not
+     * overlap, except that re-patching the same address replaces the
+     * patch (keeping the original bytes). The patch must lie in mapped
+     * memory, and is made without the side effects of a guest access.
+     */
+    pub fn apply_patch(&mut self, address: u32, bytes: &[u8]) -> Result<(), String> {
+        if address as u64 + bytes.len() as u64 > 1 << 24 {
+            return Err(format!("patch at ${:x} runs past the 24-bit address space", address));
+        }
+        let end = address + bytes.len() as u32;
+        if !(address..end).all(|a| self.is_mapped(a)) {
+            return Err(format!("patch at ${:x} is outside mapped memory", address));
+        }
+        let overlaps = self.patches.range(..end).next_back()
+            .is_some_and(|(a, orig)| *a != address && *a + orig.len() as u32 > address);
+        if overlaps {
+            return Err(format!("patch at ${:x} overlaps another patch", address));
+        }
+        if let Some(orig) = self.patches.get(&address) {
+            if orig.len() != bytes.len() {
+                return Err(format!("patch at ${:x} differs in length from the existing patch", address));
             }
+        } else {
+            let orig = (address..end).map(|a| self.peek_raw(a)).collect();
+            self.patches.insert(address, orig);
         }
+        self.poke_raw_bytes(address, bytes);
+        Ok(())
     }
 
-    #[inline]
-    fn debugger_tick(&mut self, debugger: &mut Option<debugger::DebuggerServer>, cpu: &mut Cpu) {
-        if let Some(ref mut ds) = debugger {
-            ds.tick(self, cpu);
+    /**
+     * Restore the bytes replaced by the patch at `address`. Returns false
+     * if there is no patch there.
+     */
+    pub fn revert_patch(&mut self, address: u32) -> bool {
+        match self.patches.remove(&address) {
+            Some(orig) => {
+                self.poke_raw_bytes(address, &orig);
+                true
+            }
+            None => false
         }
     }
 
-    pub fn start(&mut self, debugger_con: Option<debugger::DebuggerConnection>) {
-        let mut cpu = Cpu::new_ez80();
+    /**
+     * Replace the guest byte at `addr` with `f` of its current value,
+     * returning the old value. This happens between instructions, so the
+     * guest sees either the old value or the new one: host code can use it
+     * for a handshake with a guest polling a flag.
+     */
+    pub fn with_guest_byte<F: FnOnce(u8) -> u8>(&mut self, addr: u32, f: F) -> u8 {
+        let old = self.peek(addr);
+        self.poke(addr, f(old));
+        old
+    }
 
-        let mut debugger = if debugger_con.is_some() {
-            self.paused = true;
-            Some(debugger::DebuggerServer::new(debugger_con.unwrap()))
+    /**
+     * Call `hook` before each instruction, with its PC and the 6 bytes
+     * there (the longest eZ80 instruction). Bytes it returns are executed
+     * instead, just this once. Memory isn't changed: they are read in
+     * place of the bytes from PC while the instruction executes, until it
+     * writes to one of those addresses.
+     */
+    pub fn set_fetch_hook(&mut self, hook: Option<FetchHook>) {
+        self.fetch_hook = hook;
+    }
+
+    /**
+     * The byte at `address` as the guest would read it, but without the
+     * side effects of a guest access: no cycles are used, and faults,
+     * watchpoints and the access log don't see it. Unmapped addresses
+     * read as 0xf5.
+     */
+    pub fn peek_raw(&self, address: u32) -> u8 {
+        if let Some(a) = self.get_internal_ram_address(address) {
+            self.mem_internal[a as usize]
+        } else if let Some(a) = self.get_rom_address(address) {
+            self.mem_rom[a as usize]
+        } else if let Some(a) = self.get_external_ram_address(address) {
+            self.mem_external[a as usize]
         } else {
-            None
-        };
+            0xf5
+        }
+    }
 
-        match self.ram_init {
-            RamInit::Random => {
-                for i in 0x40000..0xc0000 {
-                    self.poke(i, rand::thread_rng().gen_range(0..=255));
-                }
+    fn is_mapped(&self, address: u32) -> bool {
+        self.get_internal_ram_address(address).is_some() ||
+            self.get_rom_address(address).is_some() ||
+            self.get_external_ram_address(address).is_some()
+    }
 
-                for i in 0..ONCHIP_RAM_SIZE {
-                    self.mem_internal[i as usize] = rand::thread_rng().gen_range(0..=255);
-                }
+    // as peek_raw, for writes. unmapped addresses are ignored
+    fn poke_raw_bytes(&mut self, address: u32, bytes: &[u8]) {
+        for (i, b) in bytes.iter().enumerate() {
+            let address = (address + i as u32) & 0xffffff;
+            if let Some(a) = self.get_internal_ram_address(address) {
+                self.mem_internal[a as usize] = *b;
+            } else if let Some(a) = self.get_rom_address(address) {
+                self.mem_rom[a as usize] = *b;
+            } else if let Some(a) = self.get_external_ram_address(address) {
+                self.mem_external[a as usize] = *b;
             }
-            RamInit::Zero => {}
         }
+    }
 
-        self.load_mos();
+    fn apply_fetch_hook(&mut self, pc: u32) {
+        let bytes: Vec<u8> = (0..6).map(|i| self.peek_raw((pc + i) & 0xffffff)).collect();
+        let replacement = self.fetch_hook.as_mut().unwrap()(pc, &bytes);
+        self.fetch_replacement = replacement.map(|bytes| (pc, bytes.into_iter().map(Some).collect()));
+    }
 
-        cpu.state.set_pc(0);
-        //cpu.set_trace(true);
+    fn fetch_replacement_at(&self, address: u32) -> Option<u8> {
+        let (pc, bytes) = self.fetch_replacement.as_ref()?;
+        bytes.get((address.wrapping_sub(*pc) & 0xffffff) as usize).copied().flatten()
+    }
 
-        let cycles_per_ms: u64 = self.clockspeed_hz / 1000;
-        let mut timeslice_start = std::time::Instant::now();
-        loop {
-            // in unlimited CPU mode, this inner loop never exits
-            let mut cycle: u64 = 0;
-            while cycle < cycles_per_ms {
-                self.debugger_tick(&mut debugger, &mut cpu);
-                if self.paused { break; }
-                self.do_interrupts(&mut cpu);
-                cycle += self.execute_instruction(&mut cpu) as u64;
-            }
+    /**
+     * Detect the guest overwriting instructions it has already executed,
+     * reporting each as MachineEvent::SelfModifyingCode. An instruction's
+     * bytes are taken to be those read just after its PC while it
+     * executes, so a data read there can occasionally be counted as code.
+     * Each executed byte is reported at most once until it is executed
+     * again. Only writes by guest instructions count, not the host's
+     * (hostfs reads, loading binaries). Enabling uses 2MiB for the map of
+     * executed bytes.
+     */
+    pub fn set_self_modifying_code_detection(&mut self, enable: bool) {
+        self.code_bytes = if enable { Some(vec![0; (1 << 24) / 64]) } else { None };
+    }
 
-            while timeslice_start.elapsed() < std::time::Duration::from_millis(1) {
-                std::thread::sleep(std::time::Duration::from_micros(500));
+    /**
+     * Keep the last `len` guest memory accesses (instruction fetches
+     * included), for recent_accesses() to show what led up to a fault.
+     * None stops logging and discards the log.
+     */
+    pub fn set_access_log(&mut self, len: Option<usize>) {
+        self.access_log_len = len.unwrap_or(0);
+        self.access_log = len.map(|len| std::cell::RefCell::new(std::collections::VecDeque::with_capacity(len)));
+    }
+
+    /**
+     * The logged memory accesses, oldest first.
+     */
+    pub fn recent_accesses(&self) -> Vec<MemAccess> {
+        match &self.access_log {
+            Some(log) => log.borrow().iter().copied().collect(),
+            None => vec![]
+        }
+    }
+
+    /**
+     * Set the watchpoints, replacing any already set. The first guest
+     * access to hit one is held in watchpoint_hit.
+     */
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<debugger::Watchpoint>) {
+        self.watchpoints = watchpoints;
+    }
+
+    fn check_watchpoints(&self, addr: u32, value: u8, access: MemAccessKind) {
+        if self.watchpoint_hit.get().is_none() && self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.watchpoint_hit.set(Some(MemAccess { pc: self.last_pc, addr, value, access }));
+        }
+    }
+
+    fn log_access(&self, addr: u32, value: u8, access: MemAccessKind) {
+        if let Some(log) = &self.access_log {
+            let mut log = log.borrow_mut();
+            if log.len() == self.access_log_len {
+                if self.access_log_len == 0 { return }
+                log.pop_front();
             }
-            timeslice_start = timeslice_start.checked_add(
-                std::time::Duration::from_millis(1)
-            ).unwrap_or(std::time::Instant::now());
+            log.push_back(MemAccess { pc: self.last_pc, addr, value, access });
         }
     }
+
+    #[inline]
+    fn note_fetch(&self, pc: u32, address: u32) {
+        // the longest eZ80 instruction is 6 bytes (prefix, DD CB d op)
+        let offset = address.wrapping_sub(pc) & 0xffffff;
+        if offset < 6 && offset >= self.fetch_len.get() {
+            self.fetch_len.set(offset + 1);
+        }
+    }
+
+    fn mark_code_bytes(&mut self, pc: u32, len: u32) {
+        if let Some(bits) = self.code_bytes.as_mut() {
+            for a in pc..pc + len {
+                let a = a & 0xffffff;
+                bits[(a / 64) as usize] |= 1 << (a % 64);
+            }
+        }
+    }
+
+    fn check_code_write(&mut self, address: u32) {
+        if let Some(bits) = self.code_bytes.as_mut() {
+            let a = address & 0xffffff;
+            let mask = 1u64 << (a % 64);
+            let word = &mut bits[(a / 64) as usize];
+            if *word & mask != 0 {
+                *word &= !mask;
+                self.push_event(MachineEvent::SelfModifyingCode { addr: a });
+            }
+        }
+    }
+
+    /**
+     * Start (or stop) hashing the PC and registers after every instruction
+     * into a running digest. Two runs of the same program from the same
+     * state give the same execution_digest(), so it can serve as a golden
+     * trace when regression testing the emulator. Enabling resets the digest.
+     */
+    pub fn set_execution_digest(&mut self, enable: bool) {
+        self.execution_digest = if enable { Some(FNV_OFFSET_BASIS) } else { None };
+    }
+
+    /**
+     * The digest of all instructions executed since set_execution_digest(true),
+     * or 0 if it is not enabled.
+     */
+    pub fn execution_digest(&self) -> u64 {
+        self.execution_digest.unwrap_or(0)
+    }
+
+    fn fold_execution_digest(digest: u64, cpu: &Cpu) -> u64 {
+        let r = RegisterSetup::from_registers(&cpu.state.reg);
+        let words = [
+            cpu.state.pc(), cpu.state.reg.adl as u32,
+            ((r.a as u32) << 8) | r.f as u32, r.bc, r.de, r.hl, r.ix, r.iy, r.sp
+        ];
+        words.iter()
+            .flat_map(|w| w.to_le_bytes())
+            .fold(digest, |h, b| (h ^ b as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    fn track_busy_wait(&mut self, pc: u32, cycles: u32, detect: BusyWaitDetection) {
+        match self.busy_wait.as_mut() {
+            Some(bw) if !self.guest_wrote && pc.max(bw.hi) - pc.min(bw.lo) < detect.max_span => {
+                bw.lo = bw.lo.min(pc);
+                bw.hi = bw.hi.max(pc);
+                bw.cycles += cycles as u64;
+                if !bw.reported && bw.cycles >= detect.cycles {
+                    bw.reported = true;
+                    let event = MachineEvent::BusyWait { pc_range: bw.lo..=bw.hi, cycles: bw.cycles };
+                    self.push_event(event);
+                }
+            }
+            _ => {
+                self.busy_wait = if self.guest_wrote {
+                    None
+                } else {
+                    Some(BusyWait { lo: pc, hi: pc, cycles: cycles as u64, reported: false })
+                };
+            }
+        }
+    }
+
+    fn apply_pending_ticks(&mut self) {
+        let cycles = self.pending_ticks;
+        self.pending_ticks = 0;
+
+        for t in &mut self.prt_timers {
+            t.apply_ticks(cycles);
+        }
+
+        self.uart0.apply_ticks(cycles as i32);
+        self.rtc.apply_ticks(cycles);
+    }
+
+    /**
+     * Jump the RTC forward by `by`, as if that much time had passed, for
+     * testing time-dependent guest code without waiting.
+     */
+    pub fn advance_rtc(&mut self, by: std::time::Duration) {
+        self.rtc.advance(by);
+    }
+
+    /**
+     * Cold boot MOS headlessly, with a VDP auto-responder on UART0 in
+     * place of its usual link, and run until MOS prints its command prompt
+     * (a line ending " *") or `cycle_cap` cycles have passed. Returns the
+     * cycles the boot took. `cpu` is left at the prompt, and the original
+     * UART0 link is reconnected.
+     */
+    pub fn boot_to_prompt(&mut self, cpu: &mut Cpu, cycle_cap: u64) -> Result<u64, BootError> {
+        self.reset(cpu, ResetKind::Cold);
+        self.load_mos().map_err(BootError::LoadMos)?;
+
+        let output = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let link = self.uart0.replace_link(Box::new(VdpAutoResponder::new(output.clone())));
+
+        let mut cycles: u64 = 0;
+        let result = loop {
+            if is_mos_prompt(&output.borrow()) {
+                break Ok(cycles);
+            }
+            if cycles >= cycle_cap {
+                break Err(BootError::Timeout { output: String::from_utf8_lossy(&output.borrow()).into_owned() });
+            }
+            self.do_interrupts(cpu);
+            cycles += self.execute_instruction(cpu) as u64;
+            if self.triple_fault_halt {
+                break Err(BootError::TripleFault { output: String::from_utf8_lossy(&output.borrow()).into_owned() });
+            }
+        };
+
+        self.uart0.replace_link(link);
+        self.at_mos_prompt = result.is_ok();
+        result
+    }
+
+    /**
+     * Type `cmd` at the MOS prompt and run until the next prompt, returning
+     * what MOS printed in between (after the echoed command line). Boots
+     * to the prompt first, as boot_to_prompt(), unless a previous call (or
+     * boot_to_prompt) left MOS there. Running the CPU any other way in
+     * between isn't noticed, so may leave MOS elsewhere. `cycle_cap`
+     * applies to booting and to the command separately.
+     */
+    pub fn exec_command(&mut self, cpu: &mut Cpu, cmd: &str, cycle_cap: u64) -> Result<String, BootError> {
+        if !self.at_mos_prompt {
+            self.boot_to_prompt(cpu, cycle_cap)?;
+        }
+        self.at_mos_prompt = false;
+
+        let output = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let mut vdp = VdpAutoResponder::new(output.clone());
+        vdp.queue_input(&keyboard::key_packets(&keyboard::KeyboardLayout::Us, &format!("{}\r", cmd)));
+        let link = self.uart0.replace_link(Box::new(vdp));
+
+        // the prompt follows the echoed command line
+        let has_prompt = |out: &[u8]| out.contains(&b'\n') && is_mos_prompt(out);
+        let mut cycles: u64 = 0;
+        let result = loop {
+            if has_prompt(&output.borrow()) {
+                break Ok(());
+            }
+            if cycles >= cycle_cap {
+                break Err(BootError::Timeout { output: String::from_utf8_lossy(&output.borrow()).into_owned() });
+            }
+            self.do_interrupts(cpu);
+            cycles += self.execute_instruction(cpu) as u64;
+            if self.triple_fault_halt {
+                break Err(BootError::TripleFault { output: String::from_utf8_lossy(&output.borrow()).into_owned() });
+            }
+        };
+        self.uart0.replace_link(link);
+        result?;
+
+        self.at_mos_prompt = true;
+        let output = output.borrow();
+        let start = output.iter().position(|b| *b == b'\n').unwrap() + 1;
+        let end = output.iter().rposition(|b| *b == b'\n' || *b == b'\r').unwrap() + 1;
+        Ok(String::from_utf8_lossy(&output[start..end.max(start)]).into_owned())
+    }
+
+    // fill external and on-chip RAM as ram_init says
+    fn init_ram(&mut self) {
+        match self.ram_init {
+            RamInit::Random => {
+                for b in self.mem_external.iter_mut().chain(self.mem_internal.iter_mut()) {
+                    *b = rand::thread_rng().gen_range(0..=255);
+                }
+            }
+            RamInit::Zero => {
+                self.mem_external.fill(0);
+                self.mem_internal.fill(0);
+            }
+        }
+    }
+
+    /**
+     * Power the machine off and on again now: see ResetKind::PowerCycle.
+     * Use request_reset() (or a reset_handle(), from another thread)
+     * instead while the machine is running.
+     */
+    pub fn power_cycle(&mut self, cpu: &mut Cpu) {
+        self.reset(cpu, ResetKind::PowerCycle);
+    }
+
+    /**
+     * Queue a reset, to be applied by the next do_interrupts() call, so
+     * it always lands between instructions. A Reset event is emitted
+     * when it happens.
+     */
+    pub fn request_reset(&self, kind: ResetKind) {
+        self.reset_handle().request(kind);
+    }
+
+    /**
+     * A handle for requesting resets, as request_reset(), from another
+     * thread while start() runs the machine.
+     */
+    pub fn reset_handle(&self) -> ResetHandle {
+        ResetHandle(self.pending_reset.clone())
+    }
+
+    /**
+     * Take the oldest unhandled machine event, if any. Up to 1024 events
+     * are kept until polled; after that each new event drops the oldest,
+     * as counted by events_dropped().
+     */
+    pub fn poll_event(&mut self) -> Option<MachineEvent> {
+        self.events.pop_front()
+    }
+
+    /**
+     * How many events have been dropped, unpolled, from a full queue.
+     */
+    pub fn events_dropped(&self) -> u64 {
+        self.events_dropped
+    }
+
+    fn push_event(&mut self, event: MachineEvent) {
+        if self.events.len() == EVENT_QUEUE_LEN {
+            self.events.pop_front();
+            self.events_dropped += 1;
+        }
+        self.events.push_back(event);
+    }
+
+    /**
+     * Run `cpu` for up to `cycle_cap` cycles, as an iterator of the
+     * MachineEvents that happen, including UART0 transmits and out of
+     * bounds memory accesses. Iteration ends at the cap, on HALT (after
+     * MachineEvent::Halted) or on a TripleFaultAction::Halt triple fault.
+     * The machine only runs as the iterator is advanced.
+     */
+    pub fn run_events<'a>(&'a mut self, cpu: &'a mut Cpu, cycle_cap: u64) -> impl Iterator<Item = MachineEvent> + 'a {
+        self.uart0.set_tx_capture(true);
+        RunEvents { machine: self, cpu, cycles: 0, cycle_cap, done: false }
+    }
+
+    fn reset(&mut self, cpu: &mut Cpu, kind: ResetKind) {
+        match kind {
+            ResetKind::Warm => {
+                // MOS soft reset code always runs from ADL mode.
+                // and set_pc(0) will actually set pc := (mb<<16 + 0) in non-adl mode
+                cpu.state.reg.adl = true;
+                cpu.state.reg.madl = true;
+                cpu.state.set_pc(0);
+            }
+            ResetKind::Cold | ResetKind::PowerCycle => {
+                *cpu = Cpu::new_ez80();
+                cpu.state.set_pc(0);
+
+                self.onchip_mem_enable = true;
+                self.onchip_mem_segment = 0xff;
+                self.flash_addr_u = 0;
+                self.cs0_lbr = 0;
+                self.cs0_ubr = 0xff;
+                self.cs0_ctl = 0xe8;
+                self.cs1_3_regs = [0; 9];
+                for t in &mut self.prt_timers {
+                    *t = prt_timer::PrtTimer::new();
+                }
+
+                self.triple_fault_halt = false;
+                self.consecutive_faults = 0;
+
+                // MOS will remount and reopen everything
+                self.open_files.clear();
+                self.open_dirs.clear();
+                self.mos_current_dir = MosPath(std::path::PathBuf::new());
+
+                if kind == ResetKind::PowerCycle {
+                    self.init_ram();
+                    self.uart0.reset();
+                    self.uart1.reset();
+                    self.rtc = rtc::Rtc::new(rtc_cycles_per_second(self.clockspeed_hz));
+                }
+            }
+        }
+        self.at_mos_prompt = false;
+        self.push_event(MachineEvent::Reset(kind));
+    }
+
+    // dispatch to the handler at `vector`, which pushes onto the stack
+    // like any instruction and so is checked against set_stack_bounds()
+    fn interrupt(&mut self, cpu: &mut Cpu, vector: u32) {
+        let pc = cpu.state.pc();
+        let sp_before = Self::stack_address(cpu);
+        Environment::new(&mut cpu.state, self).interrupt(vector);
+        if let Some((low, high)) = self.stack_bounds {
+            self.check_stack_bounds(pc, sp_before, Self::stack_address(cpu), low, high);
+        }
+    }
+
+    #[inline]
+    pub fn do_interrupts(&mut self, cpu: &mut Cpu) {
+        // Not an interrupt. Is a reset pending?
+        if self.pending_reset.load(std::sync::atomic::Ordering::Relaxed) != 0 {
+            if let Some(kind) = ResetHandle::decode(self.pending_reset.swap(0, std::sync::atomic::Ordering::Relaxed)) {
+                self.reset(cpu, kind);
+                return;
+            }
+        }
+        if cpu.state.instructions_executed & 0xff == 0 {
+            // perform a soft reset if requested
+            if self.soft_reset.load(std::sync::atomic::Ordering::Relaxed) {
+                self.reset(cpu, ResetKind::Warm);
+                self.soft_reset.store(false, std::sync::atomic::Ordering::Relaxed);
+                return;
+            }
+        }
+
+        if cpu.state.instructions_executed & 0x3f == 0 && cpu.state.reg.get_iff1() {
+            // Interrupts in priority order
+            for i in 0..self.prt_timers.len() {
+                if self.prt_timers[i].irq_due() {
+                    self.interrupt(cpu, 0xa + 2*(i as u32));
+                    return;
+                }
+            }
+
+            // fire uart interrupt
+            if self.uart0.is_rx_interrupt_enabled() && self.uart0.maybe_fill_rx_buf() != None || // character(s) received
+               self.uart0.ier & 0x02 != 0 { // character(s) to send
+                //println!("uart interrupt!");
+                self.interrupt(cpu, 0x18); // uart0_handler
+                return;
+            }
+            // fire gpio interrupts
+            {
+                let (b_int, c_int, d_int) = {
+                    let gpios = self.gpios.lock().unwrap();
+                    let b_int = gpios.b.get_interrupt_due();
+                    let c_int = gpios.c.get_interrupt_due();
+                    let d_int = gpios.d.get_interrupt_due();
+                    (b_int, c_int, d_int)
+                };
+
+                if self.fire_gpio_interrupts(cpu, 0x30, b_int) {
+                    return;
+                }
+                if self.fire_gpio_interrupts(cpu, 0x40, c_int) {
+                    return;
+                }
+                if self.fire_gpio_interrupts(cpu, 0x50, d_int) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /**
+     * The interrupt sources currently asserted, in priority order, for
+     * display by debuggers. UART receive only shows as pending once the
+     * received byte has been taken from the link.
+     */
+    pub fn pending_interrupts(&self) -> Vec<InterruptSource> {
+        let mut sources = vec![];
+        let mut priority = 0;
+
+        for (i, t) in self.prt_timers.iter().enumerate() {
+            if t.irq_pending() {
+                sources.push(InterruptSource {
+                    interrupt: Interrupt::PrtTimer(i as u8),
+                    vector: 0xa + 2*(i as u32),
+                    enabled: t.irq_enabled(),
+                    priority,
+                });
+            }
+            priority += 1;
+        }
+
+        if self.uart0.has_rx_data() {
+            sources.push(InterruptSource {
+                interrupt: Interrupt::Uart0Rx,
+                vector: 0x18,
+                enabled: self.uart0.is_rx_interrupt_enabled(),
+                priority,
+            });
+        }
+        if self.uart0.is_tx_fifo_empty() {
+            sources.push(InterruptSource {
+                interrupt: Interrupt::Uart0Tx,
+                vector: 0x18,
+                enabled: self.uart0.is_tx_interrupt_enabled(),
+                priority,
+            });
+        }
+        priority += 1;
+
+        let gpios = self.gpios.lock().unwrap();
+        for (port, vector_base) in [(gpio::GpioPort::B, 0x30), (gpio::GpioPort::C, 0x40), (gpio::GpioPort::D, 0x50)] {
+            let due = gpios.port(port).get_interrupt_due();
+            for pin in 0..=7 {
+                if due & (1<<pin) != 0 {
+                    sources.push(InterruptSource {
+                        interrupt: Interrupt::Gpio { port, pin },
+                        vector: vector_base + 2*(pin as u32),
+                        // pins only raise interrupts when in an interrupt mode
+                        enabled: true,
+                        priority,
+                    });
+                }
+                priority += 1;
+            }
+        }
+
+        sources
+    }
+
+    #[inline]
+    fn debugger_tick(&mut self, debugger: &mut Option<debugger::DebuggerServer>, cpu: &mut Cpu) {
+        if let Some(ref mut ds) = debugger {
+            ds.tick(self, cpu);
+        }
+    }
+
+    /**
+     * Cold boot MOS and run it, paced to the configured clock speed. Only
+     * returns when a set_max_wall_time limit is reached
+     * (StopReason::WallClockTimeout) or on a triple fault with
+     * TripleFaultAction::Halt (StopReason::TripleFault).
+     */
+    pub fn start(&mut self, debugger_con: Option<debugger::DebuggerConnection>) -> StopReason {
+        let mut cpu = Cpu::new_ez80();
+
+        let mut debugger = if debugger_con.is_some() {
+            self.paused = true;
+            Some(debugger::DebuggerServer::new(debugger_con.unwrap()))
+        } else {
+            None
+        };
+
+        self.init_ram();
+
+        if let Err(e) = self.load_mos() {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+
+        cpu.state.set_pc(0);
+        //cpu.set_trace(true);
+
+        self.run(&mut cpu, &mut debugger)
+    }
+
+    // start()'s run loop, keeping emulation to clockspeed_hz
+    fn run(&mut self, cpu: &mut Cpu, debugger: &mut Option<debugger::DebuggerServer>) -> StopReason {
+        let run_start = std::time::Instant::now();
+        let out_of_time = |machine: &AgonMachine| machine.max_wall_time.is_some_and(|max| run_start.elapsed() >= max);
+
+        let cycles_per_ms: u64 = self.clockspeed_hz / 1000;
+        let mut timeslice_start = std::time::Instant::now();
+        loop {
+            // in unlimited CPU mode, this inner loop never exits
+            let mut cycle: u64 = 0;
+            let mut instructions: u32 = 0;
+            while cycle < cycles_per_ms {
+                self.debugger_tick(debugger, cpu);
+                if self.paused { break; }
+                self.do_interrupts(cpu);
+                cycle += self.execute_instruction(cpu) as u64;
+
+                if self.triple_fault_halt {
+                    return StopReason::TripleFault;
+                }
+
+                instructions = instructions.wrapping_add(1);
+                if instructions & (WALL_TIME_CHECK_INSTRUCTIONS - 1) == 0 && out_of_time(self) {
+                    return StopReason::WallClockTimeout;
+                }
+            }
+            if out_of_time(self) {
+                return StopReason::WallClockTimeout;
+            }
+
+            while timeslice_start.elapsed() < std::time::Duration::from_millis(1) {
+                std::thread::sleep(std::time::Duration::from_micros(500));
+            }
+            timeslice_start = timeslice_start.checked_add(
+                std::time::Duration::from_millis(1)
+            ).unwrap_or(std::time::Instant::now());
+        }
+    }
+}
+
+struct RunEvents<'a> {
+    machine: &'a mut AgonMachine,
+    cpu: &'a mut Cpu,
+    cycles: u64,
+    cycle_cap: u64,
+    done: bool,
+}
+
+impl RunEvents<'_> {
+    fn step(&mut self) {
+        let machine = &mut *self.machine;
+        machine.do_interrupts(self.cpu);
+        self.cycles += machine.execute_instruction(self.cpu) as u64;
+
+        let tx = machine.uart0.take_tx_captured();
+        for byte in tx {
+            machine.push_event(MachineEvent::Uart0Tx(byte));
+        }
+        if let Some(addr) = machine.mem_out_of_bounds.take() {
+            machine.push_event(MachineEvent::MemOutOfBounds { pc: machine.last_pc, addr });
+        }
+        if self.cpu.state.halted {
+            machine.push_event(MachineEvent::Halted { pc: machine.last_pc });
+            self.done = true;
+        }
+        if machine.triple_fault_halt || self.cycles >= self.cycle_cap {
+            self.done = true;
+        }
+    }
+}
+
+impl Iterator for RunEvents<'_> {
+    type Item = MachineEvent;
+
+    fn next(&mut self) -> Option<MachineEvent> {
+        loop {
+            if let Some(event) = self.machine.poll_event() {
+                return Some(event);
+            }
+            if self.done {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+impl Drop for RunEvents<'_> {
+    fn drop(&mut self) {
+        self.machine.uart0.set_tx_capture(false);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    struct NullLink;
+    impl uart::SerialLink for NullLink {
+        fn send(&mut self, _byte: u8) {}
+        fn recv(&mut self) -> Option<u8> { None }
+        fn read_clear_to_send(&mut self) -> bool { true }
+    }
+
+    // scratch areas of external RAM used by the tests
+    const TEST_STACK: u32 = 0x70000;
+    const TEST_FIL: u32 = 0x50000;
+    const TEST_STRING: u32 = 0x60000;
+
+    pub(crate) fn test_machine_config() -> AgonMachineConfig {
+        AgonMachineConfig {
+            ram_init: RamInit::Zero,
+            uart0_link: Box::new(NullLink),
+            uart1_link: Box::new(NullLink),
+            soft_reset: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            gpios: Arc::new(Mutex::new(gpio::GpioSet::new())),
+            clockspeed_hz: 18_432_000,
+            mos_bin: std::path::PathBuf::from("MOS.bin"),
+        }
+    }
+
+    pub(crate) fn test_machine() -> AgonMachine {
+        AgonMachine::new(test_machine_config())
+    }
+
+    // a fresh, empty sdcard directory
+    pub(crate) fn test_sdcard_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("agon-cpu-emulator-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn poke_string(machine: &mut AgonMachine, address: u32, s: &str) -> u32 {
+        z80_mem_tools::memcpy_to_z80(machine, address, s.as_bytes());
+        machine.poke(address + s.len() as u32, 0);
+        address
+    }
+
+    // set up the stack as it is on entry to a MOS fatfs function
+    fn setup_mos_call(machine: &mut AgonMachine, cpu: &mut Cpu, args: &[u32]) {
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::SP, TEST_STACK);
+        machine._poke24(TEST_STACK, 0x1234); // return address
+        for (i, arg) in args.iter().enumerate() {
+            machine._poke24(TEST_STACK + 3 + 3 * i as u32, *arg);
+        }
+    }
+
+    fn f_open(machine: &mut AgonMachine, cpu: &mut Cpu, fptr: u32, path: &str, mode: u32) -> u32 {
+        let path_ptr = poke_string(machine, TEST_STRING, path);
+        setup_mos_call(machine, cpu, &[fptr, path_ptr, mode]);
+        machine.hostfs_mos_call(mos::MosCall::FOpen, cpu);
+        cpu.state.reg.get24(Reg16::HL)
+    }
+
+    // yields `data`, then fails
+    struct FailingReader {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos == self.data.len() {
+                return Err(std::io::Error::other("injected read error"));
+            }
+            let n = std::cmp::min(buf.len(), self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_to_guest_partial_on_error() {
+        let mut machine = test_machine();
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut f = FailingReader { data: data.clone(), pos: 0 };
+
+        let (num_bytes_read, err) = machine.read_to_guest(&mut f, 0x40000, 2000);
+        assert_eq!(num_bytes_read, 1000);
+        assert!(err.is_some());
+        for (i, b) in data.iter().enumerate() {
+            assert_eq!(machine.peek(0x40000 + i as u32), *b);
+        }
+    }
+
+    #[test]
+    fn test_path_args_reuse_buffers() {
+        let dir = test_sdcard_dir("path_buffers");
+        std::fs::write(dir.join("data.bin"), b"x").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        for _ in 0..10 {
+            // both the success and the FR_NO_FILE exits hand the buffer back
+            assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "data.bin", 1), mos::FR_OK);
+            setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL]);
+            machine.hostfs_mos_call(mos::MosCall::FClose, &mut cpu);
+            assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "missing.bin", 1), mos::FR_NO_FILE);
+        }
+        assert_eq!(machine.path_buffers.len(), 1);
+    }
+
+    #[test]
+    fn test_f_read() {
+        let dir = test_sdcard_dir("f_read");
+        std::fs::write(dir.join("data.bin"), (0..2000).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "data.bin", 1), mos::FR_OK);
+
+        // more than the host chunk size, less than the file
+        let br = 0x58000;
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 1500, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(machine._peek24(br), 1500);
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 1500);
+        assert_eq!(machine.peek(0x40000 + 1499), (1499 % 256) as u8);
+
+        // short read at EOF
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 1500, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(machine._peek24(br), 500);
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 2000);
+    }
+
+    #[test]
+    fn test_preload_tree() {
+        let fixture = test_sdcard_dir("preload_tree_fixture");
+        std::fs::create_dir_all(fixture.join("GAMES/ELITE")).unwrap();
+        std::fs::write(fixture.join("README.TXT"), b"top").unwrap();
+        std::fs::write(fixture.join("GAMES/ELITE/ELITE.BIN"), [0xc9; 300]).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1592224440, 0);
+        filetime::set_file_mtime(fixture.join("README.TXT"), mtime).unwrap();
+        // a directory link loop isn't followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", fixture.join("GAMES/LOOP")).unwrap();
+
+        let dir = test_sdcard_dir("preload_tree");
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        machine.preload_tree("/fixture", &fixture).unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(dir.join("fixture")).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["GAMES", "README.TXT"]);
+        assert!(machine.stat_guest_path("/fixture/games/elite").unwrap().is_dir);
+        assert!(machine.stat_guest_path("/fixture/games/loop").is_err());
+        let readme = machine.stat_guest_path("/fixture/readme.txt").unwrap();
+        assert_eq!(filetime::FileTime::from_system_time(readme.modified.unwrap()), mtime);
+
+        // and the guest reads the contents
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "/fixture/games/elite/elite.bin", 1), mos::FR_OK);
+        let br = 0x58000;
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 512, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(machine._peek24(br), 300);
+        assert_eq!(guest_bytes(&machine, 0x40000, 300), vec![0xc9; 300]);
+    }
+
+    #[test]
+    fn test_peek_file() {
+        let dir = test_sdcard_dir("peek_file");
+        std::fs::write(dir.join("data.bin"), b"0123456789").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "data.bin", 1), mos::FR_OK);
+        assert_eq!(machine.peek_file(TEST_FIL, 4).unwrap(), b"0123");
+        assert_eq!(machine.peek_file(TEST_FIL, 4).unwrap(), b"0123");
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 0);
+
+        let br = 0x58000;
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 6, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(machine._peek24(br), 6);
+        assert_eq!(guest_bytes(&machine, 0x40000, 6), b"012345");
+
+        // short at EOF
+        assert_eq!(machine.peek_file(TEST_FIL, 10).unwrap(), b"6789");
+        assert_eq!(machine.peek_file(TEST_FIL + 0x100, 1).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_export_import_open_files() {
+        let dir = test_sdcard_dir("export_open_files");
+        std::fs::create_dir(dir.join("SUB")).unwrap();
+        std::fs::write(dir.join("SUB/DATA.TXT"), b"hello world").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "sub/data.txt", 1), mos::FR_OK);
+
+        let br = 0x58000;
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 6, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(machine._peek24(br), 6);
+
+        let table = machine.export_open_files().unwrap();
+
+        // move to an equivalent sdcard directory, and do away with the old one
+        let copy = test_sdcard_dir("export_open_files_copy");
+        std::fs::create_dir(copy.join("SUB")).unwrap();
+        std::fs::copy(dir.join("SUB/DATA.TXT"), copy.join("SUB/DATA.TXT")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        machine.set_sdcard_directory(copy);
+        machine.import_open_files(&table).unwrap();
+
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 100, br]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(machine._peek24(br), 5);
+        assert_eq!((0..5).map(|i| machine.peek(0x40000 + i)).collect::<Vec<u8>>(), b"world");
+
+        assert!(machine.import_open_files("{").is_err());
+    }
+
+    fn f_gets(machine: &mut AgonMachine, cpu: &mut Cpu, buf: u32, len: u32) -> u32 {
+        setup_mos_call(machine, cpu, &[buf, len, TEST_FIL]);
+        machine.hostfs_mos_call(mos::MosCall::FGets, cpu);
+        cpu.state.reg.get24(Reg16::HL)
+    }
+
+    fn guest_bytes(machine: &AgonMachine, address: u32, len: u32) -> Vec<u8> {
+        (0..len).map(|i| machine.peek(address + i)).collect()
+    }
+
+    #[test]
+    fn test_f_gets_nul() {
+        let dir = test_sdcard_dir("f_gets_nul");
+        std::fs::write(dir.join("nul.txt"), b"ab\0cd\r\nxyz").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        let buf = 0x40000;
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "nul.txt", 1), mos::FR_OK);
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 64), buf);
+        assert_eq!(guest_bytes(&machine, buf, 3), b"ab\0");
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 64), buf);
+        assert_eq!(guest_bytes(&machine, buf, 5), b"cd\r\n\0");
+        // last line has no newline
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 64), buf);
+        assert_eq!(guest_bytes(&machine, buf, 4), b"xyz\0");
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 64), 0);
+
+        // as FatFS with FF_USE_STRFUNC = 2
+        machine.set_f_gets_config(mos::FGetsConfig { stop_at_nul: false, strip_cr: true });
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "nul.txt", 1), mos::FR_OK);
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 64), buf);
+        assert_eq!(guest_bytes(&machine, buf, 7), b"ab\0cd\n\0");
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 7);
+    }
+
+    #[test]
+    fn test_f_gets_long_line() {
+        let dir = test_sdcard_dir("f_gets_long_line");
+        std::fs::write(dir.join("long.txt"), [b'x'; 100].iter().chain(b"\n")
+            .copied().collect::<Vec<u8>>()).unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        let buf = 0x40000;
+        z80_mem_tools::memset(&mut machine, buf, 0xaa, 16);
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "long.txt", 1), mos::FR_OK);
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 10), buf);
+        // 9 characters and the terminator, and nothing beyond the buffer
+        assert_eq!(guest_bytes(&machine, buf, 11), b"xxxxxxxxx\0\xaa");
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 9);
+
+        // the rest of the line follows
+        for _ in 0..11 {
+            assert_eq!(f_gets(&mut machine, &mut cpu, buf, 10), buf);
+        }
+        assert_eq!(guest_bytes(&machine, buf, 3), b"x\n\0");
+        assert_eq!(f_gets(&mut machine, &mut cpu, buf, 10), 0);
+    }
+
+    #[test]
+    fn test_write_through() {
+        let dir = test_sdcard_dir("write_through");
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        machine.set_write_through(true);
+        let mut cpu = Cpu::new_ez80();
+        // FA_WRITE | FA_CREATE_ALWAYS
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "log.txt", 0x0a), mos::FR_OK);
+
+        let bw = 0x58000;
+        let mut expected = vec![];
+        for chunk in ["first ", "second"] {
+            poke_string(&mut machine, 0x40000, chunk);
+            setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, chunk.len() as u32, bw]);
+            machine.hostfs_mos_call(mos::MosCall::FWrite, &mut cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+
+            // on disk without f_sync or f_close
+            expected.extend_from_slice(chunk.as_bytes());
+            assert_eq!(std::fs::read(dir.join("log.txt")).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_hostfs_sdcard_overlay() {
+        let dir = test_sdcard_dir("hostfs_sdcard_overlay");
+        std::fs::write(dir.join("a.txt"), b"original").unwrap();
+        std::fs::write(dir.join("b.txt"), b"doomed").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        machine.set_sdcard_overlay(true).unwrap();
+        let scratch = machine.hostfs_overlay.as_ref().unwrap().scratch.clone();
+        let mut cpu = Cpu::new_ez80();
+        let write_file = |machine: &mut AgonMachine, cpu: &mut Cpu, path: &str, mode: u32, data: &str| {
+            assert_eq!(f_open(machine, cpu, TEST_FIL, path, mode), mos::FR_OK);
+            poke_string(machine, 0x40000, data);
+            setup_mos_call(machine, cpu, &[TEST_FIL, 0x40000, data.len() as u32, 0x58000]);
+            machine.hostfs_mos_call(mos::MosCall::FWrite, cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+            setup_mos_call(machine, cpu, &[TEST_FIL]);
+            machine.hostfs_mos_call(mos::MosCall::FClose, cpu);
+        };
+        let read_file = |machine: &mut AgonMachine, cpu: &mut Cpu, path: &str| {
+            assert_eq!(f_open(machine, cpu, TEST_FIL, path, 1), mos::FR_OK);
+            let data = machine.peek_file(TEST_FIL, 100).unwrap();
+            setup_mos_call(machine, cpu, &[TEST_FIL]);
+            machine.hostfs_mos_call(mos::MosCall::FClose, cpu);
+            data
+        };
+
+        // FA_WRITE, then FA_WRITE | FA_CREATE_ALWAYS, and a delete
+        write_file(&mut machine, &mut cpu, "a.txt", 0x02, "changed!");
+        write_file(&mut machine, &mut cpu, "new.txt", 0x0a, "new");
+        poke_string(&mut machine, TEST_STRING, "b.txt");
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_STRING]);
+        machine.hostfs_mos_call(mos::MosCall::FUnlink, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+
+        // the guest sees its changes
+        assert_eq!(read_file(&mut machine, &mut cpu, "a.txt"), b"changed!");
+        assert_eq!(read_file(&mut machine, &mut cpu, "new.txt"), b"new");
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "b.txt", 1), mos::FR_NO_FILE);
+
+        // but the real sdcard is untouched
+        let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"original");
+        assert_eq!(std::fs::read(dir.join("b.txt")).unwrap(), b"doomed");
+
+        // disabling discards the changes
+        machine.set_sdcard_overlay(false).unwrap();
+        assert!(!scratch.exists());
+        assert_eq!(read_file(&mut machine, &mut cpu, "a.txt"), b"original");
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "new.txt", 1), mos::FR_NO_FILE);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_hostfs_overlay_removes_stale() {
+        // left by a process that has gone (pids don't get this high)
+        let stale = std::env::temp_dir().join(format!("{}{}-0", HOSTFS_OVERLAY_PREFIX, u32::MAX - 1));
+        std::fs::create_dir_all(stale.join("sub")).unwrap();
+        let ours = HostfsOverlay::new(&test_sdcard_dir("hostfs_overlay_removes_stale")).unwrap();
+
+        HostfsOverlay::remove_stale();
+        assert!(!stale.exists());
+        assert!(ours.scratch.exists());
+        assert!(ours.scratch.starts_with(std::env::temp_dir()));
+    }
+
+    #[test]
+    fn test_f_open_read_write() {
+        let dir = test_sdcard_dir("f_open_read_write");
+        std::fs::write(dir.join("data.bin"), b"0123456789").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+        let rw = 0x58000;
+        // FA_READ | FA_WRITE
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "data.bin", 0x03), mos::FR_OK);
+
+        let f_read = |machine: &mut AgonMachine, cpu: &mut Cpu, len: u32| {
+            setup_mos_call(machine, cpu, &[TEST_FIL, 0x40000, len, rw]);
+            machine.hostfs_mos_call(mos::MosCall::FRead, cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+            guest_bytes(machine, 0x40000, machine._peek24(rw))
+        };
+        let f_write = |machine: &mut AgonMachine, cpu: &mut Cpu, data: &str| {
+            poke_string(machine, 0x40000, data);
+            setup_mos_call(machine, cpu, &[TEST_FIL, 0x40000, data.len() as u32, rw]);
+            machine.hostfs_mos_call(mos::MosCall::FWrite, cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        };
+
+        assert_eq!(f_read(&mut machine, &mut cpu, 4), b"0123");
+        f_write(&mut machine, &mut cpu, "ab");
+        assert_eq!(f_read(&mut machine, &mut cpu, 2), b"67");
+        // not truncated, and writing past the end grows the file
+        f_write(&mut machine, &mut cpu, "cdefg");
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_FPTR), 13);
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_OBJSIZE), 13);
+
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL]);
+        machine.hostfs_mos_call(mos::MosCall::FClose, &mut cpu);
+        assert_eq!(std::fs::read(dir.join("data.bin")).unwrap(), b"0123ab67cdefg");
+    }
+
+    #[test]
+    fn test_access_log() {
+        let mut machine = test_machine();
+        machine.set_access_log(Some(3));
+        machine.last_pc = 0x40000;
+        machine.poke(0x50000, 1);
+        machine.poke(0x50001, 2);
+        machine.last_pc = 0x40004;
+        machine.peek(0x50000);
+        machine.poke(0x50002, 3);
+
+        let access = |addr, value, access| MemAccess { pc: 0x40000, addr, value, access };
+        assert_eq!(machine.recent_accesses(), vec![
+            access(0x50001, 2, MemAccessKind::Write),
+            MemAccess { pc: 0x40004, ..access(0x50000, 1, MemAccessKind::Read) },
+            MemAccess { pc: 0x40004, ..access(0x50002, 3, MemAccessKind::Write) },
+        ]);
+
+        machine.set_access_log(None);
+        machine.peek(0x50000);
+        assert!(machine.recent_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_f_rename_moves_cwd() {
+        let dir = test_sdcard_dir("f_rename_moves_cwd");
+        std::fs::create_dir_all(dir.join("projects/game/src")).unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        let path_call = |machine: &mut AgonMachine, cpu: &mut Cpu, call, paths: &[&str]| {
+            let mut args = vec![];
+            let mut ptr = TEST_STRING;
+            for p in paths {
+                args.push(poke_string(machine, ptr, p));
+                ptr += p.len() as u32 + 1;
+            }
+            setup_mos_call(machine, cpu, &args);
+            machine.hostfs_mos_call(call, cpu);
+            cpu.state.reg.get24(Reg16::HL)
+        };
+        let getcwd = |machine: &mut AgonMachine, cpu: &mut Cpu| {
+            setup_mos_call(machine, cpu, &[0x40000, 256]);
+            machine.hostfs_mos_call(mos::MosCall::FGetcwd, cpu);
+            let len = (0..256).position(|i| machine.peek(0x40000 + i) == 0).unwrap() as u32;
+            String::from_utf8(guest_bytes(machine, 0x40000, len)).unwrap()
+        };
+
+        assert_eq!(path_call(&mut machine, &mut cpu, mos::MosCall::FChdir, &["projects/game/src"]), mos::FR_OK);
+        assert_eq!(path_call(&mut machine, &mut cpu, mos::MosCall::FRename, &["/projects", "/old"]), mos::FR_OK);
+        assert_eq!(getcwd(&mut machine, &mut cpu), "/old/game/src");
+
+        // renaming something that doesn't contain the cwd leaves it alone
+        std::fs::create_dir(dir.join("other")).unwrap();
+        assert_eq!(path_call(&mut machine, &mut cpu, mos::MosCall::FRename, &["/other", "/others"]), mos::FR_OK);
+        assert_eq!(getcwd(&mut machine, &mut cpu), "/old/game/src");
+        assert_eq!(path_call(&mut machine, &mut cpu, mos::MosCall::FChdir, &[".."]), mos::FR_OK);
+        assert_eq!(getcwd(&mut machine, &mut cpu), "/old/game");
+    }
+
+    #[test]
+    fn test_f_open_relative_to_cwd() {
+        let dir = test_sdcard_dir("f_open_relative_to_cwd");
+        std::fs::create_dir(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("top.txt"), b"top").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+        let path_ptr = poke_string(&mut machine, TEST_STRING, "SubDir");
+        setup_mos_call(&mut machine, &mut cpu, &[path_ptr]);
+        machine.hostfs_mos_call(mos::MosCall::FChdir, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+
+        // FA_WRITE | FA_CREATE_ALWAYS
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", 0x0a), mos::FR_OK);
+        assert!(dir.join("subdir/file.txt").is_file());
+        assert!(!dir.join("file.txt").exists());
+
+        // .. can't climb out of the sd card root
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x100, "../../top.txt", 1), mos::FR_OK);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x200, "/subdir/file.txt", 1), mos::FR_OK);
+    }
+
+    #[test]
+    fn test_long_file_names_disabled() {
+        let dir = test_sdcard_dir("long_file_names_disabled");
+        std::fs::write(dir.join("existing_long_name.txt"), b"").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        machine.set_long_file_names(false);
+        let mut cpu = Cpu::new_ez80();
+        let create = mos::FA_WRITE | mos::FA_CREATE_ALWAYS;
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "GAME.BIN", create), mos::FR_OK);
+        assert!(dir.join("GAME.BIN").is_file());
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x100, "a_long_filename.txt", create), mos::FR_INVALID_NAME);
+        assert!(!dir.join("a_long_filename.txt").exists());
+        // opening existing files isn't affected
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x200, "existing_long_name.txt", 1), mos::FR_OK);
+
+        let path_ptr = poke_string(&mut machine, TEST_STRING, "directory.long");
+        setup_mos_call(&mut machine, &mut cpu, &[path_ptr]);
+        machine.hostfs_mos_call(mos::MosCall::FMkdir, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_INVALID_NAME);
+
+        machine.set_long_file_names(true);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x300, "a_long_filename.txt", create), mos::FR_OK);
+    }
+
+    #[test]
+    fn test_max_wall_time() {
+        for clockspeed_hz in [18_432_000, u64::MAX] {
+            let mut machine = AgonMachine::new(AgonMachineConfig { clockspeed_hz, ..test_machine_config() });
+            // jr $
+            machine.poke(0x40000, 0x18);
+            machine.poke(0x40001, 0xfe);
+            let mut cpu = Cpu::new_ez80();
+            cpu.state.reg.adl = true;
+            cpu.state.set_pc(0x40000);
+
+            machine.set_max_wall_time(Some(std::time::Duration::from_millis(50)));
+            let started = std::time::Instant::now();
+            assert_eq!(machine.run(&mut cpu, &mut None), StopReason::WallClockTimeout);
+            let elapsed = started.elapsed();
+            assert!(elapsed >= std::time::Duration::from_millis(50) && elapsed < std::time::Duration::from_secs(5));
+            assert_eq!(cpu.state.pc(), 0x40000);
+        }
+    }
+
+    #[test]
+    fn test_f_chmod() {
+        let dir = test_sdcard_dir("f_chmod");
+        std::fs::write(dir.join("notes.txt"), b"hi").unwrap();
+        let mut cpu = Cpu::new_ez80();
+        let f_chmod = |machine: &mut AgonMachine, cpu: &mut Cpu, path: &str, attr: u8, mask: u8| {
+            let path_ptr = poke_string(machine, TEST_STRING, path);
+            setup_mos_call(machine, cpu, &[path_ptr, attr as u32, mask as u32]);
+            machine.hostfs_mos_call(mos::MosCall::FChmod, cpu);
+            cpu.state.reg.get24(Reg16::HL)
+        };
+        let f_stat_attrib = |machine: &mut AgonMachine, cpu: &mut Cpu, path: &str| {
+            let path_ptr = poke_string(machine, TEST_STRING, path);
+            setup_mos_call(machine, cpu, &[path_ptr, 0x40000]);
+            machine.hostfs_mos_call(mos::MosCall::FStat, cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+            machine.peek(0x40000 + mos::FILINFO_MEMBER_FATTRIB_U8)
+        };
+
+        let all = mos::AM_RDO | mos::AM_HID | mos::AM_SYS | mos::AM_ARC;
+        {
+            let mut machine = test_machine();
+            machine.set_sdcard_directory(dir.clone());
+            assert_eq!(f_chmod(&mut machine, &mut cpu, "notes.txt", mos::AM_RDO | mos::AM_HID, all), mos::FR_OK);
+            assert_eq!(f_stat_attrib(&mut machine, &mut cpu, "NOTES.TXT"), mos::AM_RDO | mos::AM_HID);
+            assert!(std::fs::metadata(dir.join("notes.txt")).unwrap().permissions().readonly());
+
+            // the mask limits what changes
+            assert_eq!(f_chmod(&mut machine, &mut cpu, "notes.txt", mos::AM_ARC, mos::AM_ARC), mos::FR_OK);
+        }
+
+        // the store persists, without being listed
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        assert_eq!(f_stat_attrib(&mut machine, &mut cpu, "notes.txt"), mos::AM_RDO | mos::AM_HID | mos::AM_ARC);
+        assert_eq!(f_opendir(&mut machine, &mut cpu, 0x50000, "/"), mos::FR_OK);
+        let mut names = vec![];
+        loop {
+            setup_mos_call(&mut machine, &mut cpu, &[0x50000, 0x40000]);
+            machine.hostfs_mos_call(mos::MosCall::FReaddir, &mut cpu);
+            let name = 0x40000 + mos::FILINFO_MEMBER_FNAME_256BYTES;
+            let len = (0..256).position(|i| machine.peek(name + i) == 0).unwrap() as u32;
+            if len == 0 { break }
+            names.push(guest_bytes(&machine, name, len));
+        }
+        assert_eq!(names, vec![b"notes.txt".to_vec()]);
+
+        assert_eq!(f_chmod(&mut machine, &mut cpu, "notes.txt", 0, mos::AM_RDO), mos::FR_OK);
+        assert!(!std::fs::metadata(dir.join("notes.txt")).unwrap().permissions().readonly());
+        assert_eq!(f_chmod(&mut machine, &mut cpu, "missing.txt", 0, mos::AM_HID), mos::FR_NO_FILE);
+    }
+
+    #[test]
+    fn test_fat_attributes_rename_unlink() {
+        let dir = test_sdcard_dir("fat_attributes_rename_unlink");
+        std::fs::create_dir(dir.join("SUB")).unwrap();
+        std::fs::write(dir.join("SUB/A.TXT"), b"a").unwrap();
+        std::fs::write(dir.join("B.TXT"), b"b").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+        let call = |machine: &mut AgonMachine, cpu: &mut Cpu, call: mos::MosCall, paths: &[&str], args: &[u32]| {
+            let mut all = vec![];
+            for (i, path) in paths.iter().enumerate() {
+                all.push(poke_string(machine, TEST_STRING + 0x100 * i as u32, path));
+            }
+            all.extend(args);
+            setup_mos_call(machine, cpu, &all);
+            machine.hostfs_mos_call(call, cpu);
+            cpu.state.reg.get24(Reg16::HL)
+        };
+        let attrib = |machine: &mut AgonMachine, cpu: &mut Cpu, path: &str| {
+            assert_eq!(call(machine, cpu, mos::MosCall::FStat, &[path], &[0x40000]), mos::FR_OK);
+            machine.peek(0x40000 + mos::FILINFO_MEMBER_FATTRIB_U8)
+        };
+
+        assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FChmod, &["sub/a.txt"], &[mos::AM_HID as u32, mos::AM_HID as u32]), mos::FR_OK);
+        assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FChmod, &["b.txt"], &[mos::AM_SYS as u32, mos::AM_SYS as u32]), mos::FR_OK);
+
+        // attributes follow renamed files, and files in renamed directories
+        assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FRename, &["sub", "newsub"], &[]), mos::FR_OK);
+        assert_eq!(attrib(&mut machine, &mut cpu, "newsub/a.txt"), mos::AM_HID);
+        assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FRename, &["b.txt", "c.txt"], &[]), mos::FR_OK);
+        assert_eq!(attrib(&mut machine, &mut cpu, "c.txt"), mos::AM_SYS);
+
+        // and go with deleted ones
+        assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FUnlink, &["c.txt"], &[]), mos::FR_OK);
+        std::fs::write(dir.join("c.txt"), b"new").unwrap();
+        assert_eq!(attrib(&mut machine, &mut cpu, "c.txt"), 0);
+        std::fs::write(dir.join("b.txt"), b"new").unwrap();
+        assert_eq!(attrib(&mut machine, &mut cpu, "b.txt"), 0);
+
+        // the store itself can't be reached
+        for name in [FAT_ATTRIB_FILE, "/.AGON-FAT-ATTRIBUTES.JSON"] {
+            assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FStat, &[name], &[0x40000]), mos::FR_NO_FILE);
+            assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, name, 1), mos::FR_NO_FILE);
+            assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FUnlink, &[name], &[]), mos::FR_DENIED);
+            assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FRename, &["newsub", name], &[]), mos::FR_DENIED);
+            assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FMkdir, &[name], &[]), mos::FR_DENIED);
+            assert_eq!(call(&mut machine, &mut cpu, mos::MosCall::FChdir, &[name], &[]), mos::FR_NO_FILE);
+        }
+        assert!(dir.join(FAT_ATTRIB_FILE).exists());
+        assert_eq!(attrib(&mut machine, &mut cpu, "newsub/a.txt"), mos::AM_HID);
+    }
+
+    #[test]
+    fn test_f_utime() {
+        let dir = test_sdcard_dir("f_utime");
+        std::fs::write(dir.join("game.bin"), b"data").unwrap();
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        // 1999-12-31 23:59:58
+        let (fdate, ftime) = ((19 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29);
+        let filinfo = 0x40000;
+        let poke16 = |machine: &mut AgonMachine, address: u32, v: u16| z80_mem_tools::memcpy_to_z80(machine, address, &v.to_le_bytes());
+        let peek16 = |machine: &AgonMachine, address: u32| u16::from_le_bytes([machine.peek(address), machine.peek(address + 1)]);
+        poke16(&mut machine, filinfo + mos::FILINFO_MEMBER_FDATE_U16, fdate);
+        poke16(&mut machine, filinfo + mos::FILINFO_MEMBER_FTIME_U16, ftime);
+        let path_ptr = poke_string(&mut machine, TEST_STRING, "GAME.BIN");
+        setup_mos_call(&mut machine, &mut cpu, &[path_ptr, filinfo]);
+        machine.hostfs_mos_call(mos::MosCall::FUtime, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+
+        setup_mos_call(&mut machine, &mut cpu, &[path_ptr, 0x41000]);
+        machine.hostfs_mos_call(mos::MosCall::FStat, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(peek16(&machine, 0x41000 + mos::FILINFO_MEMBER_FDATE_U16), fdate);
+        assert_eq!(peek16(&machine, 0x41000 + mos::FILINFO_MEMBER_FTIME_U16), ftime);
+
+        // month 13
+        poke16(&mut machine, filinfo + mos::FILINFO_MEMBER_FDATE_U16, (19 << 9) | (13 << 5) | 1);
+        setup_mos_call(&mut machine, &mut cpu, &[path_ptr, filinfo]);
+        machine.hostfs_mos_call(mos::MosCall::FUtime, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_power_cycle() {
+        let mut machine = test_machine();
+        let mut cpu = Cpu::new_ez80();
+        machine.poke(0x40000, 0x55);
+        machine.poke(0xbffff, 0xaa);
+        machine.poke(0xffe000, 0x12);
+        machine.port_out(0xc3, 0x80); // UART0 DLAB
+        let year = |machine: &mut AgonMachine| machine.port_in(0xe7) as u32 * 100 + machine.port_in(0xe6) as u32;
+        let this_year = year(&mut machine);
+        machine.advance_rtc(std::time::Duration::from_secs(400 * 86400));
+        assert_ne!(year(&mut machine), this_year);
+
+        machine.request_reset(ResetKind::Cold);
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(machine.peek(0x40000), 0x55);
+
+        cpu.state.set_pc(0x40000);
+        machine.power_cycle(&mut cpu);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::Cold)));
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::PowerCycle)));
+        assert_eq!(cpu.state.pc(), 0);
+        assert!(machine.mem_external.iter().all(|b| *b == 0));
+        assert!(machine.mem_internal.iter().all(|b| *b == 0));
+        assert_eq!(machine.port_in(0xc3), 0);
+        // re-seeded from host time (allowing for the year turning meanwhile)
+        assert!(year(&mut machine) - this_year <= 1);
+    }
+
+    #[test]
+    fn test_triple_fault() {
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::SP, TEST_STACK);
+        {
+            let mut machine = test_machine();
+            // a wild jump into unmapped memory: each fetch faults
+            cpu.state.set_pc(0x300000);
+
+            machine.set_triple_fault_detection(Some((16, TripleFaultAction::Halt)));
+            assert_eq!(machine.run(&mut cpu, &mut None), StopReason::TripleFault);
+            assert_eq!(cpu.state.pc(), 0x300010);
+            assert_eq!(machine.poll_event(), Some(MachineEvent::TripleFault { pc: 0x30000f }));
+        }
+
+        // faults must be consecutive
+        let mut machine = test_machine();
+        machine.set_triple_fault_detection(Some((3, TripleFaultAction::Reset)));
+        for pc in [0x300000, 0x300001, 0x40000, 0x300000, 0x300001] {
+            machine.poke(0x40000, 0); // nop
+            cpu.state.set_pc(pc);
+            machine.execute_instruction(&mut cpu);
+            machine.do_interrupts(&mut cpu);
+        }
+        assert_eq!(machine.poll_event(), None);
+
+        cpu.state.set_pc(0x300002);
+        machine.execute_instruction(&mut cpu);
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::TripleFault { pc: 0x300002 }));
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::Cold)));
+        assert_eq!(cpu.state.pc(), 0);
+    }
+
+    #[test]
+    fn test_load_auto() {
+        let dir = test_sdcard_dir("load_auto");
+        std::fs::write(dir.join("prog.hex"), ":020000040004F6\n:0400000001020304F2\n:00000001FF\n").unwrap();
+        std::fs::write(dir.join("prog.bin"), [0x3e, 0x3a, 0xc9]).unwrap();
+
+        let mut machine = test_machine();
+        machine.load_auto(&dir.join("prog.hex"), 0x50000).unwrap();
+        assert_eq!((0..4).map(|i| machine.peek(0x40000 + i)).collect::<Vec<u8>>(), vec![1, 2, 3, 4]);
+        assert_eq!(machine.peek(0x50000), 0);
+
+        machine.load_auto(&dir.join("prog.bin"), 0x50000).unwrap();
+        assert_eq!((0..3).map(|i| machine.peek(0x50000 + i)).collect::<Vec<u8>>(), vec![0x3e, 0x3a, 0xc9]);
+
+        assert!(machine.load_auto(&dir.join("missing.bin"), 0x50000).is_err());
+    }
+
+    #[test]
+    fn test_stat_guest_path() {
+        let dir = test_sdcard_dir("stat_guest_path");
+        std::fs::create_dir(dir.join("Games")).unwrap();
+        std::fs::write(dir.join("Games/Elite.bin"), [0u8; 1234]).unwrap();
+        // 2020-06-15 12:34:00 UTC
+        let mtime = 1592224440;
+        filetime::set_file_mtime(dir.join("Games/Elite.bin"), filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+
+        let info = machine.stat_guest_path("/games/ELITE.BIN").unwrap();
+        assert_eq!(info.size, 1234);
+        assert!(!info.is_dir);
+        assert_eq!(info.attrib, 0);
+        let local = chrono::DateTime::from_timestamp(mtime, 0).unwrap()
+            .with_timezone(&chrono::Local::now().timezone());
+        assert_eq!(info.fdate >> 9, (local.year() - 1980) as u16);
+        assert_eq!((info.fdate >> 5) & 0xf, local.month() as u16);
+        assert_eq!(info.fdate & 0x1f, local.day() as u16);
+        assert_eq!(info.ftime >> 11, local.hour() as u16);
+        assert_eq!((info.ftime >> 5) & 0x3f, local.minute() as u16);
+        assert_eq!(info.ftime & 0x1f, 0);
+        assert_eq!(info.modified, Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64)));
+
+        let info = machine.stat_guest_path("games").unwrap();
+        assert!(info.is_dir);
+        assert_eq!(info.attrib, 0x10);
+
+        assert_eq!(machine.stat_guest_path("games/missing.bin").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_update_access_time() {
+        let dir = test_sdcard_dir("atime");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+        let long_ago = filetime::FileTime::from_unix_time(946684800, 0); // 2000-01-01
+        filetime::set_file_atime(dir.join("FILE.TXT"), long_ago).unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        // off by default
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", 1), mos::FR_OK);
+        let before = machine.stat_guest_path("file.txt").unwrap();
+        assert_eq!(before.size, 5);
+        assert!(!before.is_dir);
+        if let Some(accessed) = before.accessed {
+            assert_eq!(accessed, std::time::UNIX_EPOCH + std::time::Duration::from_secs(946684800));
+        }
+
+        machine.set_update_access_time(true);
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 5, 0x58000]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+
+        let after = machine.stat_guest_path("/FILE.TXT").unwrap();
+        if let (Some(before), Some(after)) = (before.accessed, after.accessed) {
+            assert!(after > before);
+        }
+        assert!(machine.stat_guest_path("missing.txt").is_err());
+    }
+
+    #[test]
+    fn test_request_reset() {
+        let dir = test_sdcard_dir("reset");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", 1), mos::FR_OK);
+
+        cpu.state.reg.adl = false;
+        cpu.state.set_pc(0x1234);
+        machine.request_reset(ResetKind::Warm);
+        // nothing happens until the instruction boundary
+        assert_eq!(cpu.state.pc(), 0x1234);
+        assert_eq!(machine.poll_event(), None);
+
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(cpu.state.pc(), 0);
+        assert!(cpu.state.reg.adl);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::Warm)));
+        assert_eq!(machine.poll_event(), None);
+        // warm reset leaves hostfs state alone
+        assert!(machine.open_files.contains_key(&TEST_FIL));
+
+        machine.port_out(0xf7, 0x01); // FLASH_ADDR_U
+        machine.poke(0x40000, 0xaa);
+        cpu.state.set_pc(0x1234);
+        machine.request_reset(ResetKind::Cold);
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(cpu.state.pc(), 0);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::Cold)));
+        assert!(machine.open_files.is_empty());
+        assert_eq!(machine.flash_addr_u, 0);
+        // RAM survives
+        assert_eq!(machine.peek(0x40000), 0xaa);
+    }
+
+    #[test]
+    fn test_reset_handle_during_run() {
+        let mut machine = test_machine();
+        // jr $
+        machine.poke(0x40000, 0x18);
+        machine.poke(0x40001, 0xfe);
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.set_pc(0x40000);
+        machine.set_max_wall_time(Some(std::time::Duration::from_millis(200)));
+
+        let handle = machine.reset_handle();
+        let requester = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            handle.request(ResetKind::Warm);
+        });
+        assert_eq!(machine.run(&mut cpu, &mut None), StopReason::WallClockTimeout);
+        requester.join().unwrap();
+        assert_eq!(machine.poll_event(), Some(MachineEvent::Reset(ResetKind::Warm)));
+    }
+
+    #[test]
+    fn test_event_queue_drops_oldest() {
+        let mut machine = test_machine();
+        let mut cpu = Cpu::new_ez80();
+        for _ in 0..EVENT_QUEUE_LEN + 2 {
+            machine.request_reset(ResetKind::Warm);
+            machine.do_interrupts(&mut cpu);
+        }
+        machine.request_reset(ResetKind::Cold);
+        machine.do_interrupts(&mut cpu);
+        assert_eq!(machine.events_dropped(), 3);
+
+        let events: Vec<MachineEvent> = std::iter::from_fn(|| machine.poll_event()).collect();
+        assert_eq!(events.len(), EVENT_QUEUE_LEN);
+        assert_eq!(events.last(), Some(&MachineEvent::Reset(ResetKind::Cold)));
+    }
+
+    #[test]
+    fn test_star_command() {
+        let mut machine = test_machine();
+        let mut cpu = Cpu::new_ez80();
+        let args_seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        {
+            let args_seen = args_seen.clone();
+            machine.set_star_command("hello", Some(Box::new(move |args: &str| {
+                args_seen.borrow_mut().push(args.to_string());
+                0
+            })));
+        }
+
+        let line = poke_string(&mut machine, TEST_STRING, "*HELLO big world\r");
+        setup_mos_call(&mut machine, &mut cpu, &[line, 1]);
+        cpu.state.set_pc(0x2000);
+        assert!(machine.host_star_command(&mut cpu));
+        assert_eq!(*args_seen.borrow(), vec!["big world".to_string()]);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), 0);
+        assert_eq!(cpu.state.pc(), 0x1234);
+
+        // other commands are left to MOS
+        let line = poke_string(&mut machine, TEST_STRING, "*cat");
+        setup_mos_call(&mut machine, &mut cpu, &[line, 1]);
+        cpu.state.set_pc(0x2000);
+        assert!(!machine.host_star_command(&mut cpu));
+        assert_eq!(cpu.state.pc(), 0x2000);
+
+        machine.set_star_command("HELLO", None);
+        let line = poke_string(&mut machine, TEST_STRING, "hello");
+        setup_mos_call(&mut machine, &mut cpu, &[line, 1]);
+        assert!(!machine.host_star_command(&mut cpu));
+        assert_eq!(args_seen.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_boot_to_prompt_triple_fault() {
+        let dir = test_sdcard_dir("boot_triple_fault");
+        // print "?", then jump into unmapped memory
+        std::fs::write(dir.join("MOS.bin"), [0x31, 0x00, 0x00, 0x07, 0x3e, b'?', 0xed, 0x39, 0xc0, 0x5b, 0xc3, 0x00, 0x00, 0x30]).unwrap();
+        let mut machine = AgonMachine::new(AgonMachineConfig {
+            mos_bin: dir.join("MOS.bin"),
+            ..test_machine_config()
+        });
+        machine.set_triple_fault_detection(Some((16, TripleFaultAction::Halt)));
+        assert_eq!(machine.boot_to_prompt(&mut Cpu::new_ez80(), 100_000), Err(BootError::TripleFault { output: "?".to_string() }));
+    }
+
+    #[test]
+    fn test_boot_to_prompt() {
+        let dir = test_sdcard_dir("boot_to_prompt");
+        let boot = |flash: &[u8]| {
+            std::fs::write(dir.join("MOS.bin"), flash).unwrap();
+            let mut machine = AgonMachine::new(AgonMachineConfig {
+                mos_bin: dir.join("MOS.bin"),
+                ..test_machine_config()
+            });
+            machine.boot_to_prompt(&mut Cpu::new_ez80(), 100_000)
+        };
+
+        // enable the UART fifo, print "\r\n/ *" and wait for input
+        let mut mos = vec![0x3e, 0x01, 0xed, 0x39, 0xc2];
+        for ch in b"\r\n/ *" {
+            mos.extend_from_slice(&[0x3e, *ch, 0xed, 0x39, 0xc0]);
+        }
+        mos.extend_from_slice(&[0x18, 0xfe]);
+        let cycles = boot(&mos).unwrap();
+        // the code, then sending the last byte at the default baud rate
+        assert!((20..10_000).contains(&cycles), "{}", cycles);
+
+        // a flash image that never gets to the prompt
+        match boot(&[0x3e, b'?', 0xed, 0x39, 0xc0, 0x18, 0xfe]) {
+            Err(BootError::Timeout { output }) => assert_eq!(output, "?"),
+            other => panic!("{:?}", other),
+        }
+
+        std::fs::remove_file(dir.join("MOS.bin")).unwrap();
+        let mut machine = AgonMachine::new(AgonMachineConfig {
+            mos_bin: dir.join("MOS.bin"),
+            ..test_machine_config()
+        });
+        assert!(matches!(machine.boot_to_prompt(&mut Cpu::new_ez80(), 100), Err(BootError::LoadMos(_))));
+    }
+
+    #[test]
+    fn test_exec_command() {
+        let dir = test_sdcard_dir("exec_command");
+        // a MOS that echoes keypresses, and answers a command with "ok"
+        let mos = crate::test_asm::assemble("
+                jp.lil adl
+            adl:
+                ld sp, 0x70000
+                ld a, 1
+                out0 ($c2), a       ; uart fifo
+                ld a, 13
+                out0 ($c0), a
+                ld a, 10
+                out0 ($c0), a
+            prompt:
+                ld a, 0x2a          ; *
+                out0 ($c0), a
+            loop:
+                call getc           ; 0x81
+                call getc           ; length
+                call getc           ; keycode
+                ld b, a
+                call getc           ; modifiers
+                call getc           ; vkey
+                call getc           ; key down
+                or a
+                jr z, loop
+                ld a, b
+                cp 13
+                jr z, enter
+                out0 ($c0), a
+                jr loop
+            enter:
+                ld a, 13
+                out0 ($c0), a
+                ld a, 10
+                out0 ($c0), a
+                ld a, 0x6f          ; o
+                out0 ($c0), a
+                ld a, 0x6b          ; k
+                out0 ($c0), a
+                ld a, 13
+                out0 ($c0), a
+                ld a, 10
+                out0 ($c0), a
+                jr prompt
+            getc:
+                in0 a, ($c5)        ; LSR
+                and 1
+                jr z, getc
+                in0 a, ($c0)
+                ret", 0).unwrap();
+        std::fs::write(dir.join("MOS.bin"), mos).unwrap();
+        let mut machine = AgonMachine::new(AgonMachineConfig {
+            mos_bin: dir.join("MOS.bin"),
+            ..test_machine_config()
+        });
+        let mut cpu = Cpu::new_ez80();
+        assert_eq!(machine.exec_command(&mut cpu, "hi", 100_000), Ok("ok\r\n".to_string()));
+        // resumes at the prompt
+        assert_eq!(machine.exec_command(&mut cpu, "again", 100_000), Ok("ok\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_call_function() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        let mut cpu = Cpu::new_ez80();
+
+        // add hl, de; inc a; ret
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x19, 0x3c, 0xc9]);
+        let out = machine.call_function(&mut cpu, 0x40000, RegisterSetup {
+            a: 7, hl: 0x12345, de: 0x10001, sp: TEST_STACK,
+            ..Default::default()
+        }, 100).unwrap();
+        assert_eq!(out.hl, 0x22346);
+        assert_eq!(out.de, 0x10001);
+        assert_eq!(out.a, 8);
+        // the sentinel return address was popped
+        assert_eq!(out.sp, TEST_STACK);
+
+        // jr $ never returns
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x18, 0xfe]);
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, RegisterSetup { sp: TEST_STACK, ..Default::default() }, 100), Err(StopReason::InstructionLimit));
+
+        // a wild jump into unmapped memory stops on the triple fault
+        machine.set_triple_fault_detection(Some((16, TripleFaultAction::Halt)));
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x5b, 0xc3, 0x00, 0x00, 0x30]);
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, RegisterSetup { sp: TEST_STACK, ..Default::default() }, 100), Err(StopReason::TripleFault));
+    }
+
+    #[test]
+    fn test_log_uart_tx_to_file() {
+        let dir = test_sdcard_dir("uart_tx_log");
+        let log_path = dir.join("serial.log");
+        std::fs::write(&log_path, b"old\n").unwrap();
+
+        // returns the log contents before the machine is dropped
+        let send_hello = |cycles: u32| {
+            let mut machine = test_machine();
+            machine.log_uart_tx_to_file(&log_path).unwrap();
+            machine.port_out(0xc2, 0x01); // FIFO enable
+            for b in b"hello" {
+                machine.port_out(0xc0, *b);
+            }
+            machine.pending_ticks = cycles;
+            machine.apply_pending_ticks();
+            std::fs::read(&log_path).unwrap()
+        };
+
+        // still sending: buffered, then flushed when the machine goes away
+        assert_eq!(send_hello(100), b"old\n");
+        assert_eq!(std::fs::read(&log_path).unwrap(), b"old\nh");
+
+        // flushed once the transmitter is idle
+        assert_eq!(send_hello(100_000), b"old\nhhello");
+    }
+
+    #[test]
+    fn test_busy_wait_detection() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        machine.set_busy_wait_detection(Some(BusyWaitDetection { max_span: 16, cycles: 1000 }));
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+
+        // poll: nop; nop; jr poll
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x00, 0x00, 0x18, 0xfc]);
+        cpu.state.set_pc(0x40000);
+
+        let mut cycles = 0u64;
+        let event = loop {
+            cycles += machine.execute_instruction(&mut cpu) as u64;
+            if let Some(e) = machine.poll_event() { break e }
+            assert!(cycles < 2000, "no busy-wait reported");
+        };
+        assert!(cycles >= 1000);
+        assert_eq!(event, MachineEvent::BusyWait { pc_range: 0x40000..=0x40002, cycles });
+
+        // reported once per loop
+        for _ in 0..1000 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert_eq!(machine.poll_event(), None);
+    }
+
+    #[test]
+    fn test_execution_digest() {
+        // inc a; add hl, de; nop; jr -5
+        let program = [0x3c, 0x19, 0x00, 0x18, 0xfb];
+        let run = |program: &[u8]| {
+            let mut machine = test_machine();
+            machine.enable_hostfs = false;
+            let mut cpu = Cpu::new_ez80();
+            cpu.state.reg.adl = true;
+            cpu.state.reg.set24(Reg16::DE, 0x123);
+            z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, program);
+            cpu.state.set_pc(0x40000);
+
+            machine.set_execution_digest(true);
+            for _ in 0..200 {
+                machine.execute_instruction(&mut cpu);
+            }
+            machine.execution_digest()
+        };
+
+        let digest = run(&program);
+        assert_ne!(digest, 0);
+        assert_eq!(run(&program), digest);
+
+        // nop replaced with inc a
+        let mut changed = program;
+        changed[2] = 0x3c;
+        assert_ne!(run(&changed), digest);
+    }
+
+    #[test]
+    fn test_fetch_hook() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        let mut cpu = Cpu::new_ez80();
+        let regs = RegisterSetup { a: 7, sp: TEST_STACK, ..Default::default() };
+
+        // nop; ret
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x00, 0xc9]);
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let seen_ = seen.clone();
+        let mut fired = false;
+        machine.set_fetch_hook(Some(Box::new(move |pc, bytes| {
+            seen_.borrow_mut().push((pc, bytes[..2].to_vec()));
+            // nop -> inc a, the first time
+            if pc == 0x40000 && !fired {
+                fired = true;
+                return Some(vec![0x3c]);
+            }
+            None
+        })));
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 8);
+        assert_eq!(machine.peek(0x40000), 0x00);
+        assert_eq!(seen.borrow()[..2], [(0x40000, vec![0x00, 0xc9]), (0x40001, vec![0xc9, 0x00])]);
+
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 7);
+        machine.set_fetch_hook(None);
+
+        // after a hostfs hook, the hook sees the instruction at the return
+        // address, which is what runs
+        machine.enable_hostfs = true;
+        machine.mos_map.f_close = 0x10000;
+        let rom = guest_bytes(&machine, 0x10000, 2);
+        let code = crate::test_asm::assemble("
+                call 0x10000
+                nop
+                ret", 0x40000).unwrap();
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &code);
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let seen_ = seen.clone();
+        machine.set_fetch_hook(Some(Box::new(move |pc, _bytes| {
+            seen_.borrow_mut().push(pc);
+            if pc == 0x40004 { Some(vec![0x3c]) } else { None }
+        })));
+        let regs = RegisterSetup { a: 7, hl: TEST_FIL, sp: TEST_STACK, ..Default::default() };
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 8);
+        assert!(seen.borrow().contains(&0x40004) && !seen.borrow().contains(&0x10000));
+        assert_eq!(guest_bytes(&machine, 0x10000, 2), rom);
+        assert_eq!(machine.peek(0x40004), 0x00);
+        machine.set_fetch_hook(None);
+
+        // a replacement that writes over its own address: the write stays
+        machine.enable_hostfs = false;
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x00, 0x00, 0x00, 0x00, 0xc9]);
+        machine.set_fetch_hook(Some(Box::new(move |pc, _bytes| {
+            // ld (0x40000), a
+            if pc == 0x40000 { Some(vec![0x32, 0x00, 0x00, 0x04]) } else { None }
+        })));
+        let regs = RegisterSetup { a: 0x55, sp: TEST_STACK, ..Default::default() };
+        machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap();
+        assert_eq!(guest_bytes(&machine, 0x40000, 5), [0x55, 0x00, 0x00, 0x00, 0xc9]);
+        machine.set_fetch_hook(None);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        machine.set_self_modifying_code_detection(true);
+        let mut cpu = Cpu::new_ez80();
+        let regs = RegisterSetup { a: 7, sp: TEST_STACK, ..Default::default() };
+
+        // inc a; ret
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x3c, 0xc9]);
+        // not yet executed, so not code
+        assert_eq!(machine.poll_event(), None);
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 8);
+        // writing data (the stack) isn't reported
+        assert_eq!(machine.poll_event(), None);
+
+        // nor is the host writing there, as hostfs f_read does
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x3c]);
+        assert_eq!(machine.poll_event(), None);
+
+        // ld a, 0; ld (0x40000), a; ret: inc a -> nop
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x41000, &[0x3e, 0x00, 0x32, 0x00, 0x00, 0x04, 0xc9]);
+        machine.call_function(&mut cpu, 0x41000, regs, 10).unwrap();
+        assert_eq!(machine.poll_event(), Some(MachineEvent::SelfModifyingCode { addr: 0x40000 }));
+        machine.call_function(&mut cpu, 0x41000, regs, 10).unwrap();
+        assert_eq!(machine.poll_event(), None);
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 7);
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        machine.set_stack_bounds(TEST_STACK - 0x100, TEST_STACK);
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::SP, TEST_STACK);
+        cpu.state.set_pc(0x40000);
+        let code = crate::test_asm::assemble("
+            recurse:
+                nop
+                call recurse", 0x40000).unwrap();
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &code);
+
+        // 85 calls fit in 0x100 bytes
+        for _ in 0..2 * 85 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert_eq!(machine.poll_event(), None);
+        for _ in 0..2 * 10 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert_eq!(machine.poll_event(), Some(MachineEvent::StackOutOfBounds { sp: TEST_STACK - 86 * 3, pc: 0x40001 }));
+        assert_eq!(machine.poll_event(), None);
+
+        // still outside, but no longer checked
+        machine.clear_stack_bounds();
+        machine.execute_instruction(&mut cpu);
+        assert_eq!(machine.poll_event(), None);
+    }
+
+    #[test]
+    fn test_stack_bounds_mixed_mode() {
+        let mut machine = test_machine();
+        machine.set_stack_bounds(TEST_STACK - 0x100, TEST_STACK);
+
+        // switching from SPS to SPL isn't a stack move, wherever SPL is
+        machine.check_stack_bounds(0x40000, (false, 0xfffe), (true, TEST_STACK), TEST_STACK - 0x100, TEST_STACK);
+        machine.check_stack_bounds(0x40000, (true, TEST_STACK), (false, 0xfffe), TEST_STACK - 0x100, TEST_STACK);
+        assert_eq!(machine.poll_event(), None);
+
+        // but moves within Z80 mode are checked
+        machine.check_stack_bounds(0x1000, (false, TEST_STACK), (false, TEST_STACK + 2), TEST_STACK - 0x100, TEST_STACK);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::StackOutOfBounds { sp: TEST_STACK + 2, pc: 0x1000 }));
+    }
+
+    #[test]
+    fn test_adl_mode_tracing() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        machine.set_adl_mode_tracing(true);
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.set_pc(0x40000);
+        let code = crate::test_asm::assemble("
+                nop
+                jp.lil adl
+            adl:
+                nop", 0x40000).unwrap();
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &code);
+
+        assert!(!cpu.state.reg.adl);
+        machine.execute_instruction(&mut cpu);
+        assert_eq!(machine.poll_event(), None);
+        machine.execute_instruction(&mut cpu);
+        assert_eq!(machine.poll_event(), Some(MachineEvent::AdlModeChanged { from: false, to: true, pc: 0x40001 }));
+        machine.execute_instruction(&mut cpu);
+        assert_eq!(machine.poll_event(), None);
+    }
+
+    #[test]
+    fn test_with_guest_byte() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::SP, TEST_STACK);
+
+        // loop: ld a, (flag); ld (flag + 1), a; jr loop
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x3a, 0x00, 0x00, 0x05, 0x32, 0x01, 0x00, 0x05, 0x18, 0xf6]);
+        machine.poke(0x50000, 0);
+        cpu.state.set_pc(0x40000);
+        for _ in 0..6 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert_eq!(machine.peek(0x50001), 0);
+
+        // raise the flag mid-loop: the guest copies it on its next pass
+        assert_eq!(machine.with_guest_byte(0x50000, |old| old | 0x80), 0);
+        for _ in 0..3 {
+            machine.execute_instruction(&mut cpu);
+        }
+        assert_eq!(machine.peek(0x50001), 0x80);
+        assert_eq!(machine.with_guest_byte(0x50000, |old| old + 1), 0x80);
+        assert_eq!(machine.peek(0x50000), 0x81);
+    }
+
+    #[test]
+    fn test_run_events() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::SP, TEST_STACK);
+
+        // enable the UART0 fifo, send "Hi", read unmapped memory, spin
+        let code = crate::test_asm::assemble("
+                ld a, 1
+                out0 ($c2), a
+                ld a, 0x48      ; H
+                out0 ($c0), a
+                ld a, 0x69      ; i
+                out0 ($c0), a
+                ld a, ($300000)
+            spin:
+                jr spin", 0x40000).unwrap();
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &code);
+        cpu.state.set_pc(0x40000);
+        assert_eq!(machine.run_events(&mut cpu, 10_000).collect::<Vec<_>>(), vec![
+            // 'i' is still waiting behind 'H' in the fifo
+            MachineEvent::Uart0Tx(b'H'),
+            MachineEvent::MemOutOfBounds { pc: 0x4000f, addr: 0x300000 },
+            MachineEvent::Uart0Tx(b'i'),
+        ]);
+
+        // jr spin -> halt
+        machine.poke(0x40013, 0x76);
+        let mut events = machine.run_events(&mut cpu, 10_000);
+        assert_eq!(events.next(), Some(MachineEvent::Halted { pc: 0x40013 }));
+        assert_eq!(events.next(), None);
+    }
+
+    #[test]
+    fn test_patches() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        let mut cpu = Cpu::new_ez80();
+        let regs = RegisterSetup { a: 7, sp: TEST_STACK, ..Default::default() };
+
+        // inc a; ret
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40000, &[0x3c, 0xc9]);
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 8);
+
+        // inc a -> nop
+        machine.apply_patch(0x40000, &[0x00]).unwrap();
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 7);
+        // re-patching keeps the original bytes
+        machine.apply_patch(0x40000, &[0x00]).unwrap();
+        assert!(machine.apply_patch(0x3ffff, &[0x00, 0x00]).is_err());
+        assert!(machine.apply_patch(0xffffff, &[0x00, 0x00]).is_err());
+        assert!(machine.apply_patch(0x300000, &[0x00]).is_err());
+
+        assert!(machine.revert_patch(0x40000));
+        assert!(!machine.revert_patch(0x40000));
+        assert_eq!(machine.call_function(&mut cpu, 0x40000, regs, 10).unwrap().a, 8);
+
+        // into flash, unseen by the access log
+        machine.set_access_log(Some(8));
+        let orig = machine.peek_raw(0x100);
+        machine.apply_patch(0x100, &[!orig]).unwrap();
+        assert_eq!(machine.recent_accesses(), vec![]);
+        assert_eq!(machine.peek(0x100), !orig);
+        machine.revert_patch(0x100);
+        assert_eq!(machine.peek(0x100), orig);
+    }
+
+    #[test]
+    fn test_sdcard_write_protect() {
+        let dir = test_sdcard_dir("write_protect");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        assert!(machine.set_sdcard_switch_pins(Some((gpio::GpioPort::B, 3)), Some((gpio::GpioPort::C, 8))).is_err());
+        assert_eq!(machine.sdcard_detect_pin, None);
+        machine.set_sdcard_switch_pins(Some((gpio::GpioPort::B, 3)), Some((gpio::GpioPort::B, 4))).unwrap();
+        let mut cpu = Cpu::new_ez80();
+
+        // card inserted (detect low), not protected (wp low)
+        assert_eq!(machine.port_in(0x9a) & 0x18, 0);
+        machine.set_sdcard_write_protect(true);
+        assert_eq!(machine.port_in(0x9a) & 0x18, 0x10);
+        machine.set_sdcard_inserted(false);
+        assert_eq!(machine.port_in(0x9a) & 0x18, 0x18);
+        machine.set_sdcard_inserted(true);
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "new.txt", mos::FA_WRITE | mos::FA_CREATE_NEW), mos::FR_WRITE_PROTECTED);
+        assert!(!dir.join("new.txt").exists());
+        assert_eq!(cpu.state.pc(), 0x1234);
+
+        // reading is fine
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", 1), mos::FR_OK);
+        let data = poke_string(&mut machine, TEST_STRING, "more");
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, data, 4, 0x58000]);
+        machine.hostfs_mos_call(mos::MosCall::FWrite, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_WRITE_PROTECTED);
+        assert_eq!(std::fs::read(dir.join("FILE.TXT")).unwrap(), b"hello");
+
+        machine.set_sdcard_write_protect(false);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "new.txt", mos::FA_WRITE | mos::FA_CREATE_NEW), mos::FR_OK);
+    }
+
+    #[test]
+    fn test_sdcard_write_protect_all_calls() {
+        let dir = test_sdcard_dir("write_protect_all_calls");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        machine.set_sdcard_write_protect(true);
+        let mut cpu = Cpu::new_ez80();
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", mos::FA_WRITE), mos::FR_WRITE_PROTECTED);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "file.txt", 1), mos::FR_OK);
+        let path = poke_string(&mut machine, TEST_STRING, "file.txt");
+        let new_path = poke_string(&mut machine, TEST_STRING + 0x100, "other.txt");
+        for (call, args) in [
+            (mos::MosCall::FWrite, vec![TEST_FIL, path, 4, 0x58000]),
+            (mos::MosCall::FTruncate, vec![TEST_FIL]),
+            (mos::MosCall::FUnlink, vec![path]),
+            (mos::MosCall::FMkdir, vec![new_path]),
+            (mos::MosCall::FRename, vec![path, new_path]),
+        ] {
+            setup_mos_call(&mut machine, &mut cpu, &args);
+            machine.hostfs_mos_call(call, &mut cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_WRITE_PROTECTED, "{:?}", call);
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // reads still work
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 10, 0x58000]);
+        machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(guest_bytes(&machine, 0x40000, 5), b"hello");
+    }
+
+    #[test]
+    fn test_state_json() {
+        let mut machine = test_machine();
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.reg.set24(Reg16::HL, 0x123456);
+        cpu.state.reg.set24(Reg16::SP, 0x0affff);
+        cpu.state.set_pc(0x40000);
+        machine.port_out(0xf7, 0x02); // FLASH_ADDR_U
+
+        let state: serde_json::Value = serde_json::from_str(&machine.state_json(&cpu)).unwrap();
+        assert_eq!(state["registers"]["hl"], 0x123456);
+        assert_eq!(state["registers"]["sp"], 0x0affff);
+        assert_eq!(state["registers"]["pc"], 0x40000);
+        assert_eq!(state["registers"]["adl"], true);
+        assert_eq!(state["peripherals"]["memory_map"]["flash_addr_u"], 2);
+        assert_eq!(state["peripherals"]["prt_timers"].as_array().unwrap().len(), 6);
+        assert_eq!(state["stats"]["open_files"], 0);
+    }
+
+    fn f_unlink(machine: &mut AgonMachine, cpu: &mut Cpu, path: &str) -> u32 {
+        let path_ptr = poke_string(machine, TEST_STRING, path);
+        setup_mos_call(machine, cpu, &[path_ptr]);
+        machine.hostfs_mos_call(mos::MosCall::FUnlink, cpu);
+        cpu.state.reg.get24(Reg16::HL)
+    }
+
+    #[test]
+    fn test_f_unlink() {
+        let dir = test_sdcard_dir("unlink");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+        std::fs::create_dir(dir.join("empty")).unwrap();
+        std::fs::create_dir(dir.join("full")).unwrap();
+        std::fs::write(dir.join("full").join("inner.txt"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+
+        assert_eq!(f_unlink(&mut machine, &mut cpu, "file.txt"), mos::FR_OK);
+        assert!(!dir.join("FILE.TXT").exists());
+        assert_eq!(f_unlink(&mut machine, &mut cpu, "file.txt"), mos::FR_NO_FILE);
+
+        assert_eq!(f_unlink(&mut machine, &mut cpu, "EMPTY"), mos::FR_OK);
+        assert!(!dir.join("empty").exists());
+
+        assert_eq!(f_unlink(&mut machine, &mut cpu, "full"), mos::FR_DENIED);
+        assert!(dir.join("full").join("inner.txt").exists());
+        assert_eq!(cpu.state.pc(), 0x1234);
+    }
+
+    struct CountingLink(std::rc::Rc<std::cell::Cell<u32>>);
+    impl uart::SerialLink for CountingLink {
+        fn send(&mut self, _byte: u8) { self.0.set(self.0.get() + 1); }
+        fn recv(&mut self) -> Option<u8> { None }
+        fn read_clear_to_send(&mut self) -> bool { true }
+    }
+
+    #[test]
+    fn test_tick_granularity() {
+        // bytes sent by uart0 after running the same stretch of code
+        let bytes_sent = |granularity: u32| {
+            let sent = std::rc::Rc::new(std::cell::Cell::new(0));
+            let mut machine = AgonMachine::new(AgonMachineConfig {
+                uart0_link: Box::new(CountingLink(sent.clone())),
+                ..test_machine_config()
+            });
+            machine.enable_hostfs = false;
+            machine.set_tick_granularity(granularity);
+            let mut cpu = Cpu::new_ez80();
+            cpu.state.reg.adl = true;
+            // zeroed RAM: a run of NOPs
+            cpu.state.set_pc(0x40000);
+
+            machine.port_out(0xc2, 0x01); // enable fifo
+            for b in 0..16 {
+                machine.port_out(0xc0, b);
+            }
+            let mut cycles = 0;
+            while cycles < 2000 {
+                cycles += machine.execute_instruction(&mut cpu);
+            }
+            sent.get()
+        };
+
+        let fine = bytes_sent(1);
+        let coarse = bytes_sent(500);
+        let cooldown = 2 * 16 * 9;
+        assert!(fine > 1);
+        assert!((fine as i32 - coarse as i32).abs() <= 500 / cooldown + 1);
+    }
+
+    #[test]
+    fn test_independent_prt_timers() {
+        let mut machine = test_machine();
+        // PRT0: reload 10, PRT1: reload 25. both continuous, clk_div 4
+        machine.port_out(0x81, 10);
+        machine.port_out(0x82, 0);
+        machine.port_out(0x84, 25);
+        machine.port_out(0x85, 0);
+        machine.port_out(0x80, 0x53); // IRQ_EN | PRT_MODE | RST_EN | PRT_EN
+        machine.port_out(0x83, 0x53);
+
+        let mut fired = [0, 0];
+        for _ in 0..4000 {
+            machine.pending_ticks += 1;
+            machine.apply_pending_ticks();
+            if machine.port_in(0x80) & 0x80 != 0 { fired[0] += 1; }
+            if machine.port_in(0x83) & 0x80 != 0 { fired[1] += 1; }
+        }
+        // one period is (reload * clk_div) cycles, or one more (counting
+        // the reload step) in continuous mode
+        assert!((4000 / 44..=4000 / 40).contains(&fired[0]), "{:?}", fired);
+        assert!((4000 / 104..=4000 / 100).contains(&fired[1]), "{:?}", fired);
+        // the others never started
+        assert_eq!(machine.port_in(0x86) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_file_locking_same_file_two_paths() {
+        let dir = test_sdcard_dir("locking");
+        std::fs::write(dir.join("FILE.TXT"), b"hello").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        machine.set_file_locking(true);
+        let mut cpu = Cpu::new_ez80();
+        const FIL2: u32 = TEST_FIL + 0x100;
+
+        // any number of readers
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "FILE.TXT", 1), mos::FR_OK);
+        assert_eq!(f_open(&mut machine, &mut cpu, FIL2, "./file.txt", 1), mos::FR_OK);
+        assert_eq!(machine.open_files[&TEST_FIL].host_path, machine.open_files[&FIL2].host_path);
+
+        // but not a writer, via yet another path
+        let fil3 = TEST_FIL + 0x200;
+        assert_eq!(f_open(&mut machine, &mut cpu, fil3, "sub/../File.txt", mos::FA_WRITE), mos::FR_LOCKED);
+        assert!(!machine.open_files.contains_key(&fil3));
+
+        // a writer excludes readers
+        setup_mos_call(&mut machine, &mut cpu, &[FIL2]);
+        machine.hostfs_mos_call(mos::MosCall::FClose, &mut cpu);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "FILE.TXT", mos::FA_WRITE), mos::FR_OK);
+        assert_eq!(f_open(&mut machine, &mut cpu, FIL2, "./file.txt", 1), mos::FR_LOCKED);
+
+        machine.set_file_locking(false);
+        assert_eq!(f_open(&mut machine, &mut cpu, FIL2, "./file.txt", 1), mos::FR_OK);
+    }
+
+    fn f_opendir(machine: &mut AgonMachine, cpu: &mut Cpu, dir_ptr: u32, path: &str) -> u32 {
+        let path_ptr = poke_string(machine, TEST_STRING, path);
+        setup_mos_call(machine, cpu, &[dir_ptr, path_ptr]);
+        machine.hostfs_mos_call(mos::MosCall::FOpendir, cpu);
+        cpu.state.reg.get24(Reg16::HL)
+    }
+
+    #[test]
+    fn test_max_open_dirs() {
+        let dir = test_sdcard_dir("max_open_dirs");
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        machine.set_max_open_dirs(Some(2));
+        let mut cpu = Cpu::new_ez80();
+        const DIR1: u32 = 0x50000;
+        const DIR2: u32 = 0x50100;
+        const DIR3: u32 = 0x50200;
+
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR1, "/"), mos::FR_OK);
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR2, "/"), mos::FR_OK);
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR3, "/"), mos::FR_TOO_MANY_OPEN_FILES);
+        assert_eq!(cpu.state.pc(), 0x1234);
+        // reusing an open DIR is fine
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR2, "/"), mos::FR_OK);
+
+        setup_mos_call(&mut machine, &mut cpu, &[DIR1]);
+        machine.hostfs_mos_call(mos::MosCall::FClosedir, &mut cpu);
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR3, "/"), mos::FR_OK);
+
+        assert_eq!(f_opendir(&mut machine, &mut cpu, DIR3, "/missing"), mos::FR_NO_FILE);
+    }
+
+    #[test]
+    fn test_fresult_in_hl() {
+        let dir = test_sdcard_dir("fresult_in_hl");
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+
+        // all 24 bits of HL are the FRESULT, whatever was there before
+        let path = poke_string(&mut machine, TEST_STRING, "missing.txt");
+        setup_mos_call(&mut machine, &mut cpu, &[path, 0x40000]);
+        cpu.state.reg.set24(Reg16::HL, 0xffffff);
+        machine.hostfs_mos_call(mos::MosCall::FStat, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_NO_FILE);
+        assert_eq!(mos::get_fresult(&cpu), mos::FR_NO_FILE);
+
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL]);
+        cpu.state.reg.set24(Reg16::HL, 0xffffff);
+        machine.hostfs_mos_call(mos::MosCall::FClose, &mut cpu);
+        assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        assert_eq!(cpu.state.pc(), 0x1234);
+    }
+
+    #[test]
+    fn test_f_open_backslash_path() {
+        let dir = test_sdcard_dir("backslash");
+        std::fs::create_dir(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("subdir").join("FILE.TXT"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "\\subdir\\file.txt", 1), mos::FR_OK);
+        assert!(machine.open_files.contains_key(&TEST_FIL));
+        assert_eq!(machine._peek24(TEST_FIL + mos::FIL_MEMBER_OBJSIZE), 5);
+        assert_eq!(machine.mos_path_join("subdir\\..\\subdir\\file.txt").0,
+                   std::path::PathBuf::from("subdir/FILE.TXT"));
+        // returned to caller
+        assert_eq!(cpu.state.pc(), 0x1234);
+    }
+
+    #[test]
+    fn test_mos_log_failures_only() {
+        let dir = test_sdcard_dir("mos_log");
+        std::fs::write(dir.join("exists.txt"), b"hello").unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+
+        let log = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let log_ = log.clone();
+        machine.set_mos_log(Some((mos::MosLogMode::FailuresOnly, Box::new(move |r: &mos::MosLogRecord| {
+            log_.borrow_mut().push(r.clone());
+        }))));
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "exists.txt", 1), mos::FR_OK);
+        assert!(log.borrow().is_empty());
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x100, "missing.txt", 1), mos::FR_NO_FILE);
+        assert_eq!(*log.borrow(), vec![mos::MosLogRecord {
+            call: mos::MosCall::FOpen,
+            path: Some("missing.txt".to_string()),
+            fresult: mos::FR_NO_FILE,
+        }]);
+        assert_eq!(log.borrow()[0].to_string(), "f_open(\"missing.txt\") -> FR_NO_FILE");
+    }
+
+    #[test]
+    fn test_mos_call_log() {
+        let dir = test_sdcard_dir("mos_call_log");
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir.clone());
+        let mut cpu = Cpu::new_ez80();
+        machine.set_mos_call_recording(true);
+
+        // FA_WRITE | FA_CREATE_ALWAYS
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "log.txt", 0x0a), mos::FR_OK);
+        poke_string(&mut machine, 0x40000, "hi");
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 2, 0x58000]);
+        machine.hostfs_mos_call(mos::MosCall::FWrite, &mut cpu);
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL]);
+        machine.hostfs_mos_call(mos::MosCall::FClose, &mut cpu);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "missing.txt", 1), mos::FR_NO_FILE);
+
+        let record = |call, args: &[u32], path: Option<&str>, fresult| mos::MosCallRecord {
+            call, args: args.to_vec(), path: path.map(|p| p.to_string()), fresult
+        };
+        assert_eq!(machine.mos_call_log(), [
+            record(mos::MosCall::FOpen, &[TEST_FIL, TEST_STRING, 0x0a], Some("log.txt"), mos::FR_OK),
+            record(mos::MosCall::FWrite, &[TEST_FIL, 0x40000, 2, 0x58000], None, mos::FR_OK),
+            record(mos::MosCall::FClose, &[TEST_FIL], None, mos::FR_OK),
+            record(mos::MosCall::FOpen, &[TEST_FIL, TEST_STRING, 1], Some("missing.txt"), mos::FR_NO_FILE),
+        ]);
+
+        let log_path = std::env::temp_dir().join(format!("agon-cpu-emulator-{}-mos-call-log.json", std::process::id()));
+        machine.write_mos_call_log(&log_path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&log_path).unwrap()).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[1]["call"].as_str(), Some("f_write"));
+        assert_eq!(entries[3]["path"].as_str(), Some("missing.txt"));
+        assert_eq!(entries[3]["fresult"].as_u64(), Some(mos::FR_NO_FILE as u64));
+        std::fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_mos_call_stats() {
+        let dir = test_sdcard_dir("mos_call_stats");
+        std::fs::write(dir.join("data.bin"), [0x55; 100]).unwrap();
+
+        let mut machine = test_machine();
+        machine.set_sdcard_directory(dir);
+        let mut cpu = Cpu::new_ez80();
+        assert!(machine.mos_call_stats().is_empty());
+
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL, "data.bin", 1), mos::FR_OK);
+        assert_eq!(f_open(&mut machine, &mut cpu, TEST_FIL + 0x100, "missing.bin", 1), mos::FR_NO_FILE);
+        for _ in 0..3 {
+            setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 10, 0x58000]);
+            machine.hostfs_mos_call(mos::MosCall::FRead, &mut cpu);
+            assert_eq!(cpu.state.reg.get24(Reg16::HL), mos::FR_OK);
+        }
+
+        let stats = machine.mos_call_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[&mos::MosCall::FOpen].count, 2);
+        let read = stats[&mos::MosCall::FRead];
+        assert_eq!(read.count, 3);
+        // at least a cycle per byte written to the guest buffer
+        assert!(read.cycles >= 30);
+
+        // and those cycles are charged to the instruction that made the call
+        machine.mos_map.f_read = 0x10000;
+        setup_mos_call(&mut machine, &mut cpu, &[TEST_FIL, 0x40000, 10, 0x58000]);
+        cpu.state.set_pc(0x10000);
+        let cycles = machine.execute_instruction(&mut cpu);
+        let read_cycles = machine.mos_call_stats()[&mos::MosCall::FRead].cycles - read.cycles;
+        assert!(read_cycles >= 10 && cycles as u64 > read_cycles, "{} {}", cycles, read_cycles);
+    }
+
+    #[test]
+    fn test_emulator_signature() {
+        let mut machine = test_machine();
+        let signature = |machine: &mut AgonMachine| (0xff00..=0xff05).map(|p| machine.port_in(p)).collect::<Vec<u8>>();
+        assert_eq!(signature(&mut machine), [0; 6]);
+
+        machine.set_emulator_signature(true);
+        let sig = signature(&mut machine);
+        assert_eq!(&sig[..4], b"AgEm");
+        assert_eq!(sig[4..], [env!("CARGO_PKG_VERSION_MAJOR").parse::<u8>().unwrap(), env!("CARGO_PKG_VERSION_MINOR").parse::<u8>().unwrap()]);
+    }
+
+    #[test]
+    fn test_external_ram_wait_states() {
+        let mut machine = test_machine();
+        let cycles_for_access = |machine: &mut AgonMachine, address: u32| {
+            machine.cycle_counter.set(0);
+            machine.peek(address);
+            machine.poke(address, 0);
+            machine.cycle_counter.get()
+        };
+
+        // by default the reset value's 7 wait states aren't charged
+        assert_eq!(cycles_for_access(&mut machine, 0x40000), 2);
+        machine.set_memory_wait_states(true);
+        assert_eq!(cycles_for_access(&mut machine, 0x40000), 16);
+
+        // CS0 enabled, 0 wait states
+        machine.port_out(0xaa, 0x08);
+        assert_eq!(machine.port_in(0xaa), 0x08);
+        assert_eq!(cycles_for_access(&mut machine, 0x40000), 2);
+
+        // 3 wait states
+        machine.port_out(0xaa, 0x68);
+        assert_eq!(cycles_for_access(&mut machine, 0x40000), 8);
+
+        // on-chip sram has no wait states
+        assert_eq!(cycles_for_access(&mut machine, 0xffe000), 2);
+    }
+
+    #[test]
+    fn test_external_ram_fetch_wait_states() {
+        let mut machine = test_machine();
+        machine.enable_hostfs = false;
+        machine.set_memory_wait_states(true);
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+
+        let loop_cycles = |machine: &mut AgonMachine, cpu: &mut Cpu, address: u32| {
+            // nop; nop; jr -4
+            z80_mem_tools::memcpy_to_z80(machine, address, &[0x00, 0x00, 0x18, 0xfc]);
+            cpu.state.set_pc(address);
+            (0..300).map(|_| machine.execute_instruction(cpu)).sum::<u32>()
+        };
+
+        // 0 wait states: external RAM fetches cost the same as on-chip
+        machine.port_out(0xaa, 0x08);
+        let onchip = loop_cycles(&mut machine, &mut cpu, 0xffe000);
+        assert_eq!(loop_cycles(&mut machine, &mut cpu, 0x40000), onchip);
+
+        // 3 wait states on every byte fetched
+        machine.port_out(0xaa, 0x68);
+        assert_eq!(loop_cycles(&mut machine, &mut cpu, 0xffe000), onchip);
+        let external = loop_cycles(&mut machine, &mut cpu, 0x40000);
+        assert!(external >= onchip + 3 * 300, "{} vs {}", external, onchip);
+    }
+
+    #[test]
+    fn test_pending_interrupts() {
+        let mut machine = test_machine();
+        // PRT_IRQ with IRQ_EN
+        machine.port_out(0x80, 0xc0);
+        // PRT_IRQ without IRQ_EN
+        machine.port_out(0x89, 0x80);
+
+        let pending = machine.pending_interrupts();
+        let prt: Vec<&InterruptSource> = pending.iter()
+            .filter(|s| matches!(s.interrupt, Interrupt::PrtTimer(_)))
+            .collect();
+        assert_eq!(prt, vec![
+            &InterruptSource { interrupt: Interrupt::PrtTimer(0), vector: 0xa, enabled: true, priority: 0 },
+            &InterruptSource { interrupt: Interrupt::PrtTimer(3), vector: 0x10, enabled: false, priority: 3 },
+        ]);
+        // tx holding register empty, but the interrupt isn't enabled
+        assert!(pending.contains(&InterruptSource { interrupt: Interrupt::Uart0Tx, vector: 0x18, enabled: false, priority: 6 }));
+    }
 }
@@ -1,4 +1,4 @@
-use agon_cpu_emulator::{ SerialLink, RamInit, AgonMachine, AgonMachineConfig, gpio };
+use agon_cpu_emulator::{ SerialLink, RamInit, AgonMachine, AgonMachineConfig, gpio, keyboard };
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 use std::io::{ self, BufRead, Write };
@@ -9,25 +9,8 @@ fn send_bytes(tx: &Sender<u8>, msg: &Vec<u8>) {
     }
 }
 
-fn send_keys(tx: &Sender<u8>, msg: &str) {
-    for key in msg.as_bytes() {
-        // cmd, len, keycode, modifiers, vkey, keydown
-        // key down
-        tx.send(0x81).unwrap();
-        tx.send(4).unwrap();
-        tx.send(*key).unwrap();
-        tx.send(0).unwrap();
-        tx.send(0).unwrap();
-        tx.send(1).unwrap();
-
-        // key up
-        tx.send(0x81).unwrap();
-        tx.send(4).unwrap();
-        tx.send(*key).unwrap();
-        tx.send(0).unwrap();
-        tx.send(0).unwrap();
-        tx.send(0).unwrap();
-    }
+fn send_keys(tx: &Sender<u8>, layout: &keyboard::KeyboardLayout, msg: &str) {
+    send_bytes(tx, &keyboard::key_packets(layout, msg));
 }
 
 // Fake VDP. Minimal for MOS to work, outputting to stdout */
@@ -110,7 +93,8 @@ fn handle_vdp(tx_to_ez80: &Sender<u8>, rx_from_ez80: &Receiver<u8>, vdp_terminal
 }
 
 fn start_vdp(tx_vdp_to_ez80: Sender<u8>, rx_ez80_to_vdp: Receiver<u8>,
-             gpios: std::sync::Arc<std::sync::Mutex<gpio::GpioSet>>) {
+             gpios: std::sync::Arc<std::sync::Mutex<gpio::GpioSet>>,
+             keyboard_layout: keyboard::KeyboardLayout) {
     let (tx_stdin, rx_stdin): (Sender<String>, Receiver<String>) = mpsc::channel();
 
     // to avoid blocking on stdin, use a thread and channel to read from it
@@ -156,8 +140,8 @@ fn start_vdp(tx_vdp_to_ez80: Sender<u8>, rx_ez80_to_vdp: Receiver<u8>,
                         }
                         tx_vdp_to_ez80.send(10).unwrap();
                     } else {
-                        send_keys(&tx_vdp_to_ez80, &line);
-                        send_keys(&tx_vdp_to_ez80, "\r");
+                        send_keys(&tx_vdp_to_ez80, &keyboard_layout, &line);
+                        send_keys(&tx_vdp_to_ez80, &keyboard_layout, "\r");
                     }
                     last_kb_input = std::time::Instant::now();
                 }
@@ -202,6 +186,7 @@ fn main() {
 
     let mut unlimited_cpu = false;
     let mut sdcard_img = None;
+    let mut keyboard_layout = keyboard::KeyboardLayout::Us;
     let mut args: Vec<String> = std::env::args().skip(1).collect();
     while args.len() > 0 {
         let arg = args.remove(0);
@@ -212,12 +197,23 @@ fn main() {
             "--sdcard-img" => {
                 sdcard_img = if args.len() > 0 { Some(args.remove(0)) } else { None };
             }
+            "--keyboard-layout" if !args.is_empty() => {
+                keyboard_layout = match args.remove(0).as_str() {
+                    "us" => keyboard::KeyboardLayout::Us,
+                    "uk" => keyboard::KeyboardLayout::Uk,
+                    other => {
+                        eprintln!("Unknown keyboard layout '{}'", other);
+                        std::process::exit(-1);
+                    }
+                };
+            }
             "--help" | "-h" | _ => {
                 println!("Usage: agon [OPTIONS]");
                 println!();
                 println!("Options:");
-                println!("  --sdcard-img <filename>  Use an SDcard image rather than hostfs");
-                println!("  -u, --unlimited-cpu      Don't limit CPU to Agon Light 18.432MHz");
+                println!("  --keyboard-layout <us|uk>  Layout of keystrokes typed from stdin (default us)");
+                println!("  --sdcard-img <filename>    Use an SDcard image rather than hostfs");
+                println!("  -u, --unlimited-cpu        Don't limit CPU to Agon Light 18.432MHz");
                 std::process::exit(0);
             }
         }
@@ -249,5 +245,5 @@ fn main() {
         machine.start(None);
     });
 
-    start_vdp(tx_vdp_to_ez80, rx_ez80_to_vdp, gpios);
+    start_vdp(tx_vdp_to_ez80, rx_ez80_to_vdp, gpios, keyboard_layout);
 }
@@ -3,7 +3,7 @@
 use ez80::{ Environment, Machine };
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
-use crate::AgonMachine;
+use crate::{ AgonMachine, MemAccessKind };
 
 pub struct DebuggerConnection {
     pub tx: Sender<DebugResp>,
@@ -31,6 +31,8 @@ pub enum DebugCmd {
     GetState,
     DisassemblePc { adl: Option<bool> },
     Disassemble { adl: Option<bool>, start: u32, end: u32 },
+    ApplyDebugConfig(DebugConfig),
+    ExportDebugConfig,
 }
 
 #[derive(Debug)]
@@ -56,6 +58,7 @@ pub enum DebugResp {
         disasm: Vec<ez80::disassembler::Disasm>
     },
     Triggers(Vec<Trigger>),
+    DebugConfig(DebugConfig),
 }
 
 #[derive(Debug,Clone)]
@@ -65,14 +68,170 @@ pub struct Trigger {
     pub actions: Vec<DebugCmd>
 }
 
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub trigger: Trigger,
+    // times the address is reached before the trigger's actions run
+    pub ignore_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Pause when the guest accesses `len` bytes from `address`. Reads
+/// include instruction fetches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub len: u32,
+    pub access: WatchAccess,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u32, kind: MemAccessKind) -> bool {
+        address.wrapping_sub(self.address) < self.len && match (self.access, kind) {
+            (WatchAccess::ReadWrite, _) => true,
+            (WatchAccess::Read, MemAccessKind::Read) | (WatchAccess::Write, MemAccessKind::Write) => true,
+            _ => false
+        }
+    }
+}
+
+/// The full set of breakpoints and watchpoints, for saving and restoring
+/// a debug session
+#[derive(Debug, Clone, Default)]
+pub struct DebugConfig {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    // the PC for the innermost frame, otherwise a return address
+    pub address: u32,
+    // nearest preceding symbol, and the offset from it
+    pub function: Option<(String, u32)>,
+}
+
+// stack entries examined by backtrace(), looking for return addresses
+const BACKTRACE_STACK_WINDOW: u32 = 256;
+
+/**
+ * Reconstruct the call stack, innermost frame first, resolving addresses
+ * to the nearest preceding symbol in `symbols` (eg: from
+ * symbol_map::read_zds_map_file).
+ *
+ * This is heuristic: the ez80 has no frame pointers, so the stack is
+ * scanned for values that look like return addresses (ie. are preceded
+ * by a CALL or RST instruction). Stale or coincidental values on the
+ * stack can show up as spurious frames.
+ */
+pub fn backtrace(machine: &AgonMachine, cpu: &ez80::Cpu, symbols: &std::collections::HashMap<String, u32>, max_depth: usize) -> Vec<StackFrame> {
+    let mut by_address: Vec<(u32, &String)> = symbols.iter().map(|(name, addr)| (*addr, name)).collect();
+    by_address.sort();
+    let resolve = |address: u32| -> Option<(String, u32)> {
+        let i = by_address.partition_point(|(a, _)| *a <= address);
+        if i == 0 { None } else {
+            let (sym_addr, name) = by_address[i - 1];
+            Some((name.clone(), address - sym_addr))
+        }
+    };
+
+    // SP wraps at 24 bits in ADL mode, and within MBASE:0000-FFFF in Z80 mode
+    let adl = cpu.state.reg.adl;
+    let (mut sp, entry_size, sp_mask) = if adl {
+        (cpu.state.reg.get24(ez80::Reg16::SP), 3, 0xffffff)
+    } else {
+        (cpu.state.reg.get16_mbase(ez80::Reg16::SP) & 0xffff, 2, 0xffff)
+    };
+    let mbase = if adl { 0 } else { cpu.state.reg.get16_mbase(ez80::Reg16::SP) & 0xff0000 };
+    // read without the side effects of a guest access
+    let stack_byte = |sp: u32, i: u32| machine.peek_raw(mbase | ((sp + i) & sp_mask)) as u32;
+
+    let mut frames = vec![StackFrame { address: cpu.state.pc(), function: resolve(cpu.state.pc()) }];
+
+    for _ in 0..BACKTRACE_STACK_WINDOW {
+        if frames.len() >= max_depth { break }
+        let address = if adl {
+            stack_byte(sp, 0) | stack_byte(sp, 1) << 8 | stack_byte(sp, 2) << 16
+        } else {
+            mbase | stack_byte(sp, 0) | stack_byte(sp, 1) << 8
+        };
+        if is_return_address(machine, address, adl) {
+            frames.push(StackFrame { address, function: resolve(address) });
+        }
+        sp = (sp + entry_size) & sp_mask;
+    }
+
+    frames
+}
+
+// is `address` just after a CALL or RST instruction?
+fn is_return_address(machine: &AgonMachine, address: u32, adl: bool) -> bool {
+    let is_call = |op: u8| matches!(op, 0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc);
+    let is_rst = |op: u8| op & 0xc7 == 0xc7;
+    let is_prefix = |op: u8| matches!(op, 0x40 | 0x49 | 0x52 | 0x5b);
+    let peek_back = |n: u32| address.checked_sub(n).map(|a| machine.peek_raw(a));
+    let imm_len = if adl { 3 } else { 2 };
+
+    peek_back(imm_len + 1).is_some_and(is_call) ||
+        peek_back(1).is_some_and(is_rst) ||
+        // with a mode suffix, eg: call.lil
+        (peek_back(5).is_some_and(is_prefix) && peek_back(4).is_some_and(is_call)) ||
+        (peek_back(4).is_some_and(is_prefix) && peek_back(3).is_some_and(is_call)) ||
+        (peek_back(2).is_some_and(is_prefix) && peek_back(1).is_some_and(is_rst))
+}
+
 pub struct DebuggerServer {
     con: DebuggerConnection,
-    triggers: Vec<Trigger>,
+    // triggers, each with its remaining ignore count
+    triggers: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    // to be passed to the machine on the next tick
+    watchpoints_changed: bool,
 }
 
 impl DebuggerServer {
     pub fn new(con: DebuggerConnection) -> Self {
-        DebuggerServer { con, triggers: vec![] }
+        DebuggerServer { con, triggers: vec![], watchpoints: vec![], watchpoints_changed: false }
+    }
+
+    /**
+     * Replace all breakpoints and watchpoints with those in `config`, in
+     * one go. Watchpoints take effect at the end of the next tick().
+     */
+    pub fn apply_debug_config(&mut self, config: &DebugConfig) {
+        self.triggers = config.breakpoints.clone();
+        self.watchpoints = config.watchpoints.clone();
+        self.watchpoints_changed = true;
+    }
+
+    /**
+     * The current breakpoints, with what remains of their ignore counts,
+     * and watchpoints.
+     */
+    pub fn export_debug_config(&self) -> DebugConfig {
+        DebugConfig {
+            breakpoints: self.triggers.clone(),
+            watchpoints: self.watchpoints.clone(),
+        }
+    }
+
+    fn add_trigger(&mut self, trigger: Trigger) {
+        self.triggers.push(Breakpoint { trigger, ignore_count: 0 });
+    }
+
+    fn on_watchpoint(&mut self, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) {
+        if let Some(hit) = machine.watchpoint_hit.take() {
+            self.con.tx.send(DebugResp::IsPaused(true)).unwrap();
+            self.con.tx.send(DebugResp::Message(format!("Watchpoint: {:?} of ${:x} (${:02x}) at PC=${:x}", hit.access, hit.addr, hit.value, hit.pc))).unwrap();
+            self.send_state(machine, cpu);
+            machine.paused = true;
+        }
     }
 
     fn on_out_of_bounds(&mut self, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) -> bool {
@@ -93,28 +252,31 @@ impl DebuggerServer {
     pub fn tick(&mut self, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) {
         let pc = cpu.state.pc();
 
-        // catch out of bounds memory accesses
+        // catch out of bounds memory accesses, and watchpoints
         self.on_out_of_bounds(machine, cpu);
+        self.on_watchpoint(machine, cpu);
 
-        // check triggers
-        if !machine.paused {
-            let to_run: Vec<Trigger> = self
-                .triggers
-                .iter()
-                .filter(|t| t.address == pc)
-                .map(|f| f.clone())
-                .collect();
-
-            for t in to_run {
-                for a in &t.actions {
-                    self.handle_debug_cmd(a, machine, cpu);
+        // check triggers, each counting down its own ignore count, and
+        // delete those that are only to execute once
+        let paused = machine.paused;
+        let mut to_run: Vec<Trigger> = vec![];
+        self.triggers.retain_mut(|b| {
+            if b.trigger.address != pc { return true; }
+            if !paused {
+                if b.ignore_count > 0 {
+                    b.ignore_count -= 1;
+                    return true;
                 }
+                to_run.push(b.trigger.clone());
+            }
+            !b.trigger.once
+        });
+        for t in &to_run {
+            for a in &t.actions {
+                self.handle_debug_cmd(a, machine, cpu);
             }
         }
 
-        // delete triggers that are only to execute once
-        self.triggers.retain(|b| !(b.address == pc && b.once));
-
         loop {
             match self.con.rx.try_recv() {
                 Ok(cmd) => self.handle_debug_cmd(&cmd, machine, cpu),
@@ -122,11 +284,16 @@ impl DebuggerServer {
                 Err(mpsc::TryRecvError::Empty) => break,
             }
         }
+
+        if std::mem::take(&mut self.watchpoints_changed) {
+            machine.set_watchpoints(self.watchpoints.clone());
+        }
         
         // clear any out of bounds memory access marker (either from z80 code
         // prior to this function's invocation, or caused by memory accesses
         // of the debugger here.
         machine.mem_out_of_bounds.set(None);
+        machine.watchpoint_hit.set(None);
     }
 
     fn handle_debug_cmd(&mut self, cmd: &DebugCmd, machine: &mut AgonMachine, cpu: &mut ez80::Cpu) {
@@ -141,7 +308,7 @@ impl DebuggerServer {
                 self.con.tx.send(DebugResp::Pong).unwrap();
             }
             DebugCmd::ListTriggers => {
-                self.con.tx.send(DebugResp::Triggers(self.triggers.clone())).unwrap();
+                self.con.tx.send(DebugResp::Triggers(self.triggers.iter().map(|b| b.trigger.clone()).collect())).unwrap();
             }
             DebugCmd::DisassemblePc { adl } => {
                 let start = cpu.state.pc();
@@ -164,7 +331,7 @@ impl DebuggerServer {
                     // RST instruction at (pc)
                     0xc7 | 0xd7 | 0xe7 | 0xf7 | 0xcf | 0xdf | 0xef | 0xff => {
                         let addr_next = pc + (if prefix_override.is_some() { 2 } else { 1 });
-                        self.triggers.push(Trigger {
+                        self.add_trigger(Trigger {
                             address: addr_next,
                             once: true,
                             actions: vec![
@@ -183,7 +350,7 @@ impl DebuggerServer {
                             Some(false) => 4,
                             _ => if cpu.state.reg.adl { 4 } else { 3 }
                         };
-                        self.triggers.push(Trigger {
+                        self.add_trigger(Trigger {
                             address: addr_next,
                             once: true,
                             actions: vec![
@@ -227,13 +394,20 @@ impl DebuggerServer {
                 }
             }
             DebugCmd::AddTrigger(t) => {
-                self.triggers.push(t.clone());
+                self.add_trigger(t.clone());
                 self.con.tx.send(DebugResp::Pong).unwrap();
             }
             DebugCmd::DeleteTrigger(addr) => {
-                self.triggers.retain(|b| b.address != *addr);
+                self.triggers.retain(|b| b.trigger.address != *addr);
+                self.con.tx.send(DebugResp::Pong).unwrap();
+            }
+            DebugCmd::ApplyDebugConfig(config) => {
+                self.apply_debug_config(config);
                 self.con.tx.send(DebugResp::Pong).unwrap();
             }
+            DebugCmd::ExportDebugConfig => {
+                self.con.tx.send(DebugResp::DebugConfig(self.export_debug_config())).unwrap();
+            }
             DebugCmd::Ping => self.con.tx.send(DebugResp::Pong).unwrap(),
             DebugCmd::GetRegisters => self.send_registers(cpu),
             DebugCmd::GetState => self.send_state(machine, cpu),
@@ -299,3 +473,128 @@ impl DebuggerServer {
         self.con.tx.send(DebugResp::Registers(cpu.registers().clone())).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agon_machine::tests::test_machine;
+    use ez80::{ Cpu, Machine, Reg16, z80_mem_tools };
+
+    #[test]
+    fn test_backtrace() {
+        let mut machine = test_machine();
+        let symbols: std::collections::HashMap<String, u32> = [
+            ("_main", 0x40000), ("_foo", 0x40100), ("_bar", 0x40200)
+        ].iter().map(|(n, a)| (n.to_string(), *a)).collect();
+
+        // main calls foo, which calls bar
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40010, &[0xcd, 0x00, 0x01, 0x04]);
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40120, &[0xcd, 0x00, 0x02, 0x04]);
+
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.set_pc(0x40205);
+        let sp = 0x6fff0;
+        cpu.state.reg.set24(Reg16::SP, sp);
+        // a saved register that isn't a return address
+        machine._poke24(sp, 0x40101);
+        machine._poke24(sp + 3, 0x40124);
+        machine._poke24(sp + 6, 0x40014);
+
+        let frames = backtrace(&machine, &cpu, &symbols, 16);
+        assert_eq!(frames, vec![
+            StackFrame { address: 0x40205, function: Some(("_bar".to_string(), 5)) },
+            StackFrame { address: 0x40124, function: Some(("_foo".to_string(), 0x24)) },
+            StackFrame { address: 0x40014, function: Some(("_main".to_string(), 0x14)) },
+        ]);
+
+        assert_eq!(backtrace(&machine, &cpu, &symbols, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_backtrace_has_no_side_effects() {
+        let mut machine = test_machine();
+        let symbols: std::collections::HashMap<String, u32> = [("_main".to_string(), 0x40000)].into();
+        z80_mem_tools::memcpy_to_z80(&mut machine, 0x40010, &[0xcd, 0x00, 0x01, 0x04]);
+
+        // the stack wraps from the top of memory to 0
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.reg.adl = true;
+        cpu.state.set_pc(0x40100);
+        cpu.state.reg.set24(Reg16::SP, 0xfffffd);
+        machine._poke24(0, 0x40014);
+
+        machine.set_access_log(Some(16));
+        machine.set_watchpoints(vec![Watchpoint { address: 0, len: 0x1000000, access: WatchAccess::ReadWrite }]);
+        machine.cycle_counter.set(0);
+
+        let frames = backtrace(&machine, &cpu, &symbols, 16);
+        assert_eq!(frames[1], StackFrame { address: 0x40014, function: Some(("_main".to_string(), 0x14)) });
+
+        assert_eq!(machine.cycle_counter.get(), 0);
+        assert!(machine.mem_out_of_bounds.get().is_none());
+        assert!(machine.watchpoint_hit.get().is_none());
+        assert!(machine.recent_accesses().is_empty());
+        assert!(machine.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_debug_config() {
+        let (tx_cmd, rx_cmd) = mpsc::channel();
+        let (tx_resp, rx_resp) = mpsc::channel();
+        let mut server = DebuggerServer::new(DebuggerConnection { tx: tx_resp, rx: rx_cmd });
+        let pause_at = |address, once| Trigger { address, once, actions: vec![DebugCmd::Pause] };
+        server.apply_debug_config(&DebugConfig {
+            breakpoints: vec![
+                Breakpoint { trigger: pause_at(0x40000, false), ignore_count: 3 },
+                Breakpoint { trigger: pause_at(0x40000, true), ignore_count: 1 },
+                Breakpoint { trigger: pause_at(0x40100, true), ignore_count: 0 },
+            ],
+            watchpoints: vec![Watchpoint { address: 0x50010, len: 4, access: WatchAccess::Write }],
+        });
+
+        let saved = server.export_debug_config();
+        tx_cmd.send(DebugCmd::ApplyDebugConfig(DebugConfig::default())).unwrap();
+        tx_cmd.send(DebugCmd::ListTriggers).unwrap();
+        tx_cmd.send(DebugCmd::ApplyDebugConfig(saved)).unwrap();
+        tx_cmd.send(DebugCmd::ExportDebugConfig).unwrap();
+
+        let mut machine = test_machine();
+        let mut cpu = Cpu::new_ez80();
+        cpu.state.set_pc(0x50000);
+        server.tick(&mut machine, &mut cpu);
+
+        assert!(matches!(rx_resp.try_recv(), Ok(DebugResp::Pong)));
+        assert!(matches!(rx_resp.try_recv(), Ok(DebugResp::Triggers(t)) if t.is_empty()));
+        assert!(matches!(rx_resp.try_recv(), Ok(DebugResp::Pong)));
+        let Ok(DebugResp::DebugConfig(restored)) = rx_resp.try_recv() else { panic!() };
+        assert_eq!(restored.watchpoints, vec![Watchpoint { address: 0x50010, len: 4, access: WatchAccess::Write }]);
+        let restored: Vec<(u32, bool, u32)> = restored.breakpoints.iter()
+            .map(|b| (b.trigger.address, b.trigger.once, b.ignore_count))
+            .collect();
+        assert_eq!(restored, vec![(0x40000, false, 3), (0x40000, true, 1), (0x40100, true, 0)]);
+
+        // each ignore count holds off its own breakpoint
+        cpu.state.set_pc(0x40000);
+        server.tick(&mut machine, &mut cpu);
+        assert!(!machine.paused);
+        server.tick(&mut machine, &mut cpu);
+        assert!(machine.paused);
+        let remaining: Vec<(bool, u32)> = server.export_debug_config().breakpoints.iter()
+            .map(|b| (b.trigger.once, b.ignore_count))
+            .collect();
+        assert_eq!(remaining, vec![(false, 1), (true, 0)]);
+
+        // the watchpoint pauses on a write, but not a read, in its range
+        machine.paused = false;
+        cpu.state.set_pc(0x50000);
+        machine.peek(0x50010);
+        machine.poke(0x50014, 1);
+        server.tick(&mut machine, &mut cpu);
+        assert!(!machine.paused);
+        machine.poke(0x50013, 1);
+        server.tick(&mut machine, &mut cpu);
+        assert!(machine.paused);
+        assert!(machine.watchpoint_hit.get().is_none());
+    }
+}
@@ -1,3 +1,10 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GpioPort {
+    B,
+    C,
+    D
+}
+
 pub struct GpioSet {
     pub b: Gpio,
     pub c: Gpio,
@@ -12,6 +19,22 @@ impl GpioSet {
             d: Gpio::new(),
         }
     }
+
+    pub fn port(&self, port: GpioPort) -> &Gpio {
+        match port {
+            GpioPort::B => &self.b,
+            GpioPort::C => &self.c,
+            GpioPort::D => &self.d,
+        }
+    }
+
+    pub fn port_mut(&mut self, port: GpioPort) -> &mut Gpio {
+        match port {
+            GpioPort::B => &mut self.b,
+            GpioPort::C => &mut self.c,
+            GpioPort::D => &mut self.d,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -37,7 +60,7 @@ impl Gpio {
         self.set_input_pins(level);
     }
 
-    pub fn get_interrupt_due(&mut self) -> u8 { self.interrupt_due }
+    pub fn get_interrupt_due(&self) -> u8 { self.interrupt_due }
 
     // Note these aren't the mode numbers zilog docs use, but these made more sense
     // - pin 0..=7
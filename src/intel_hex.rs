@@ -0,0 +1,104 @@
+//! Intel HEX file parsing, for loading programs into emulated memory.
+//!
+//! Supports data (00), end of file (01), extended segment address (02)
+//! and extended linear address (04) records. Start address records
+//! (03, 05) are accepted and ignored.
+
+/// A run of bytes to be written starting at `address`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexChunk {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// True if `contents` looks like Intel HEX: it starts with ':' and every
+/// non-blank line is a well-formed record.
+pub fn looks_like_intel_hex(contents: &[u8]) -> bool {
+    match std::str::from_utf8(contents) {
+        Ok(text) => text.trim_start().starts_with(':') && parse_intel_hex(text).is_ok(),
+        Err(_) => false
+    }
+}
+
+pub fn parse_intel_hex(text: &str) -> Result<Vec<HexChunk>, String> {
+    let mut chunks = vec![];
+    let mut base_address: u32 = 0;
+
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() { continue }
+
+        let record = parse_record(line).map_err(|e| format!("line {}: {}", line_no + 1, e))?;
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let rec_type = record[3];
+        let data = &record[4..];
+
+        match rec_type {
+            0x00 => chunks.push(HexChunk { address: base_address + offset, data: data.to_vec() }),
+            0x01 => break,
+            0x02 | 0x04 => {
+                if data.len() != 2 {
+                    return Err(format!("line {}: malformed extended address record", line_no + 1));
+                }
+                let v = u16::from_be_bytes([data[0], data[1]]) as u32;
+                base_address = if rec_type == 0x02 { v << 4 } else { v << 16 };
+            }
+            0x03 | 0x05 => {}
+            _ => return Err(format!("line {}: unsupported record type {:02x}", line_no + 1, rec_type))
+        }
+    }
+
+    Ok(chunks)
+}
+
+// decode and checksum ':llaaaatt[dd...]cc', returning the bytes without the checksum
+fn parse_record(line: &str) -> Result<Vec<u8>, String> {
+    let hex = line.strip_prefix(':').ok_or("record does not start with ':'")?;
+    // non-ASCII would split a char when sliced into digit pairs
+    if !hex.is_ascii() || hex.len() % 2 != 0 || hex.len() < 10 {
+        return Err("malformed record".to_string());
+    }
+
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "invalid hex digit".to_string()))
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    if bytes.len() != bytes[0] as usize + 5 {
+        return Err("record length mismatch".to_string());
+    }
+    if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+        return Err("bad checksum".to_string());
+    }
+
+    Ok(bytes[..bytes.len() - 1].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_intel_hex() {
+        let hex = ":020000040004F6\n\
+                   :0400000001020304F2\n\
+                   :02001000AABB89\n\
+                   :00000001FF\n";
+        assert_eq!(parse_intel_hex(hex).unwrap(), vec![
+            HexChunk { address: 0x40000, data: vec![1, 2, 3, 4] },
+            HexChunk { address: 0x40010, data: vec![0xaa, 0xbb] },
+        ]);
+        assert!(looks_like_intel_hex(hex.as_bytes()));
+    }
+
+    #[test]
+    fn test_reject_bad_hex() {
+        // bad checksum
+        assert!(parse_intel_hex(":0400000001020304F3\n").is_err());
+        // binary that happens to start with ':'
+        assert!(!looks_like_intel_hex(b":\x01\x02\x03"));
+        assert!(!looks_like_intel_hex(b"\x3e\x3a\xc9"));
+        // non-ASCII, with an even byte count
+        assert!(parse_intel_hex(":\u{e9}0000000000\n").is_err());
+        assert!(!looks_like_intel_hex(":\u{e9}0000000000".as_bytes()));
+    }
+}
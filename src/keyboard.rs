@@ -0,0 +1,160 @@
+//! Turning host text into VDP keyboard packets, for injecting keystrokes
+//! into MOS as if typed on a keyboard with a given layout.
+
+use std::collections::HashMap;
+
+// VDP keyboard packet modifier bits (as the MOS sysvar_keymods)
+pub const MOD_CTRL: u8 = 0x01;
+pub const MOD_SHIFT: u8 = 0x02;
+pub const MOD_ALT_LEFT: u8 = 0x04;
+pub const MOD_ALT_RIGHT: u8 = 0x08; // AltGr
+
+/// What the VDP reports for one key press
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub keycode: u8,
+    pub modifiers: u8,
+    pub vkey: u8,
+}
+
+/// The layout MOS is configured for, which decides the modifiers (and
+/// virtual key codes) sent with each character.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum KeyboardLayout {
+    #[default]
+    Us,
+    Uk,
+    /// Characters not in the map are sent as on the US layout
+    Custom(HashMap<char, KeyStroke>),
+}
+
+// FabGL virtual key codes, as the VDP reports in the vkey field
+const VK_SPACE: u8 = 1;
+const VK_0: u8 = 2;
+const VK_LOWER_A: u8 = 22;
+const VK_UPPER_A: u8 = 48;
+const VK_ESCAPE: u8 = 125;
+const VK_DELETE: u8 = 130;
+const VK_BACKSPACE: u8 = 132;
+const VK_TAB: u8 = 142;
+const VK_RETURN: u8 = 143;
+
+// the virtual key of each symbol, whichever layout it is typed on
+const VK_SYMBOLS: [(char, u8); 37] = [
+    ('`', 74), ('\'', 76), ('"', 77), ('=', 78), ('-', 79), ('+', 81), ('*', 84),
+    ('\\', 85), ('/', 87), ('.', 89), (':', 90), (',', 91), (';', 92), ('&', 93),
+    ('|', 94), ('#', 95), ('@', 96), ('^', 97), ('$', 98), ('£', 99), ('€', 100),
+    ('%', 101), ('!', 102), ('?', 103), ('{', 104), ('}', 105), ('[', 106),
+    (']', 107), ('(', 108), (')', 109), ('<', 110), ('>', 111), ('_', 112),
+    ('°', 113), ('§', 114), ('~', 115), ('¬', 116),
+];
+
+// symbols typed with shift or AltGr held, per layout. other printable
+// ASCII is typed unmodified, and other non-ASCII can't be typed
+const US_SHIFTED: &str = "~!@#$%^&*()_+{}|:\"<>?";
+const US_ALTGR: &str = "";
+const UK_SHIFTED: &str = "¬!\"£$%^&*()_+{}:@~|<>?";
+const UK_ALTGR: &str = "€";
+
+impl KeyboardLayout {
+    /// The keystroke typing `ch`, or None for characters the layout can't type
+    pub fn key_stroke(&self, ch: char) -> Option<KeyStroke> {
+        let (shifted, altgr) = match self {
+            KeyboardLayout::Custom(map) => {
+                if let Some(stroke) = map.get(&ch) {
+                    return Some(*stroke);
+                }
+                (US_SHIFTED, US_ALTGR)
+            }
+            KeyboardLayout::Us => (US_SHIFTED, US_ALTGR),
+            KeyboardLayout::Uk => (UK_SHIFTED, UK_ALTGR),
+        };
+
+        let (vkey, modifiers) = match ch {
+            '\r' => (VK_RETURN, 0),
+            '\t' => (VK_TAB, 0),
+            '\x08' => (VK_BACKSPACE, 0),
+            '\x1b' => (VK_ESCAPE, 0),
+            '\x7f' => (VK_DELETE, 0),
+            // ctrl-a to ctrl-z
+            '\x01'..='\x1a' => (VK_LOWER_A + ch as u8 - 1, MOD_CTRL),
+            ' ' => (VK_SPACE, 0),
+            '0'..='9' => (VK_0 + ch as u8 - b'0', 0),
+            'a'..='z' => (VK_LOWER_A + ch as u8 - b'a', 0),
+            'A'..='Z' => (VK_UPPER_A + ch as u8 - b'A', MOD_SHIFT),
+            _ => {
+                let modifiers = if shifted.contains(ch) {
+                    MOD_SHIFT
+                } else if altgr.contains(ch) {
+                    MOD_ALT_RIGHT
+                } else if ch.is_ascii_graphic() {
+                    0
+                } else {
+                    return None
+                };
+                let &(_, vkey) = VK_SYMBOLS.iter().find(|(c, _)| *c == ch)?;
+                (vkey, modifiers)
+            }
+        };
+        // the VDP's keycode is ASCII, except for the few symbols beyond it
+        let keycode = match ch {
+            '£' => 163,
+            '€' => 128,
+            '°' => 248,
+            '§' => 0x15,
+            '¬' => 0xaa,
+            _ => ch as u8,
+        };
+        Some(KeyStroke { keycode, modifiers, vkey })
+    }
+}
+
+/// Key down and key up packets for each character of `text` in turn.
+/// Characters the layout can't type are skipped.
+pub fn key_packets(layout: &KeyboardLayout, text: &str) -> Vec<u8> {
+    let mut packets = vec![];
+    for stroke in text.chars().filter_map(|ch| layout.key_stroke(ch)) {
+        for keydown in [1, 0] {
+            // cmd, len, keycode, modifiers, vkey, keydown
+            packets.extend_from_slice(&[0x81, 4, stroke.keycode, stroke.modifiers, stroke.vkey, keydown]);
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() {
+        // '#' is shift-3 on a US keyboard, but has its own key on a UK one
+        assert_eq!(key_packets(&KeyboardLayout::Us, "#"), vec![
+            0x81, 4, b'#', MOD_SHIFT, 95, 1,
+            0x81, 4, b'#', MOD_SHIFT, 95, 0,
+        ]);
+        assert_eq!(key_packets(&KeyboardLayout::Uk, "#"), vec![
+            0x81, 4, b'#', 0, 95, 1,
+            0x81, 4, b'#', 0, 95, 0,
+        ]);
+        assert_eq!(KeyboardLayout::Uk.key_stroke('A'), Some(KeyStroke { keycode: b'A', modifiers: MOD_SHIFT, vkey: 48 }));
+        assert_eq!(KeyboardLayout::Us.key_stroke('a'), Some(KeyStroke { keycode: b'a', modifiers: 0, vkey: 22 }));
+        assert_eq!(KeyboardLayout::Us.key_stroke('\r'), Some(KeyStroke { keycode: 13, modifiers: 0, vkey: 143 }));
+        assert_eq!(KeyboardLayout::Us.key_stroke('\x03'), Some(KeyStroke { keycode: 3, modifiers: MOD_CTRL, vkey: 24 }));
+
+        // UK-only keys: '£' is shift-3, '€' is AltGr-4, '"' moves to shift-2
+        assert_eq!(KeyboardLayout::Uk.key_stroke('£'), Some(KeyStroke { keycode: 163, modifiers: MOD_SHIFT, vkey: 99 }));
+        assert_eq!(KeyboardLayout::Uk.key_stroke('€'), Some(KeyStroke { keycode: 128, modifiers: MOD_ALT_RIGHT, vkey: 100 }));
+        assert_eq!(KeyboardLayout::Uk.key_stroke('"').unwrap().modifiers, MOD_SHIFT);
+        assert_eq!(KeyboardLayout::Uk.key_stroke('\\').unwrap().modifiers, 0);
+        assert_eq!(KeyboardLayout::Us.key_stroke('£'), None);
+        assert_eq!(KeyboardLayout::Us.key_stroke('€'), None);
+
+        // German: '@' is AltGr-q
+        let de = KeyboardLayout::Custom(HashMap::from([
+            ('@', KeyStroke { keycode: b'@', modifiers: MOD_ALT_RIGHT, vkey: 0x60 }),
+        ]));
+        assert_eq!(key_packets(&de, "@a")[..6], [0x81, 4, b'@', MOD_ALT_RIGHT, 0x60, 1]);
+        assert_eq!(de.key_stroke('a'), KeyboardLayout::Us.key_stroke('a'));
+    }
+}
@@ -1,12 +1,30 @@
 mod agon_machine;
 mod mos;
 mod prt_timer;
-mod symbol_map;
+pub mod symbol_map;
+mod intel_hex;
 mod uart;
+mod rtc;
+#[cfg(test)]
+mod test_asm;
 mod spi_sdcard;
+pub mod vdp_responder;
+pub mod vdp_protocol;
+pub mod tcp_serial;
+pub mod paced_display;
 pub mod debugger;
 pub mod gpio;
-pub use uart::SerialLink;
+pub mod keyboard;
+pub mod serial_script;
+pub use uart::{ SerialLink, EmptyRxRead, FrameErrors, DEFAULT_MAX_TRANSMIT_COOLDOWN };
+pub use tcp_serial::TcpSerialLink;
 pub use agon_machine::AgonMachine;
 pub use agon_machine::AgonMachineConfig;
 pub use agon_machine::RamInit;
+pub use agon_machine::GuestFileInfo;
+pub use agon_machine::RegisterSetup;
+pub use agon_machine::{ BootError, StopReason };
+pub use agon_machine::{ MemAccess, MemAccessKind };
+pub use agon_machine::{ MachineEvent, ResetKind, ResetHandle, TripleFaultAction, BusyWaitDetection, FetchHook };
+pub use agon_machine::{ Interrupt, InterruptSource };
+pub use mos::{ MosCall, CallStat, MosCallRecord, FGetsConfig, MosLogMode, MosLogRecord, MosLogSink, FlashSignature, StarCommandHandler, fresult_name };
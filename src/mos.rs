@@ -1,8 +1,41 @@
-use ez80::Machine;
+use ez80::{ Machine, Cpu, Reg16 };
 
 // MOS error codes (enum FRESULT)
 pub const FR_OK: u32 = 0;
 pub const FR_DISK_ERR: u32 = 1;
+pub const FR_NO_FILE: u32 = 4;
+pub const FR_INVALID_NAME: u32 = 6;
+pub const FR_DENIED: u32 = 7;
+pub const FR_WRITE_PROTECTED: u32 = 10;
+pub const FR_LOCKED: u32 = 16;
+pub const FR_TOO_MANY_OPEN_FILES: u32 = 18;
+pub const FR_INVALID_PARAMETER: u32 = 19;
+
+pub fn fresult_name(fresult: u32) -> &'static str {
+    match fresult {
+        0 => "FR_OK",
+        1 => "FR_DISK_ERR",
+        2 => "FR_INT_ERR",
+        3 => "FR_NOT_READY",
+        4 => "FR_NO_FILE",
+        5 => "FR_NO_PATH",
+        6 => "FR_INVALID_NAME",
+        7 => "FR_DENIED",
+        8 => "FR_EXIST",
+        9 => "FR_INVALID_OBJECT",
+        10 => "FR_WRITE_PROTECTED",
+        11 => "FR_INVALID_DRIVE",
+        12 => "FR_NOT_ENABLED",
+        13 => "FR_NO_FILESYSTEM",
+        14 => "FR_MKFS_ABORTED",
+        15 => "FR_TIMEOUT",
+        16 => "FR_LOCKED",
+        17 => "FR_NOT_ENOUGH_CORE",
+        18 => "FR_TOO_MANY_OPEN_FILES",
+        19 => "FR_INVALID_PARAMETER",
+        _ => "FR_UNKNOWN"
+    }
+}
 
 // FatFS struct FIL
 pub const SIZEOF_MOS_FIL_STRUCT: u32 = 36;
@@ -21,6 +54,148 @@ pub const FILINFO_MEMBER_FNAME_256BYTES: u32 = 22;
 pub const FA_WRITE: u32 = 2;
 pub const FA_CREATE_NEW: u32 = 4;
 pub const FA_CREATE_ALWAYS: u32 = 8;
+// FILINFO fattrib bits
+pub const AM_RDO: u8 = 0x01;
+pub const AM_HID: u8 = 0x02;
+pub const AM_SYS: u8 = 0x04;
+pub const AM_DIR: u8 = 0x10;
+pub const AM_ARC: u8 = 0x20;
+
+/// The fatfs functions intercepted by hostfs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MosCall {
+    FChdir,
+    FChmod,
+    FClose,
+    FClosedir,
+    FGetcwd,
+    FGetlabel,
+    FGets,
+    FLseek,
+    FMkdir,
+    FMount,
+    FOpen,
+    FOpendir,
+    FPutc,
+    FRead,
+    FReaddir,
+    FRename,
+    FStat,
+    FTruncate,
+    FUnlink,
+    FUtime,
+    FWrite,
+}
+
+impl MosCall {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MosCall::FChdir => "f_chdir",
+            MosCall::FChmod => "f_chmod",
+            MosCall::FClose => "f_close",
+            MosCall::FClosedir => "f_closedir",
+            MosCall::FGetcwd => "f_getcwd",
+            MosCall::FGetlabel => "f_getlabel",
+            MosCall::FGets => "f_gets",
+            MosCall::FLseek => "f_lseek",
+            MosCall::FMkdir => "f_mkdir",
+            MosCall::FMount => "f_mount",
+            MosCall::FOpen => "f_open",
+            MosCall::FOpendir => "f_opendir",
+            MosCall::FPutc => "f_putc",
+            MosCall::FRead => "f_read",
+            MosCall::FReaddir => "f_readdir",
+            MosCall::FRename => "f_rename",
+            MosCall::FStat => "f_stat",
+            MosCall::FTruncate => "f_truncate",
+            MosCall::FUnlink => "f_unlink",
+            MosCall::FUtime => "f_utime",
+            MosCall::FWrite => "f_write",
+        }
+    }
+
+    /// Number of (24-bit) stack words of arguments
+    pub fn arg_count(&self) -> u32 {
+        match self {
+            MosCall::FChdir | MosCall::FClose | MosCall::FClosedir | MosCall::FMkdir |
+            MosCall::FTruncate | MosCall::FUnlink => 1,
+            MosCall::FGetcwd | MosCall::FLseek | MosCall::FOpendir | MosCall::FPutc |
+            MosCall::FReaddir | MosCall::FRename | MosCall::FStat | MosCall::FUtime => 2,
+            MosCall::FChmod | MosCall::FGetlabel | MosCall::FGets | MosCall::FMount |
+            MosCall::FOpen => 3,
+            MosCall::FRead | MosCall::FWrite => 4,
+        }
+    }
+
+    /// Index of the (first) path argument, for calls that take one
+    pub fn path_arg(&self) -> Option<u32> {
+        match self {
+            MosCall::FChdir | MosCall::FChmod | MosCall::FMkdir | MosCall::FRename |
+            MosCall::FStat | MosCall::FUnlink | MosCall::FUtime => Some(0),
+            MosCall::FOpen | MosCall::FOpendir => Some(1),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MosLogMode {
+    All,
+    FailuresOnly,
+}
+
+/// Line handling for the f_gets hook, to match the firmware's FatFS build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FGetsConfig {
+    /// End the line at a NUL byte (the NUL is consumed but not stored).
+    /// FatFS itself copies NULs into the buffer like any other byte.
+    pub stop_at_nul: bool,
+    /// Drop '\r', as FatFS does with FF_USE_STRFUNC = 2
+    pub strip_cr: bool,
+}
+
+impl Default for FGetsConfig {
+    fn default() -> Self {
+        FGetsConfig { stop_at_nul: true, strip_cr: false }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MosLogRecord {
+    pub call: MosCall,
+    pub path: Option<String>,
+    pub fresult: u32,
+}
+
+impl std::fmt::Display for MosLogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}(\"{}\") -> {}", self.call.name(), path, fresult_name(self.fresult)),
+            None => write!(f, "{}() -> {}", self.call.name(), fresult_name(self.fresult))
+        }
+    }
+}
+
+pub type MosLogSink = Box<dyn FnMut(&MosLogRecord)>;
+
+/// An intercepted call, as recorded by AgonMachine::set_mos_call_recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MosCallRecord {
+    pub call: MosCall,
+    /// Arguments as passed on the stack
+    pub args: Vec<u32>,
+    /// The path argument's string, for calls taking one
+    pub path: Option<String>,
+    pub fresult: u32,
+}
+
+/// How often a MosCall was intercepted, and the emulated cycles its host
+/// implementation used (for its guest memory accesses)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallStat {
+    pub count: u64,
+    pub cycles: u64,
+}
 
 #[derive(Clone, Default)]
 pub struct MosMap {
@@ -49,6 +224,11 @@ pub struct MosMap {
     pub f_truncate: u32,
     pub f_unlink: u32,
     pub f_write: u32,
+    // optional, since MOS doesn't always link them
+    pub f_chmod: Option<u32>,
+    pub f_utime: Option<u32>,
+    // optional, since it's not needed for hostfs
+    pub mos_exec: Option<u32>,
 }
 
 impl MosMap {
@@ -81,9 +261,153 @@ impl MosMap {
         mos_map.f_truncate = *(map.get("_f_truncate").ok_or(err)?);
         mos_map.f_unlink = *(map.get("_f_unlink").ok_or(err)?);
         mos_map.f_write = *(map.get("_f_write").ok_or(err)?);
+        mos_map.f_chmod = map.get("_f_chmod").copied();
+        mos_map.f_utime = map.get("_f_utime").copied();
+        mos_map.mos_exec = map.get("_mos_exec").copied();
 
         Ok(mos_map)
     }
+
+    pub fn call_at(&self, pc: u32) -> Option<MosCall> {
+        if pc == self.f_close { Some(MosCall::FClose) }
+        else if pc == self.f_gets { Some(MosCall::FGets) }
+        else if pc == self.f_read { Some(MosCall::FRead) }
+        else if pc == self.f_open { Some(MosCall::FOpen) }
+        else if pc == self.f_write { Some(MosCall::FWrite) }
+        else if pc == self.f_chdir { Some(MosCall::FChdir) }
+        else if pc == self.f_closedir { Some(MosCall::FClosedir) }
+        else if pc == self.f_getlabel { Some(MosCall::FGetlabel) }
+        else if pc == self.f_getcwd { Some(MosCall::FGetcwd) }
+        else if pc == self.f_lseek { Some(MosCall::FLseek) }
+        else if pc == self.f_mkdir { Some(MosCall::FMkdir) }
+        else if pc == self.f_mount { Some(MosCall::FMount) }
+        else if pc == self.f_opendir { Some(MosCall::FOpendir) }
+        else if pc == self.f_putc { Some(MosCall::FPutc) }
+        else if pc == self.f_readdir { Some(MosCall::FReaddir) }
+        else if pc == self.f_rename { Some(MosCall::FRename) }
+        else if pc == self.f_stat { Some(MosCall::FStat) }
+        else if pc == self.f_unlink { Some(MosCall::FUnlink) }
+        else if pc == self.f_truncate { Some(MosCall::FTruncate) }
+        else if Some(pc) == self.f_chmod { Some(MosCall::FChmod) }
+        else if Some(pc) == self.f_utime { Some(MosCall::FUtime) }
+        else { None }
+    }
+}
+
+/// A byte pattern identifying the start of a MOS function in flash.
+/// `None` entries are wildcards (typically absolute addresses embedded
+/// in the code).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashSignature {
+    pub symbol: String,
+    pub pattern: Vec<Option<u8>>,
+}
+
+impl FlashSignature {
+    /// Parse a pattern of space-separated hex bytes, with `??` as a
+    /// wildcard, eg: "dd e5 dd 21 00 00 00 dd 39 ?? ?? ??"
+    pub fn new(symbol: &str, pattern: &str) -> Result<FlashSignature, String> {
+        let pattern = pattern.split_whitespace().map(|b| {
+            if b == "??" {
+                Ok(None)
+            } else {
+                u8::from_str_radix(b, 16).map(Some).map_err(|_| format!("invalid signature byte '{}'", b))
+            }
+        }).collect::<Result<Vec<Option<u8>>, String>>()?;
+
+        if pattern.is_empty() {
+            return Err(format!("empty signature for {}", symbol));
+        }
+        Ok(FlashSignature { symbol: symbol.to_string(), pattern })
+    }
+
+    fn matches_at(&self, flash: &[u8], offset: usize) -> bool {
+        flash.len() - offset >= self.pattern.len() &&
+            self.pattern.iter().zip(&flash[offset..]).all(|(p, b)| p.is_none_or(|p| p == *b))
+    }
+}
+
+impl MosMap {
+    /**
+     * EXPERIMENTAL: locate the fatfs functions by searching flash for the
+     * given code signatures, for when no .map file is available.
+     *
+     * This is best-effort only. Signatures are specific to the MOS
+     * version (and compiler) that built the flash image, so a signature
+     * set that works for one release may find nothing in the next.
+     * A signature matching zero or several locations counts as not found,
+     * and None is returned unless every fatfs symbol is located.
+     */
+    pub fn from_flash_signatures(flash: &[u8], signatures: &[FlashSignature]) -> Option<MosMap> {
+        let mut map = std::collections::HashMap::new();
+
+        for sig in signatures {
+            let mut found = (0..flash.len()).filter(|offset| sig.matches_at(flash, *offset));
+            match (found.next(), found.next()) {
+                (Some(address), None) => { map.insert(sig.symbol.clone(), address as u32); }
+                _ => return None
+            }
+        }
+
+        MosMap::from_symbol_map(map).ok()
+    }
+
+    /**
+     * EXPERIMENTAL: from_flash_signatures() over the flash loaded into
+     * `machine`, with the signatures given to its
+     * set_mos_flash_signatures(). As best-effort as that: None if no
+     * signatures are set.
+     */
+    pub fn from_flash_scan(machine: &crate::AgonMachine) -> Option<MosMap> {
+        MosMap::from_flash_signatures(machine.flash(), machine.mos_flash_signatures())
+    }
+}
+
+/// Host handler for a MOS star command. Given the argument string, returns
+/// the MOS error code (0 for success)
+pub type StarCommandHandler = Box<dyn FnMut(&str) -> u32>;
+
+/**
+ * Split a MOS command line into command name and argument string,
+ * eg "*load foo.bin" -> ("load", "foo.bin")
+ */
+pub fn parse_star_command(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start_matches([' ', '*']);
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, args.trim_start()))
+    }
+}
+
+/**
+ * Whether `name` (one path component) is a valid 8.3 short file name,
+ * as FatFS requires when built without long file name support. Case
+ * doesn't matter, as FatFS upper-cases short names.
+ */
+pub fn is_valid_sfn(name: &str) -> bool {
+    let (base, ext) = name.split_once('.').unwrap_or((name, "x"));
+    let valid = |part: &str, max_len: usize| {
+        !part.is_empty() && part.len() <= max_len &&
+            part.bytes().all(|c| c > b' ' && c != 0x7f && !b"\"*+,./:;<=>?[\\]|".contains(&c))
+    };
+    valid(base, 8) && valid(ext, 3)
+}
+
+/**
+ * Return `fresult` from a hooked fatfs call. MOS is built with ZDS C,
+ * which returns ints (FRESULT being an enum) in HL, all 24 bits of it.
+ */
+pub fn set_fresult(cpu: &mut Cpu, fresult: u32) {
+    cpu.state.reg.set24(Reg16::HL, fresult);
+}
+
+/**
+ * The FRESULT a hooked fatfs call returned, as set by set_fresult.
+ */
+pub fn get_fresult(cpu: &Cpu) -> u32 {
+    cpu.state.reg.get24(Reg16::HL)
 }
 
 /**
@@ -92,6 +416,16 @@ impl MosMap {
  */
 pub fn get_mos_path_string<M: Machine>(machine: &M, address: u32) -> Vec<u8> {
     let mut s: Vec<u8> = vec![];
+    get_mos_path_string_into(machine, address, &mut s);
+    s
+}
+
+/**
+ * As get_mos_path_string, but into a caller's scratch buffer (replacing
+ * its contents), so repeated calls needn't allocate.
+ */
+pub fn get_mos_path_string_into<M: Machine>(machine: &M, address: u32, s: &mut Vec<u8>) {
+    s.clear();
     let mut ptr = address;
 
     loop {
@@ -101,5 +435,114 @@ pub fn get_mos_path_string<M: Machine>(machine: &M, address: u32) -> Vec<u8> {
         }
         ptr += 1;
     }
-    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_sfn() {
+        for name in ["README.TXT", "a", "game.bin", "12345678.abc", "MY-FILE1.C"] {
+            assert!(is_valid_sfn(name), "{}", name);
+        }
+        for name in ["", "longfilename.txt", "file.text", "a.b.c", "with space", "a+b", ".hidden", "file."] {
+            assert!(!is_valid_sfn(name), "{}", name);
+        }
+    }
+
+    const FS_SYMBOLS: [&str; 25] = [
+        "_f_chdir", "_f_chdrive", "_f_close", "_f_closedir", "_f_getcwd",
+        "_f_getfree", "_f_getlabel", "_f_gets", "_f_lseek", "_f_mkdir",
+        "_f_mount", "_f_open", "_f_opendir", "_f_printf", "_f_putc",
+        "_f_puts", "_f_read", "_f_readdir", "_f_rename", "_f_setlabel",
+        "_f_stat", "_f_sync", "_f_truncate", "_f_unlink", "_f_write",
+    ];
+
+    // a distinct (fake) prologue for each function, at 0x100 intervals,
+    // with junk where the wildcards are, and their signatures
+    fn flash_scan_fixture() -> (Vec<u8>, Vec<FlashSignature>) {
+        let mut flash = vec![0xffu8; 0x20000];
+        let mut signatures = vec![];
+        for (i, sym) in FS_SYMBOLS.iter().enumerate() {
+            let address = 0x1000 + 0x100 * i;
+            flash[address..address + 6].copy_from_slice(&[0xdd, 0xe5, i as u8, 0x12, 0x34, 0xc9]);
+            signatures.push(FlashSignature::new(sym, &format!("dd e5 {:02x} ?? ?? c9", i)).unwrap());
+        }
+        (flash, signatures)
+    }
+
+    #[test]
+    fn test_from_flash_signatures() {
+        let (mut flash, signatures) = flash_scan_fixture();
+
+        let mos_map = MosMap::from_flash_signatures(&flash, &signatures).unwrap();
+        assert_eq!(mos_map.f_chdir, 0x1000);
+        assert_eq!(mos_map.f_open, 0x1000 + 0x100 * 11);
+        assert_eq!(mos_map.f_write, 0x1000 + 0x100 * 24);
+        assert_eq!(mos_map.call_at(0x1000 + 0x100 * 16), Some(MosCall::FRead));
+
+        // an ambiguous signature means no map
+        flash[0x8000..0x8006].copy_from_slice(&[0xdd, 0xe5, 0, 0, 0, 0xc9]);
+        assert!(MosMap::from_flash_signatures(&flash, &signatures).is_none());
+
+        // as does a missing function
+        assert!(MosMap::from_flash_signatures(&flash, &signatures[1..]).is_none());
+    }
+
+    #[test]
+    fn test_from_flash_scan() {
+        let (flash, signatures) = flash_scan_fixture();
+        let mut machine = crate::agon_machine::tests::test_machine();
+        ez80::z80_mem_tools::memcpy_to_z80(&mut machine, 0, &flash);
+        assert!(MosMap::from_flash_scan(&machine).is_none());
+
+        machine.set_mos_flash_signatures(signatures);
+        let mos_map = MosMap::from_flash_scan(&machine).unwrap();
+        assert_eq!(mos_map.f_open, 0x1000 + 0x100 * 11);
+        assert_eq!(mos_map.call_at(0x1000 + 0x100 * 2), Some(MosCall::FClose));
+    }
+
+    struct TestMem(Vec<u8>);
+    impl Machine for TestMem {
+        fn use_cycles(&self, _cycles: u32) {}
+        fn peek(&self, address: u32) -> u8 { self.0[address as usize] }
+        fn poke(&mut self, address: u32, value: u8) { self.0[address as usize] = value; }
+        fn port_in(&mut self, _address: u16) -> u8 { 0 }
+        fn port_out(&mut self, _address: u16, _value: u8) {}
+    }
+
+    #[test]
+    fn test_get_mos_path_string_into() {
+        let mut mem = TestMem(vec![0; 0x100]);
+        mem.0[..12].copy_from_slice(b"/mos/a.bin\r\0");
+        mem.0[0x80..0x85].copy_from_slice(b"b.txt");
+        assert_eq!(get_mos_path_string(&mem, 0), b"/mos/a.bin");
+
+        let mut scratch = Vec::with_capacity(256);
+        let buf_ptr = scratch.as_ptr();
+        for i in 0..10_000 {
+            let address = if i % 2 == 0 { 0 } else { 0x80 };
+            get_mos_path_string_into(&mem, address, &mut scratch);
+            assert_eq!(scratch, if i % 2 == 0 { &b"/mos/a.bin"[..] } else { b"b.txt" });
+        }
+        // never reallocated
+        assert_eq!(scratch.as_ptr(), buf_ptr);
+        assert_eq!(scratch.capacity(), 256);
+    }
+
+    #[test]
+    fn test_parse_star_command() {
+        assert_eq!(parse_star_command("*load foo.bin 40000"), Some(("load", "foo.bin 40000")));
+        assert_eq!(parse_star_command("  * hello   world"), Some(("hello", "world")));
+        assert_eq!(parse_star_command("cat"), Some(("cat", "")));
+        assert_eq!(parse_star_command(" * "), None);
+    }
+
+    #[test]
+    fn test_flash_signature_parse() {
+        assert_eq!(FlashSignature::new("_f_open", "dd ?? c9").unwrap().pattern, vec![Some(0xdd), None, Some(0xc9)]);
+        assert!(FlashSignature::new("_f_open", "dd xx").is_err());
+        assert!(FlashSignature::new("_f_open", "").is_err());
+    }
 }
@@ -0,0 +1,139 @@
+//! Pacing UART output for live display. The guest's bytes go on to the
+//! wrapped link as fast as it sends them, while a copy is released to a
+//! display sink at a steady, readable rate in host (wall clock) time,
+//! however fast emulation runs.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+use crate::uart::SerialLink;
+
+/// Wraps another link, also passing each transmitted byte to `sink` at no
+/// more than `chars_per_second`. Bytes not yet due are buffered.
+pub struct PacedDisplayLink {
+    inner: Box<dyn SerialLink>,
+    sink: Box<dyn FnMut(u8)>,
+    // 0 for no pacing
+    chars_per_second: u32,
+    pending: VecDeque<u8>,
+    // characters that may be released, as of last_release
+    credit: f64,
+    last_release: Instant,
+}
+
+impl PacedDisplayLink {
+    pub fn new(inner: Box<dyn SerialLink>, sink: Box<dyn FnMut(u8)>, chars_per_second: u32) -> Self {
+        PacedDisplayLink { inner, sink, chars_per_second, pending: VecDeque::new(), credit: 1.0, last_release: Instant::now() }
+    }
+
+    /// Change the display rate. 0 releases everything as it is sent
+    pub fn set_chars_per_second(&mut self, chars_per_second: u32) {
+        self.chars_per_second = chars_per_second;
+    }
+
+    /// Bytes sent but not yet released to the sink
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /**
+     * SerialLink::send, as if at `now` rather than the current time.
+     */
+    pub fn send_at(&mut self, byte: u8, now: Instant) {
+        self.inner.send(byte);
+        let was_idle = self.pending.is_empty();
+        self.pending.push_back(byte);
+        if was_idle {
+            // credit accrues from now, not from when the link went idle
+            self.last_release = now;
+        }
+        self.release_due(now);
+    }
+
+    /**
+     * Release the bytes due by `now` to the sink. Called whenever the
+     * UART uses the link; a display that must drain the buffer while the
+     * guest is idle can call it too.
+     */
+    pub fn release_due(&mut self, now: Instant) {
+        if self.pending.is_empty() {
+            // idle: the next byte shows straight away, but no burst builds up
+            self.credit = 1.0;
+            self.last_release = now;
+            return;
+        }
+        if self.chars_per_second == 0 {
+            self.pending.drain(..).for_each(&mut self.sink);
+            return;
+        }
+        self.credit += now.saturating_duration_since(self.last_release).as_secs_f64() * self.chars_per_second as f64;
+        self.last_release = now;
+        while self.credit >= 1.0 {
+            match self.pending.pop_front() {
+                Some(byte) => (self.sink)(byte),
+                None => break
+            }
+            self.credit -= 1.0;
+        }
+    }
+}
+
+impl SerialLink for PacedDisplayLink {
+    fn send(&mut self, byte: u8) {
+        self.send_at(byte, Instant::now());
+    }
+    fn recv(&mut self) -> Option<u8> {
+        self.release_due(Instant::now());
+        self.inner.recv()
+    }
+    fn read_clear_to_send(&mut self) -> bool { self.inner.read_clear_to_send() }
+    fn on_tx_complete(&mut self) { self.inner.on_tx_complete() }
+    fn apply_ticks(&mut self, cycles: i32) { self.inner.apply_ticks(cycles) }
+    fn on_tx_overflow(&mut self, byte: u8) -> bool { self.inner.on_tx_overflow(byte) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+    impl SerialLink for Capture {
+        fn send(&mut self, byte: u8) { self.0.borrow_mut().push(byte); }
+        fn recv(&mut self) -> Option<u8> { None }
+        fn read_clear_to_send(&mut self) -> bool { true }
+    }
+
+    #[test]
+    fn test_paced_display() {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let shown = Rc::new(RefCell::new(vec![]));
+        let shown_ = shown.clone();
+        let mut link = PacedDisplayLink::new(Box::new(Capture(sent.clone())),
+            Box::new(move |b| shown_.borrow_mut().push(b)), 100);
+
+        // the guest sends 200 bytes at once: the link passes them all on,
+        // and the display starts with just the first
+        let start = Instant::now();
+        for i in 0..200 {
+            link.send_at(i as u8, start);
+        }
+        assert_eq!(sent.borrow().len(), 200);
+        assert_eq!(*shown.borrow(), [0]);
+
+        // 100 chars/second
+        link.release_due(start + Duration::from_millis(500));
+        assert_eq!(link.pending(), 149);
+        assert_eq!(*shown.borrow(), (0..51).collect::<Vec<u8>>());
+
+        // nothing more until the next one is due
+        link.release_due(start + Duration::from_millis(505));
+        assert_eq!(link.pending(), 149);
+
+        // unpaced: the rest at once
+        link.set_chars_per_second(0);
+        link.release_due(start + Duration::from_millis(501));
+        assert_eq!(*shown.borrow(), *sent.borrow());
+    }
+}
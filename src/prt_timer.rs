@@ -12,7 +12,7 @@ pub struct PrtTimer {
     ctl: u8,
     reload: u16,
     counter: u16,
-    step_: u16,
+    step_: u32,
     latch_counter_high: u8,
 
     //t_: std::time::Instant
@@ -21,6 +21,8 @@ pub struct PrtTimer {
 const PRT_EN: u8 = 0x1;
 const RST_EN: u8 = 0x2;
 const PRT_MODE: u8 = 0x10;
+const IRQ_EN: u8 = 0x40;
+const PRT_IRQ: u8 = 0x80;
 
 impl PrtTimer {
     pub fn new() -> Self {
@@ -34,6 +36,19 @@ impl PrtTimer {
         (self.ctl & 0xc0) == 0xc0
     }
 
+    pub fn irq_pending(&self) -> bool {
+        self.ctl & PRT_IRQ != 0
+    }
+
+    pub fn irq_enabled(&self) -> bool {
+        self.ctl & IRQ_EN != 0
+    }
+
+    // side-effect free reads, for inspection
+    pub fn ctl(&self) -> u8 { self.ctl }
+    pub fn reload(&self) -> u16 { self.reload }
+    pub fn counter(&self) -> u16 { self.counter }
+
     pub fn write_ctl(&mut self, c: u8) {
         self.ctl = c;
     }
@@ -71,16 +86,17 @@ impl PrtTimer {
         }
     }
 
-    // intended to be called per-instruction, so clock_ticks should be < 8
-    pub fn apply_ticks(&mut self, clock_ticks: u16) {
+    // usually called per-instruction, but clock_ticks may be larger when
+    // peripheral ticks are batched
+    pub fn apply_ticks(&mut self, clock_ticks: u32) {
         if self.ctl & PRT_EN == 0 { return }
         if self.ctl & RST_EN != 0 {
             self.counter = self.reload;
             self.ctl &= !RST_EN;
         }
-        let clk_div = self.clk_div();
+        let clk_div = self.clk_div() as u32;
         self.step_ = self.step_.wrapping_add(clock_ticks);
-        if self.step_ >= clk_div {
+        while self.step_ >= clk_div && self.ctl & PRT_EN != 0 {
             self.step_ -= clk_div;
             if self.counter == 0 {
                 if self.ctl & PRT_MODE != 0 {
@@ -169,4 +185,22 @@ mod tests {
         timer.write_reload_high(0xca);
         assert_eq!(timer.reload, 0xcafe);
     }
+
+    #[test]
+    fn test_batched_ticks() {
+        // continuous, clk_div 4
+        let mut fine = PrtTimer::new();
+        let mut coarse = PrtTimer::new();
+        for t in [&mut fine, &mut coarse] {
+            t.write_reload_low(10);
+            t.write_ctl(3 | 0x10);
+            t.apply_ticks(0);
+        }
+        for _ in 0..100 {
+            fine.apply_ticks(1);
+        }
+        coarse.apply_ticks(100);
+        assert_eq!(fine.counter, coarse.counter);
+        assert_eq!(fine.read_ctl(), coarse.read_ctl());
+    }
 }
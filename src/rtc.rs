@@ -0,0 +1,259 @@
+//! The eZ80F92's on-chip real-time clock, at ports 0xe0 (RTC_SEC) to
+//! 0xed (RTC_CTRL). It counts emulated time, in CPU cycles at the
+//! machine's clock speed. The alarm registers are storage only: the
+//! alarm never fires.
+use chrono::{ Datelike, Timelike };
+
+// the Gregorian calendar repeats every 400 years (weekdays included), and
+// the clock only shows years mod 10000
+const DAYS_PER_10000_YEARS: u64 = 146097 * 25;
+
+// RTC_CTRL bits
+const RTC_CTRL_BCD_EN: u8 = 0x20;
+const RTC_CTRL_UNLOCK: u8 = 0x01;
+
+// register offsets from 0xe0
+const RTC_SEC: u8 = 0;
+const RTC_MIN: u8 = 1;
+const RTC_HRS: u8 = 2;
+const RTC_DOW: u8 = 3;
+const RTC_DOM: u8 = 4;
+const RTC_MON: u8 = 5;
+const RTC_YR: u8 = 6;
+const RTC_CEN: u8 = 7;
+const RTC_CTRL: u8 = 0xd;
+
+pub struct Rtc {
+    sec: u8,
+    min: u8,
+    hrs: u8,
+    // 1-7, 1 being Sunday
+    dow: u8,
+    dom: u8,
+    mon: u8,
+    yr: u8,
+    cen: u8,
+    // RTC_ASEC..RTC_ACTRL
+    alarm: [u8; 5],
+    ctrl: u8,
+    // cycles since the last second
+    cycles: u64,
+    cycles_per_second: u64,
+}
+
+impl Rtc {
+    /// A clock set to the host's local time, counting seconds of
+    /// `cycles_per_second` CPU cycles
+    pub fn new(cycles_per_second: u64) -> Self {
+        let now = chrono::Local::now();
+        Rtc {
+            sec: now.second() as u8,
+            min: now.minute() as u8,
+            hrs: now.hour() as u8,
+            dow: now.weekday().number_from_sunday() as u8,
+            dom: now.day() as u8,
+            mon: now.month() as u8,
+            yr: (now.year() % 100) as u8,
+            cen: (now.year() / 100) as u8,
+            alarm: [0; 5],
+            ctrl: 0,
+            cycles: 0,
+            cycles_per_second: cycles_per_second.max(1),
+        }
+    }
+
+    pub fn apply_ticks(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+        while self.cycles >= self.cycles_per_second {
+            self.cycles -= self.cycles_per_second;
+            self.advance(std::time::Duration::from_secs(1));
+        }
+    }
+
+    /**
+     * Move the clock forward by `duration` (to the second), rolling over
+     * minutes, hours, days, months and years as the hardware counters do.
+     */
+    pub fn advance(&mut self, duration: std::time::Duration) {
+        let secs = duration.as_secs();
+        let time_of_day = self.hrs as u64 * 3600 + self.min as u64 * 60 + self.sec as u64 + secs % 86400;
+        let days = secs / 86400 + time_of_day / 86400;
+        let time_of_day = time_of_day % 86400;
+
+        self.hrs = (time_of_day / 3600) as u8;
+        self.min = (time_of_day / 60 % 60) as u8;
+        self.sec = (time_of_day % 60) as u8;
+        if days > 0 {
+            self.add_days(days);
+        }
+    }
+
+    fn add_days(&mut self, days: u64) {
+        let days = days % DAYS_PER_10000_YEARS;
+        // the guest may have set out of range values while unlocked
+        let year = (self.cen % 100) as u32 * 100 + (self.yr % 100) as u32;
+        let mon = self.mon.clamp(1, 12);
+        let dom = self.dom.clamp(1, days_in_month(year, mon));
+        let date = chrono::NaiveDate::from_ymd_opt(year as i32, mon as u32, dom as u32)
+            .and_then(|d| d.checked_add_days(chrono::Days::new(days)))
+            .expect("in chrono's range");
+
+        self.dow = ((self.dow.clamp(1, 7) - 1) as u64 + days % 7) as u8 % 7 + 1;
+        self.dom = date.day() as u8;
+        self.mon = date.month() as u8;
+        self.yr = (date.year() % 100) as u8;
+        self.cen = (date.year() / 100 % 100) as u8;
+    }
+
+    fn time_register(&mut self, reg: u8) -> Option<&mut u8> {
+        match reg {
+            RTC_SEC => Some(&mut self.sec),
+            RTC_MIN => Some(&mut self.min),
+            RTC_HRS => Some(&mut self.hrs),
+            RTC_DOW => Some(&mut self.dow),
+            RTC_DOM => Some(&mut self.dom),
+            RTC_MON => Some(&mut self.mon),
+            RTC_YR => Some(&mut self.yr),
+            RTC_CEN => Some(&mut self.cen),
+            _ => None
+        }
+    }
+
+    /// Read the register at `reg` (offset from 0xe0)
+    pub fn read(&mut self, reg: u8) -> u8 {
+        let bcd = self.ctrl & RTC_CTRL_BCD_EN != 0;
+        match reg {
+            RTC_CTRL => self.ctrl,
+            8..=0xc => self.alarm[reg as usize - 8],
+            _ => match self.time_register(reg) {
+                Some(v) if bcd => (*v / 10) << 4 | (*v % 10),
+                Some(v) => *v,
+                None => 0
+            }
+        }
+    }
+
+    /// Write the register at `reg` (offset from 0xe0). The time registers
+    /// can only be written while RTC_CTRL has RTC_UNLOCK set.
+    pub fn write(&mut self, reg: u8, value: u8) {
+        let bcd = self.ctrl & RTC_CTRL_BCD_EN != 0;
+        let unlocked = self.ctrl & RTC_CTRL_UNLOCK != 0;
+        match reg {
+            RTC_CTRL => {
+                // ALARM is read-only
+                self.ctrl = value & 0x7f;
+                // writing the time restarts the current second
+                if value & RTC_CTRL_UNLOCK != 0 { self.cycles = 0; }
+            }
+            8..=0xc => self.alarm[reg as usize - 8] = value,
+            _ => if unlocked {
+                if let Some(v) = self.time_register(reg) {
+                    *v = if bcd { (value >> 4) * 10 + (value & 0xf) } else { value };
+                }
+            }
+        }
+    }
+}
+
+fn days_in_month(year: u32, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 => if chrono::NaiveDate::from_ymd_opt(year as i32, 2, 29).is_some() { 29 } else { 28 },
+        _ => 31
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CYCLES_PER_SECOND: u64 = 18_432_000;
+
+    fn set_time(rtc: &mut Rtc, year: u32, mon: u8, dom: u8, hrs: u8, min: u8, sec: u8) {
+        rtc.write(RTC_CTRL, RTC_CTRL_UNLOCK);
+        for (reg, v) in [(RTC_CEN, (year / 100) as u8), (RTC_YR, (year % 100) as u8), (RTC_MON, mon),
+                         (RTC_DOM, dom), (RTC_HRS, hrs), (RTC_MIN, min), (RTC_SEC, sec)] {
+            rtc.write(reg, v);
+        }
+        rtc.write(RTC_CTRL, 0);
+    }
+
+    fn get_time(rtc: &mut Rtc) -> (u32, u8, u8, u8, u8, u8) {
+        (rtc.read(RTC_CEN) as u32 * 100 + rtc.read(RTC_YR) as u32, rtc.read(RTC_MON), rtc.read(RTC_DOM),
+         rtc.read(RTC_HRS), rtc.read(RTC_MIN), rtc.read(RTC_SEC))
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut rtc = Rtc::new(CYCLES_PER_SECOND);
+        let day = std::time::Duration::from_secs(86400);
+
+        // end of month, and of a non-leap February
+        set_time(&mut rtc, 2023, 1, 31, 12, 0, 0);
+        rtc.advance(day);
+        assert_eq!(get_time(&mut rtc), (2023, 2, 1, 12, 0, 0));
+        rtc.advance(day * 28);
+        assert_eq!(get_time(&mut rtc), (2023, 3, 1, 12, 0, 0));
+
+        // into a leap day, by seconds carrying all the way up
+        set_time(&mut rtc, 2024, 2, 28, 23, 59, 30);
+        rtc.advance(std::time::Duration::from_secs(45));
+        assert_eq!(get_time(&mut rtc), (2024, 2, 29, 0, 0, 15));
+        rtc.advance(day);
+        assert_eq!(get_time(&mut rtc), (2024, 3, 1, 0, 0, 15));
+
+        // new year, new century (2100 isn't a leap year)
+        set_time(&mut rtc, 2099, 12, 31, 23, 0, 0);
+        rtc.advance(std::time::Duration::from_secs(3600) + day * 59);
+        assert_eq!(get_time(&mut rtc), (2100, 3, 1, 0, 0, 0));
+
+        // 10000 years on shows the same date, and huge durations return
+        set_time(&mut rtc, 2024, 2, 29, 12, 0, 0);
+        rtc.advance(day * DAYS_PER_10000_YEARS as u32 + std::time::Duration::from_secs(3600));
+        assert_eq!(get_time(&mut rtc), (2024, 2, 29, 13, 0, 0));
+        rtc.advance(std::time::Duration::from_secs(u64::MAX));
+        assert!(rtc.read(RTC_MON) <= 12);
+    }
+
+    #[test]
+    fn test_out_of_range_date() {
+        let mut rtc = Rtc::new(CYCLES_PER_SECOND);
+        // as the guest may write them while unlocked
+        set_time(&mut rtc, 2024, 0xff, 0xff, 23, 59, 59);
+        rtc.write(RTC_CTRL, RTC_CTRL_UNLOCK);
+        rtc.write(RTC_DOW, 0xff);
+        rtc.write(RTC_CTRL, 0);
+        rtc.advance(std::time::Duration::from_secs(1));
+        // clamped to 31st December, then the next day
+        assert_eq!(get_time(&mut rtc), (2025, 1, 1, 0, 0, 0));
+        assert_eq!(rtc.read(RTC_DOW), 1);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut rtc = Rtc::new(CYCLES_PER_SECOND);
+        set_time(&mut rtc, 2024, 12, 25, 9, 30, 0);
+
+        // locked
+        rtc.write(RTC_MIN, 45);
+        assert_eq!(rtc.read(RTC_MIN), 30);
+
+        rtc.write(RTC_CTRL, RTC_CTRL_BCD_EN);
+        assert_eq!(rtc.read(RTC_DOM), 0x25);
+        assert_eq!(rtc.read(RTC_CEN), 0x20);
+
+        // a second of CPU cycles
+        rtc.apply_ticks(CYCLES_PER_SECOND as u32 - 1);
+        assert_eq!(rtc.read(RTC_SEC), 0);
+        rtc.apply_ticks(1);
+        assert_eq!(rtc.read(RTC_SEC), 1);
+
+        // at another clock speed
+        let mut rtc = Rtc::new(1000);
+        rtc.write(RTC_CTRL, RTC_CTRL_UNLOCK);
+        rtc.write(RTC_SEC, 0);
+        rtc.write(RTC_CTRL, 0);
+        rtc.apply_ticks(2000);
+        assert_eq!(rtc.read(RTC_SEC), 2);
+    }
+}
@@ -0,0 +1,167 @@
+//! Recording and replay of the bytes received by a UART, stamped with the
+//! emulated cycle at which they were delivered. Useful for reproducible
+//! bug reports.
+//!
+//! Script format is one entry per line: `<cycle> <byte>`, with the
+//! cycle count in decimal and the byte in hex, eg:
+//!
+//! ```text
+//! # cycle byte
+//! 120000 81
+//! 120040 04
+//! ```
+//!
+//! Blank lines and lines starting with '#' are ignored.
+
+use crate::uart::SerialLink;
+use std::sync::{ Arc, Mutex };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptEntry {
+    pub cycle: u64,
+    pub byte: u8,
+}
+
+pub fn parse_script(script: &str) -> Result<Vec<ScriptEntry>, String> {
+    let mut entries = vec![];
+
+    for (line_no, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+
+        let mut words = line.split_whitespace();
+        let parsed = match (words.next(), words.next(), words.next()) {
+            (Some(cycle), Some(byte), None) => {
+                cycle.parse::<u64>().ok().zip(u8::from_str_radix(byte, 16).ok())
+            }
+            _ => None
+        };
+        match parsed {
+            Some((cycle, byte)) => entries.push(ScriptEntry { cycle, byte }),
+            None => return Err(format!("failed to parse serial script line {}: {}", line_no + 1, line))
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn format_script(entries: &[ScriptEntry]) -> String {
+    let mut script = String::from("# cycle byte\n");
+    for e in entries {
+        script.push_str(&format!("{} {:02x}\n", e.cycle, e.byte));
+    }
+    script
+}
+
+/// Delivers each scripted byte once the emulated cycle count reaches its
+/// timestamp. Transmitted bytes are discarded.
+pub struct ScriptedSerialLink {
+    entries: Vec<ScriptEntry>,
+    next: usize,
+    cycles: u64,
+}
+
+impl ScriptedSerialLink {
+    pub fn new(entries: Vec<ScriptEntry>) -> Self {
+        ScriptedSerialLink { entries, next: 0, cycles: 0 }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.entries.len()
+    }
+}
+
+impl SerialLink for ScriptedSerialLink {
+    fn send(&mut self, _byte: u8) {}
+    fn recv(&mut self) -> Option<u8> {
+        match self.entries.get(self.next) {
+            Some(e) if e.cycle <= self.cycles => {
+                self.next += 1;
+                Some(e.byte)
+            }
+            _ => None
+        }
+    }
+    fn read_clear_to_send(&mut self) -> bool { true }
+    fn apply_ticks(&mut self, cycles: i32) {
+        self.cycles += cycles as u64;
+    }
+}
+
+/// Wraps another link, recording every byte it yields to the UART along
+/// with the cycle at which it was received.
+pub struct RecordingSerialLink {
+    inner: Box<dyn SerialLink>,
+    cycles: u64,
+    recording: Arc<Mutex<Vec<ScriptEntry>>>,
+}
+
+impl RecordingSerialLink {
+    pub fn new(inner: Box<dyn SerialLink>) -> Self {
+        RecordingSerialLink { inner, cycles: 0, recording: Arc::new(Mutex::new(vec![])) }
+    }
+
+    /// Shared handle to the recorded entries, which remains usable once the
+    /// link has been handed to the emulator.
+    pub fn recording(&self) -> Arc<Mutex<Vec<ScriptEntry>>> {
+        self.recording.clone()
+    }
+}
+
+impl SerialLink for RecordingSerialLink {
+    fn send(&mut self, byte: u8) { self.inner.send(byte) }
+    fn recv(&mut self) -> Option<u8> {
+        let byte = self.inner.recv();
+        if let Some(byte) = byte {
+            self.recording.lock().unwrap().push(ScriptEntry { cycle: self.cycles, byte });
+        }
+        byte
+    }
+    fn read_clear_to_send(&mut self) -> bool { self.inner.read_clear_to_send() }
+    fn on_tx_complete(&mut self) { self.inner.on_tx_complete() }
+    fn apply_ticks(&mut self, cycles: i32) {
+        self.cycles += cycles as u64;
+        self.inner.apply_ticks(cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uart::Uart;
+
+    // poll the uart every 10 cycles, as a guest might
+    fn run_session(link: Box<dyn SerialLink>) -> Vec<(u64, u8)> {
+        let mut uart = Uart::new(link);
+        let mut received = vec![];
+        for t in 0..100 {
+            if uart.read_lsr() & 1 != 0 {
+                received.push((t * 10, uart.receive_byte()));
+            }
+            uart.apply_ticks(10);
+        }
+        received
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let entries = parse_script("# comment\n\n5 41\n  17 ff \n").unwrap();
+        assert_eq!(entries, vec![ScriptEntry { cycle: 5, byte: 0x41 }, ScriptEntry { cycle: 17, byte: 0xff }]);
+        assert_eq!(parse_script(&format_script(&entries)).unwrap(), entries);
+        assert!(parse_script("5 41 x").is_err());
+        assert!(parse_script("5 zz").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let live = parse_script("5 41\n17 42\n18 43\n400 44\n").unwrap();
+        let recorder = RecordingSerialLink::new(Box::new(ScriptedSerialLink::new(live)));
+        let recording = recorder.recording();
+        let live_session = run_session(Box::new(recorder));
+        assert_eq!(live_session, vec![(10, 0x41), (20, 0x42), (30, 0x43), (400, 0x44)]);
+
+        let script = format_script(&recording.lock().unwrap());
+        let replayed_session = run_session(Box::new(ScriptedSerialLink::new(parse_script(&script).unwrap())));
+        assert_eq!(replayed_session, live_session);
+    }
+}
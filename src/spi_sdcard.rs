@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::{ Seek, SeekFrom, Read, Write };
 
 pub struct SpiSdcard {
@@ -8,6 +9,10 @@ pub struct SpiSdcard {
     image: Option<std::fs::File>,
     next_write_sector: Option<usize>,
     next_write_started: bool,
+    // sectors written while the overlay is enabled, instead of the image
+    overlay: Option<HashMap<usize, [u8; 512]>>,
+    // writes are rejected, as by a locked card
+    write_protect: bool,
 }
 
 impl SpiSdcard {
@@ -20,6 +25,8 @@ impl SpiSdcard {
             image: None,
             next_write_sector: None,
             next_write_started: false,
+            overlay: None,
+            write_protect: false,
         }
     }
 
@@ -27,6 +34,23 @@ impl SpiSdcard {
         self.image = file;
     }
 
+    /**
+     * Keep writes in memory, reading back written sectors from there and
+     * the rest from the image, which is left unchanged. Disabling discards
+     * the writes.
+     */
+    pub fn set_overlay(&mut self, enable: bool) {
+        self.overlay = if enable { Some(HashMap::new()) } else { None };
+    }
+
+    /**
+     * Reject block writes, as a locked card does: the data is answered
+     * with a write error token and the image is left unchanged.
+     */
+    pub fn set_write_protect(&mut self, write_protect: bool) {
+        self.write_protect = write_protect;
+    }
+
     pub fn recv_byte(&mut self, val: u8) {
         if let Some(image) = self.image.as_mut() {
             // 0x80 means transfer finished (immediate on the emulator)
@@ -48,13 +72,23 @@ impl SpiSdcard {
                     self.next_write_started = false;
                     self.next_write_sector = None;
                     //println!("WRITE!!!!!!!!! sector {}, {:?}", sector, self.in_buf);
-                    match image.seek(SeekFrom::Start(sector as u64 * 512))
-                        .and_then(|_| image.write_all(&self.in_buf)) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            eprintln!("Error reading SDcard image: {:?}", e);
-                        }
-                    };
+                    if self.write_protect {
+                        self.in_buf.clear();
+                        // data response: write error
+                        self.out_buf.push(0xd);
+                        self.out_buf.push(1);
+                        return;
+                    } else if let Some(overlay) = self.overlay.as_mut() {
+                        overlay.insert(sector, self.in_buf[..].try_into().unwrap());
+                    } else {
+                        match image.seek(SeekFrom::Start(sector as u64 * 512))
+                            .and_then(|_| image.write_all(&self.in_buf)) {
+                            Ok(_) => {},
+                            Err(e) => {
+                                eprintln!("Error reading SDcard image: {:?}", e);
+                            }
+                        };
+                    }
 
                     self.in_buf.clear();
                     self.out_buf.push(5);
@@ -113,13 +147,17 @@ impl SpiSdcard {
                         self.out_buf.push(0);
                         self.out_buf.push(0xfe);
                         let mut buf = [0u8; 512];
-                        match image.seek(SeekFrom::Start(sector as u64 * 512))
-                            .and_then(|_| image.read_exact(&mut buf)) {
-                            Ok(_) => {},
-                            Err(e) => {
-                                eprintln!("Error reading SDcard image: {:?}", e);
-                            }
-                        };
+                        if let Some(written) = self.overlay.as_ref().and_then(|o| o.get(&sector)) {
+                            buf = *written;
+                        } else {
+                            match image.seek(SeekFrom::Start(sector as u64 * 512))
+                                .and_then(|_| image.read_exact(&mut buf)) {
+                                Ok(_) => {},
+                                Err(e) => {
+                                    eprintln!("Error reading SDcard image: {:?}", e);
+                                }
+                            };
+                        }
                         self.out_buf.extend_from_slice(&buf);
                         // 2 crc bytes that mos ignores
                         self.out_buf.push(0);
@@ -162,3 +200,65 @@ impl SpiSdcard {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(sdcard: &mut SpiSdcard, cmd: u8, arg: u32) {
+        for b in [cmd | 0x40, (arg >> 24) as u8, (arg >> 16) as u8, (arg >> 8) as u8, arg as u8, 0x01] {
+            sdcard.recv_byte(b);
+        }
+    }
+
+    fn read_sector(sdcard: &mut SpiSdcard, sector: u32) -> Vec<u8> {
+        command(sdcard, 17, sector);
+        let data: Vec<u8> = std::iter::from_fn(|| sdcard.send_byte()).collect();
+        assert_eq!(data[..2], [0, 0xfe]);
+        data[2..514].to_vec()
+    }
+
+    #[test]
+    fn test_overlay() {
+        let path = std::env::temp_dir().join(format!("agon-cpu-emulator-{}-overlay.img", std::process::id()));
+        std::fs::write(&path, [0xaa; 1024]).unwrap();
+        let mut sdcard = SpiSdcard::new();
+        sdcard.set_image_file(Some(std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap()));
+        sdcard.set_overlay(true);
+
+        command(&mut sdcard, 24, 1);
+        sdcard.recv_byte(254);
+        for i in 0..512 {
+            sdcard.recv_byte(i as u8);
+        }
+        assert_eq!(std::iter::from_fn(|| sdcard.send_byte()).collect::<Vec<u8>>(), [0, 5, 1]);
+
+        assert_eq!(read_sector(&mut sdcard, 1), (0..512).map(|i| i as u8).collect::<Vec<u8>>());
+        assert_eq!(read_sector(&mut sdcard, 0), [0xaa; 512]);
+        assert_eq!(std::fs::read(&path).unwrap(), [0xaa; 1024]);
+
+        // discarded
+        sdcard.set_overlay(false);
+        assert_eq!(read_sector(&mut sdcard, 1), [0xaa; 512]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_protect() {
+        let path = std::env::temp_dir().join(format!("agon-cpu-emulator-{}-write-protect.img", std::process::id()));
+        std::fs::write(&path, [0xaa; 1024]).unwrap();
+        let mut sdcard = SpiSdcard::new();
+        sdcard.set_image_file(Some(std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap()));
+        sdcard.set_write_protect(true);
+
+        command(&mut sdcard, 24, 1);
+        sdcard.recv_byte(254);
+        for _ in 0..512 {
+            sdcard.recv_byte(0);
+        }
+        assert_eq!(std::iter::from_fn(|| sdcard.send_byte()).collect::<Vec<u8>>(), [0, 0xd, 1]);
+        assert_eq!(read_sector(&mut sdcard, 1), [0xaa; 512]);
+        assert_eq!(std::fs::read(&path).unwrap(), [0xaa; 1024]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
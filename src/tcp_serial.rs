@@ -0,0 +1,116 @@
+//! A serial link to a TCP socket, so a UART can be used from telnet or
+//! similar on another machine.
+
+use std::io::{ Read, Write };
+use std::net::{ TcpListener, TcpStream, SocketAddr };
+use crate::uart::SerialLink;
+
+/// Listens on a TCP port, and connects the first client to accept to the
+/// UART. While no client is connected the UART reads no data and what it
+/// sends is dropped. When the client disconnects, the next one is accepted.
+pub struct TcpSerialLink {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+}
+
+impl TcpSerialLink {
+    pub fn bind(addr: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpSerialLink { listener, stream: None })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn connection(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    let _ = stream.set_nodelay(true);
+                    self.stream = Some(stream);
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+}
+
+impl SerialLink for TcpSerialLink {
+    fn send(&mut self, byte: u8) {
+        if let Some(stream) = self.connection() {
+            match stream.write(&[byte]) {
+                Ok(_) => {}
+                // client not keeping up: drop the byte
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(_) => self.stream = None,
+            }
+        }
+    }
+
+    fn recv(&mut self) -> Option<u8> {
+        let stream = self.connection()?;
+        let mut buf = [0u8; 1];
+        match stream.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => None,
+            // EOF, or the connection failed
+            _ => {
+                self.stream = None;
+                None
+            }
+        }
+    }
+
+    fn read_clear_to_send(&mut self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uart::Uart;
+
+    fn receive(uart: &mut Uart) -> u8 {
+        for _ in 0..1000 {
+            if uart.maybe_fill_rx_buf().is_some() {
+                return uart.receive_byte();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        panic!("nothing received");
+    }
+
+    #[test]
+    fn test_tcp_serial_link() {
+        let link = TcpSerialLink::bind("127.0.0.1:0").unwrap();
+        let addr = link.local_addr().unwrap();
+        let mut uart = Uart::new(Box::new(link));
+
+        // nobody connected: nothing to read, and sending is harmless
+        assert_eq!(uart.maybe_fill_rx_buf(), None);
+        uart.send_byte(b'?');
+        uart.apply_ticks(10_000);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"hi").unwrap();
+        assert_eq!(receive(&mut uart), b'h');
+        assert_eq!(receive(&mut uart), b'i');
+
+        uart.send_byte(b'!');
+        uart.apply_ticks(10_000);
+        let mut buf = [0u8; 1];
+        client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"!");
+
+        // disconnect, then a new client gets the console
+        drop(client);
+        for _ in 0..10 {
+            assert_eq!(uart.maybe_fill_rx_buf(), None);
+        }
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"x").unwrap();
+        assert_eq!(receive(&mut uart), b'x');
+    }
+}
@@ -0,0 +1,261 @@
+//! A tiny ez80 assembler for writing test programs readably. ADL mode
+//! only (immediates and addresses are 24-bit), and only the common
+//! instructions: extend it as tests need more.
+//!
+//!     let code = test_asm::assemble("
+//!         ld a, 5
+//!     loop:
+//!         djnz loop
+//!         ret", 0x40000).unwrap();
+use std::collections::HashMap;
+
+const REGS8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "(hl)", "a"];
+const REGS16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const REGS16_PUSH: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 8] = ["nz", "z", "nc", "c", "po", "pe", "p", "m"];
+
+/**
+ * Assemble `source` to machine code to be loaded at `origin`. One
+ * instruction, label (`name:`) or `db` directive per line; `;` starts
+ * a comment. Numbers are decimal, or hex with a `0x` or `$` prefix.
+ */
+pub fn assemble(source: &str, origin: u32) -> Result<Vec<u8>, String> {
+    // first pass finds label addresses, with forward references as 0
+    let mut labels = HashMap::new();
+    assemble_pass(source, origin, &mut labels, false)?;
+    assemble_pass(source, origin, &mut labels, true)
+}
+
+fn assemble_pass(source: &str, origin: u32, labels: &mut HashMap<String, u32>, last_pass: bool) -> Result<Vec<u8>, String> {
+    let mut code = vec![];
+    for (line_no, line) in source.lines().enumerate() {
+        let mut line = line.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            if !label.contains(char::is_whitespace) && !label.is_empty() {
+                if !last_pass { labels.insert(label.to_ascii_lowercase(), origin + code.len() as u32); }
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() { continue }
+
+        let pc = origin + code.len() as u32;
+        let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+            Some((m, ops)) => (m.to_ascii_lowercase(), ops.split(',').map(|o| o.trim().to_string()).collect()),
+            None => (line.to_ascii_lowercase(), vec![])
+        };
+        let asm = Asm { labels, last_pass };
+        let bytes = asm.instruction(&mnemonic, &operands, pc)
+            .map_err(|e| format!("line {}: {}: '{}'", line_no + 1, e, line))?;
+        code.extend(bytes);
+    }
+    Ok(code)
+}
+
+struct Asm<'a> {
+    labels: &'a HashMap<String, u32>,
+    last_pass: bool,
+}
+
+fn index_of(names: &[&str], operand: &str) -> Option<u8> {
+    let operand = operand.to_ascii_lowercase().replace(' ', "");
+    names.iter().position(|n| *n == operand).map(|i| i as u8)
+}
+
+fn imm24(v: u32) -> [u8; 3] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8]
+}
+
+impl Asm<'_> {
+    fn value(&self, operand: &str) -> Result<u32, String> {
+        let operand = operand.trim();
+        let parsed = if let Some(hex) = operand.strip_prefix("0x").or(operand.strip_prefix('$')) {
+            u32::from_str_radix(hex, 16).ok()
+        } else {
+            operand.parse::<i32>().ok().map(|v| v as u32)
+        };
+        match parsed {
+            Some(v) => Ok(v),
+            None => match self.labels.get(&operand.to_ascii_lowercase()) {
+                Some(addr) => Ok(*addr),
+                None if !self.last_pass => Ok(0),
+                None => Err(format!("unknown label '{}'", operand))
+            }
+        }
+    }
+
+    fn byte(&self, operand: &str) -> Result<u8, String> {
+        Ok(self.value(operand)? as u8)
+    }
+
+    // the value inside (...), for memory and port operands
+    fn indirect(&self, operand: &str) -> Option<Result<u32, String>> {
+        let inner = operand.strip_prefix('(')?.strip_suffix(')')?;
+        Some(self.value(inner))
+    }
+
+    fn relative(&self, operand: &str, pc: u32) -> Result<u8, String> {
+        let offset = self.value(operand)? as i64 - (pc as i64 + 2);
+        if self.last_pass && !(-128..=127).contains(&offset) {
+            return Err("relative jump out of range".to_string());
+        }
+        Ok(offset as u8)
+    }
+
+    fn instruction(&self, mnemonic: &str, ops: &[String], pc: u32) -> Result<Vec<u8>, String> {
+        let op = |i: usize| ops.get(i).map(|s| s.as_str()).unwrap_or("");
+        let r8 = |i: usize| index_of(&REGS8, op(i));
+        let r16 = |i: usize| index_of(&REGS16, op(i));
+        let cond = |i: usize| index_of(&CONDITIONS, op(i));
+
+        let bytes = match (mnemonic, ops.len()) {
+            ("nop", 0) => vec![0x00],
+            ("halt", 0) => vec![0x76],
+            ("di", 0) => vec![0xf3],
+            ("ei", 0) => vec![0xfb],
+            ("exx", 0) => vec![0xd9],
+            ("ret", 0) => vec![0xc9],
+            ("ret", 1) => vec![0xc0 + cond(0).ok_or("bad condition")? * 8],
+            ("rst", 1) => vec![0xc7 + (self.byte(op(0))? & 0x38)],
+            ("db", _) => {
+                let mut bytes = vec![];
+                for o in ops {
+                    match o.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        Some(s) => bytes.extend(s.bytes()),
+                        None => bytes.push(self.byte(o)?)
+                    }
+                }
+                bytes
+            }
+            ("ld", 2) => match (r8(0), r8(1), r16(0)) {
+                (Some(6), Some(6), _) => return Err("no ld (hl),(hl)".to_string()),
+                (Some(d), Some(s), _) => vec![0x40 + d * 8 + s],
+                (Some(7), None, _) if op(1).starts_with('(') => {
+                    let mut v = vec![0x3a];
+                    v.extend(imm24(self.indirect(op(1)).unwrap()?));
+                    v
+                }
+                (Some(d), None, _) => vec![0x06 + d * 8, self.byte(op(1))?],
+                (None, Some(7), _) if op(0).starts_with('(') => {
+                    let mut v = vec![0x32];
+                    v.extend(imm24(self.indirect(op(0)).ok_or("bad operand")??));
+                    v
+                }
+                (None, None, Some(rr)) => {
+                    let mut v = vec![0x01 + rr * 16];
+                    v.extend(imm24(self.value(op(1))?));
+                    v
+                }
+                _ => return Err("bad operands".to_string())
+            }
+            ("inc", 1) | ("dec", 1) => {
+                let dec = (mnemonic == "dec") as u8;
+                match (r8(0), r16(0)) {
+                    (Some(r), _) => vec![0x04 + r * 8 + dec],
+                    (_, Some(rr)) => vec![0x03 + rr * 16 + dec * 8],
+                    _ => return Err("bad operand".to_string())
+                }
+            }
+            ("add", 2) if op(0).eq_ignore_ascii_case("hl") => vec![0x09 + r16(1).ok_or("bad operand")? * 16],
+            ("add" | "adc" | "sbc", 2) if op(0).eq_ignore_ascii_case("a") => {
+                return self.instruction(&format!("{}_a", mnemonic), &ops[1..], pc);
+            }
+            ("add_a" | "adc_a" | "sub" | "sbc_a" | "and" | "xor" | "or" | "cp", 1) => {
+                let alu = ["add_a", "adc_a", "sub", "sbc_a", "and", "xor", "or", "cp"]
+                    .iter().position(|m| *m == mnemonic).unwrap() as u8;
+                match r8(0) {
+                    Some(r) => vec![0x80 + alu * 8 + r],
+                    None => vec![0xc6 + alu * 8, self.byte(op(0))?]
+                }
+            }
+            ("push", 1) | ("pop", 1) => {
+                let qq = index_of(&REGS16_PUSH, op(0)).ok_or("bad operand")?;
+                vec![if mnemonic == "push" { 0xc5 } else { 0xc1 } + qq * 16]
+            }
+            // into ADL mode from Z80 mode, as after reset
+            ("jp.lil", 1) => {
+                let mut v = vec![0x5b, 0xc3];
+                v.extend(imm24(self.value(op(0))?));
+                v
+            }
+            ("jp", 1) if op(0).eq_ignore_ascii_case("(hl)") => vec![0xe9],
+            ("jp" | "call", 1) => {
+                let mut v = vec![if mnemonic == "jp" { 0xc3 } else { 0xcd }];
+                v.extend(imm24(self.value(op(0))?));
+                v
+            }
+            ("jp" | "call", 2) => {
+                let cc = cond(0).ok_or("bad condition")?;
+                let mut v = vec![if mnemonic == "jp" { 0xc2 } else { 0xc4 } + cc * 8];
+                v.extend(imm24(self.value(op(1))?));
+                v
+            }
+            ("jr", 1) => vec![0x18, self.relative(op(0), pc)?],
+            ("jr", 2) => match cond(0) {
+                Some(cc) if cc < 4 => vec![0x20 + cc * 8, self.relative(op(1), pc)?],
+                _ => return Err("bad condition".to_string())
+            }
+            ("djnz", 1) => vec![0x10, self.relative(op(0), pc)?],
+            ("out0", 2) => {
+                let port = self.indirect(op(0)).ok_or("bad port")??;
+                vec![0xed, 0x01 + r8(1).filter(|r| *r != 6).ok_or("bad operand")? * 8, port as u8]
+            }
+            ("in0", 2) => {
+                let port = self.indirect(op(1)).ok_or("bad port")??;
+                vec![0xed, r8(0).filter(|r| *r != 6).ok_or("bad operand")? * 8, port as u8]
+            }
+            _ => return Err("unknown instruction".to_string())
+        };
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RegisterSetup;
+    use crate::agon_machine::tests::{ test_machine, test_sdcard_dir };
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble("
+            start:  ld a, 0x41      ; comment
+                    ld hl, $40000
+                    ld (hl), a
+                    out0 ($a0), a
+                    in0 b, (0x9a)
+                    jr nz, start
+                    call done
+                    push af
+            done:   ret z
+                    db 1, \"hi\"
+                    jp.lil start", 0x40000),
+            Ok(vec![0x3e, 0x41, 0x21, 0x00, 0x00, 0x04, 0x77, 0xed, 0x39, 0xa0, 0xed, 0x00, 0x9a,
+                    0x20, 0xf1, 0xcd, 0x14, 0x00, 0x04, 0xf5, 0xc8, 1, b'h', b'i', 0x5b, 0xc3, 0x00, 0x00, 0x04]));
+
+        assert!(assemble("jp nowhere", 0).unwrap_err().contains("unknown label"));
+        assert!(assemble("ld (hl), (hl)", 0).is_err());
+    }
+
+    #[test]
+    fn test_assemble_and_run() {
+        let mut machine = test_machine();
+        let code = assemble("
+                ld a, 0x40
+                jr skip
+                inc a       ; skipped
+            skip:
+                inc a
+                add hl, de
+                ret", 0x40000).unwrap();
+        let dir = test_sdcard_dir("assemble_and_run");
+        std::fs::write(dir.join("test.bin"), code).unwrap();
+        machine.load_binary(&dir.join("test.bin"), 0x40000).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut cpu = ez80::Cpu::new_ez80();
+        let regs = machine.call_function(&mut cpu, 0x40000, RegisterSetup {
+            hl: 0x1000, de: 0x234, sp: 0x50000, ..Default::default()
+        }, 100).unwrap();
+        assert_eq!((regs.a, regs.hl), (0x41, 0x1234));
+    }
+}
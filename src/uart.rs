@@ -1,17 +1,67 @@
+use std::io::Write;
+
 const FCTL_FIFOEN: u8 = 0x1;
 
 pub trait SerialLink {
     fn send(&mut self, byte: u8);
     fn recv(&mut self) -> Option<u8>;
     fn read_clear_to_send(&mut self) -> bool;
+    /// Called when the last byte has left the transmitter, ie. the
+    /// transition to TEMT (rather than just THRE, which only says the fifo
+    /// is empty). Useful for half-duplex links that must turn the line around.
+    fn on_tx_complete(&mut self) {}
+    /// Called with the cycles elapsed each time the UART is ticked, for
+    /// links that need to know emulated time.
+    fn apply_ticks(&mut self, _cycles: i32) {}
+    /// Called when the guest writes to a full transmit fifo. By default the
+    /// byte is dropped, as on real hardware, and counted. A link that would
+    /// rather apply back-pressure (eg: signalling the host to slow the
+    /// emulator) returns true, and the byte is queued beyond the fifo's
+    /// hardware depth, so nothing is lost or reordered.
+    fn on_tx_overflow(&mut self, _byte: u8) -> bool { false }
+}
+
+/// What the guest reads from RBR when no data has been received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyRxRead {
+    #[default]
+    Zero,
+    /// the last byte received, as the RBR still holds it
+    LastByte,
+    Value(u8),
+}
+
+/// Errors to report for a received byte, as though its frame was malformed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameErrors {
+    pub overrun: bool,
+    pub parity: bool,
+    pub framing: bool,
+    pub break_interrupt: bool,
+}
+
+impl FrameErrors {
+    // as LSR bits
+    fn lsr_bits(&self) -> u8 {
+        (self.overrun as u8) << 1 | (self.parity as u8) << 2 |
+        (self.framing as u8) << 3 | (self.break_interrupt as u8) << 4
+    }
 }
 
 pub struct Uart {
     link: Box<dyn SerialLink>,
     rx_buf: Option<u8>,
+    last_rx: u8,
+    empty_rx_read: EmptyRxRead,
+    // LSR error bits for the next byte received, and those latched until
+    // LSR is read
+    injected_errors: Option<u8>,
+    line_errors: u8,
 
     /* number of clock cycles before a byte can be sent again */
     transmit_cooldown: i32,
+    // cap on the cycles a byte takes to send, whatever brg_div says
+    max_transmit_cooldown: i32,
 
     // interrupt enable register
     pub ier: u8,
@@ -25,46 +75,182 @@ pub struct Uart {
     pub spr: u8,
 
     tx_fifo: Vec<u8>,
+    // bytes lost to writes while the transmit fifo was full
+    tx_dropped: u64,
+    // bytes the link's send() panicked on
+    tx_send_failures: u64,
+    // host file mirroring everything transmitted
+    tx_log: Option<std::io::BufWriter<std::fs::File>>,
+    // bytes transmitted since the last take_tx_captured, while capturing
+    tx_captured: Option<Vec<u8>>,
 }
 
+/// Cycles to send a byte at 300 baud, the slowest standard rate
+pub const DEFAULT_MAX_TRANSMIT_COOLDOWN: i32 = 3840 * 16 * 9;
+
 impl Uart {
     pub fn new(link: Box<dyn SerialLink>) -> Self {
         Uart {
             link,
             transmit_cooldown: 0,
+            max_transmit_cooldown: DEFAULT_MAX_TRANSMIT_COOLDOWN,
             tx_fifo: vec![],
-            ier: 0, fctl: 0, lctl: 0, brg_div: 2, spr: 0, rx_buf: None
+            tx_dropped: 0,
+            tx_send_failures: 0,
+            tx_log: None,
+            tx_captured: None,
+            ier: 0, fctl: 0, lctl: 0, brg_div: 2, spr: 0, rx_buf: None,
+            last_rx: 0, empty_rx_read: EmptyRxRead::default(), injected_errors: None, line_errors: 0
         }
     }
 
+    /**
+     * Return the registers to their power-on values, discarding data in
+     * flight. The link, and the emulator's settings, are kept.
+     */
+    pub fn reset(&mut self) {
+        self.flush_tx_log();
+        self.ier = 0;
+        self.fctl = 0;
+        self.lctl = 0;
+        self.brg_div = 2;
+        self.spr = 0;
+        self.rx_buf = None;
+        self.injected_errors = None;
+        self.line_errors = 0;
+        self.tx_fifo.clear();
+        self.transmit_cooldown = 0;
+    }
+
+    /**
+     * Connect a different serial link, returning the old one.
+     */
+    pub fn replace_link(&mut self, link: Box<dyn SerialLink>) -> Box<dyn SerialLink> {
+        std::mem::replace(&mut self.link, link)
+    }
+
     pub fn apply_ticks(&mut self, cycles: i32) {
-        self.transmit_cooldown = i32::max(0, self.transmit_cooldown - cycles);
-        if self.transmit_cooldown == 0 {
-            if !self.tx_fifo.is_empty() {
-                let val = self.tx_fifo.remove(0);
-                // actually send
-                self.link.send(val);
-                self.transmit_cooldown += self.brg_div as i32 * 16 * 9; /* XXX 9 = 8bits data, 1 bit parity */
+        self.link.apply_ticks(cycles);
+        // may send several bytes, if ticks are batched
+        let mut cycles = cycles;
+        loop {
+            if self.transmit_cooldown > 0 {
+                let elapsed = i32::min(self.transmit_cooldown, cycles);
+                self.transmit_cooldown -= elapsed;
+                cycles -= elapsed;
+                if self.transmit_cooldown > 0 { break }
+                if self.tx_fifo.is_empty() {
+                    // TEMT
+                    self.link.on_tx_complete();
+                    self.flush_tx_log();
+                }
+            }
+            if self.tx_fifo.is_empty() { break }
+
+            let val = self.tx_fifo.remove(0);
+            // actually send. a panicking link (eg a host bridge with a
+            // poisoned mutex) loses the byte, not the emulator
+            let link = &mut self.link;
+            if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| link.send(val))).is_err() {
+                self.tx_send_failures += 1;
+            }
+            if let Some(log) = self.tx_log.as_mut() {
+                // best effort: a failing log mustn't stop the guest
+                let _ = log.write_all(&[val]);
             }
+            if let Some(captured) = self.tx_captured.as_mut() {
+                captured.push(val);
+            }
+            self.transmit_cooldown += self.byte_transmit_cycles();
         }
     }
 
+    fn byte_transmit_cycles(&self) -> i32 {
+        /* XXX 9 = 8bits data, 1 bit parity */
+        (self.brg_div as i32).saturating_mul(16 * 9).min(self.max_transmit_cooldown)
+    }
+
+    /**
+     * Cap the cycles each transmitted byte takes, so a huge baud rate
+     * divisor can't all but stall the UART. The default cap is
+     * DEFAULT_MAX_TRANSMIT_COOLDOWN (300 baud).
+     */
+    pub fn set_max_transmit_cooldown(&mut self, cycles: i32) {
+        self.max_transmit_cooldown = cycles.max(0);
+    }
+
+    /**
+     * Mirror every transmitted byte to `file`. Writes are buffered, and
+     * flushed whenever the transmitter goes idle and when the log is
+     * replaced or the UART dropped.
+     */
+    pub fn set_tx_log(&mut self, file: Option<std::fs::File>) {
+        self.flush_tx_log();
+        self.tx_log = file.map(std::io::BufWriter::new);
+    }
+
+    fn flush_tx_log(&mut self) {
+        if let Some(log) = self.tx_log.as_mut() {
+            let _ = log.flush();
+        }
+    }
+
+    /**
+     * Keep a copy of each byte transmitted, for take_tx_captured().
+     * Disabling discards anything not yet taken.
+     */
+    pub fn set_tx_capture(&mut self, enable: bool) {
+        self.tx_captured = if enable { Some(vec![]) } else { None };
+    }
+
+    pub fn take_tx_captured(&mut self) -> Vec<u8> {
+        self.tx_captured.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
     pub fn send_byte(&mut self, value: u8) {
-        if (self.tx_fifo.len() < 16 && self.fctl & FCTL_FIFOEN != 0) ||
-           (self.tx_fifo.is_empty() && self.fctl & FCTL_FIFOEN == 0) {
+        if !self.tx_fifo_full() || self.link.on_tx_overflow(value) {
             self.tx_fifo.push(value);
         } else {
             // drop the data. the ez80 was pushing data too fast
+            self.tx_dropped += 1;
+        }
+    }
+
+    pub fn tx_fifo_full(&self) -> bool {
+        if self.fctl & FCTL_FIFOEN != 0 {
+            self.tx_fifo.len() >= 16
+        } else {
+            !self.tx_fifo.is_empty()
         }
     }
 
+    pub fn tx_dropped(&self) -> u64 {
+        self.tx_dropped
+    }
+
+    /// Number of bytes lost to the link's send() panicking
+    pub fn tx_send_failures(&self) -> u64 {
+        self.tx_send_failures
+    }
+
     pub fn maybe_fill_rx_buf(&mut self) -> Option<u8> {
         if self.rx_buf == None {
             self.rx_buf = self.link.recv();
+            if self.rx_buf.is_some() {
+                self.line_errors |= self.injected_errors.take().unwrap_or(0);
+            }
         }
         self.rx_buf
     }
 
+    /**
+     * Report `kind` of errors in LSR with the next byte received, to
+     * exercise the guest's error recovery. They stay set until LSR is read.
+     */
+    pub fn inject_malformed_frame(&mut self, kind: FrameErrors) {
+        self.injected_errors = Some(kind.lsr_bits());
+    }
+
     pub fn receive_byte(&mut self) -> u8 {
         // uart0 receive
         self.maybe_fill_rx_buf();
@@ -73,15 +259,37 @@ impl Uart {
         self.rx_buf = None;
 
         match maybe_data {
-            Some(data) => data,
-            None => 0
+            Some(data) => {
+                self.last_rx = data;
+                data
+            }
+            None => match self.empty_rx_read {
+                EmptyRxRead::Zero => 0,
+                EmptyRxRead::LastByte => self.last_rx,
+                EmptyRxRead::Value(v) => v,
+            }
         }
     }
 
+    pub fn set_empty_rx_read(&mut self, mode: EmptyRxRead) {
+        self.empty_rx_read = mode;
+    }
+
     /** line status register */
     pub fn read_lsr(&mut self) -> u8 {
+        self.maybe_fill_rx_buf();
+        let lsr = self.peek_lsr();
+        // error bits are cleared by reading
+        self.line_errors = 0;
+        lsr
+    }
+
+    // LSR without polling the link or clearing errors
+    fn peek_lsr(&self) -> u8 {
         // 0x01 = DR (data ready: ie can receive)
-        (if self.maybe_fill_rx_buf().is_some() { 1 } else { 0 }) |
+        (if self.rx_buf.is_some() { 1 } else { 0 }) |
+        // 0x02 = OE, 0x04 = PE, 0x08 = FE, 0x10 = BI
+        self.line_errors |
         // 0x20 = TRHE (fifo / transmit  holding register empty)
         (if self.tx_fifo.is_empty() { 0x20 } else { 0 }) |
         // 0x40 = TEMT (fifo / transmit holding register empty & transmitter idle)
@@ -92,6 +300,10 @@ impl Uart {
         self.fctl = val;
     }
 
+    pub fn fctl(&self) -> u8 {
+        self.fctl
+    }
+
     pub fn read_modem_status_register(&mut self) -> u8 {
         if self.link.read_clear_to_send() { 0x10 } else { 0 }
     }
@@ -106,7 +318,283 @@ impl Uart {
         self.lctl & 0x80 != 0
     }
 
+    // the first two UART registers are shared with the baud rate
+    // generator's divisor, BRG_DLR_L and BRG_DLR_H, which are accessed in
+    // their place while LCTL bit 7 (DLAB) is set. as on hardware the two
+    // divisor bytes are independent, so can be written in either order,
+    // and writes with DLAB clear go to THR and IER, never the divisor
+
+    /// UART_RBR, or BRG_DLR_L with DLAB set
+    pub fn read_rbr_or_brg_low(&mut self) -> u8 {
+        if self.is_access_brg_registers() { self.brg_div as u8 } else { self.receive_byte() }
+    }
+
+    /// UART_IER, or BRG_DLR_H with DLAB set
+    pub fn read_ier_or_brg_high(&self) -> u8 {
+        if self.is_access_brg_registers() { (self.brg_div >> 8) as u8 } else { self.ier }
+    }
+
+    /// UART_THR, or BRG_DLR_L with DLAB set
+    pub fn write_thr_or_brg_low(&mut self, value: u8) {
+        if self.is_access_brg_registers() {
+            self.brg_div = (self.brg_div & 0xff00) | value as u16;
+        } else {
+            self.send_byte(value);
+        }
+    }
+
+    /// UART_IER, or BRG_DLR_H with DLAB set
+    pub fn write_ier_or_brg_high(&mut self, value: u8) {
+        if self.is_access_brg_registers() {
+            self.brg_div = (self.brg_div & 0xff) | (value as u16) << 8;
+        } else {
+            self.ier = value;
+        }
+    }
+
+    /**
+     * The registers as (name, offset from the UART's base port, current
+     * value, description), for register inspectors. Reading them here has
+     * no side effects, so RBR and MSR, whose reads do, are left out. The
+     * divisor bytes are listed at the offsets they share under DLAB.
+     */
+    pub fn register_map(&self) -> Vec<(&'static str, u8, u8, &'static str)> {
+        vec![
+            ("IER", 1, self.ier, "Interrupt enable"),
+            ("FCTL", 2, self.fctl, "FIFO control (as last written)"),
+            ("LCTL", 3, self.lctl, "Line control. Bit 7 (DLAB) maps BRG_DLR_L/H at offsets 0 and 1"),
+            ("LSR", 5, self.peek_lsr(), "Line status"),
+            ("SPR", 7, self.spr, "Scratch pad"),
+            ("BRG_DLR_L", 0, self.brg_div as u8, "Baud rate divisor, low byte"),
+            ("BRG_DLR_H", 1, (self.brg_div >> 8) as u8, "Baud rate divisor, high byte"),
+        ]
+    }
+
     pub fn is_rx_interrupt_enabled(&self) -> bool {
         self.ier & 1 != 0
     }
+
+    pub fn is_tx_interrupt_enabled(&self) -> bool {
+        self.ier & 2 != 0
+    }
+
+    /// True if a received byte is waiting in the receive buffer. Unlike
+    /// maybe_fill_rx_buf this doesn't poll the link.
+    pub fn has_rx_data(&self) -> bool {
+        self.rx_buf.is_some()
+    }
+
+    pub fn is_tx_fifo_empty(&self) -> bool {
+        self.tx_fifo.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Uart, SerialLink, EmptyRxRead, FrameErrors, DEFAULT_MAX_TRANSMIT_COOLDOWN, FCTL_FIFOEN };
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct LinkLog {
+        sent: Vec<u8>,
+        tx_complete: u32,
+        // if set, the link takes overflowing bytes
+        accept_overflow: bool,
+        overflowed: Vec<u8>,
+        rx: std::collections::VecDeque<u8>,
+    }
+
+    struct TestLink(Rc<RefCell<LinkLog>>);
+
+    impl SerialLink for TestLink {
+        fn send(&mut self, byte: u8) { self.0.borrow_mut().sent.push(byte); }
+        fn recv(&mut self) -> Option<u8> { self.0.borrow_mut().rx.pop_front() }
+        fn read_clear_to_send(&mut self) -> bool { true }
+        fn on_tx_complete(&mut self) { self.0.borrow_mut().tx_complete += 1; }
+        fn on_tx_overflow(&mut self, byte: u8) -> bool {
+            let mut log = self.0.borrow_mut();
+            if log.accept_overflow { log.overflowed.push(byte); }
+            log.accept_overflow
+        }
+    }
+
+    fn test_uart() -> (Uart, Rc<RefCell<LinkLog>>) {
+        let log = Rc::new(RefCell::new(LinkLog::default()));
+        (Uart::new(Box::new(TestLink(log.clone()))), log)
+    }
+
+    #[test]
+    fn test_tx_complete_after_cooldown() {
+        let (mut uart, log) = test_uart();
+        uart.send_byte(0x41);
+        uart.apply_ticks(0);
+        assert_eq!(log.borrow().sent, vec![0x41]);
+        // THRE, but not TEMT
+        assert_eq!(uart.read_lsr() & 0x60, 0x20);
+
+        let cooldown = uart.brg_div as i32 * 16 * 9;
+        uart.apply_ticks(cooldown - 1);
+        assert_eq!(log.borrow().tx_complete, 0);
+        uart.apply_ticks(1);
+        assert_eq!(log.borrow().tx_complete, 1);
+        assert_eq!(uart.read_lsr() & 0x60, 0x60);
+
+        // idle transmitter doesn't signal again
+        uart.apply_ticks(cooldown);
+        assert_eq!(log.borrow().tx_complete, 1);
+    }
+
+    #[test]
+    fn test_register_map() {
+        let (mut uart, _log) = test_uart();
+        uart.ier = 0x01;
+        uart.write_fctl(0x07);
+        uart.lctl = 0x83;
+        uart.write_thr_or_brg_low(0x34);
+        uart.write_ier_or_brg_high(0x12);
+        uart.lctl = 0x03;
+
+        let map = uart.register_map();
+        let value = |name: &str| map.iter().find(|r| r.0 == name).map(|r| (r.1, r.2));
+        assert_eq!(value("IER"), Some((1, 0x01)));
+        assert_eq!(value("LCTL"), Some((3, 0x03)));
+        assert_eq!(value("FCTL"), Some((2, 0x07)));
+        assert_eq!(value("BRG_DLR_L"), Some((0, 0x34)));
+        assert_eq!(value("BRG_DLR_H"), Some((1, 0x12)));
+        // THRE and TEMT
+        assert_eq!(value("LSR"), Some((5, 0x60)));
+        assert!(map.iter().all(|r| !r.3.is_empty()));
+    }
+
+    #[test]
+    fn test_brg_divisor_writes() {
+        let (mut uart, log) = test_uart();
+
+        // DLAB set: high byte first works as well as low first
+        uart.lctl = 0x83;
+        uart.write_ier_or_brg_high(0x12);
+        uart.write_thr_or_brg_low(0x34);
+        assert_eq!(uart.brg_div, 0x1234);
+        assert_eq!((uart.read_ier_or_brg_high(), uart.read_rbr_or_brg_low()), (0x12, 0x34));
+        assert_eq!(uart.ier, 0);
+        uart.apply_ticks(0);
+        assert!(log.borrow().sent.is_empty());
+
+        // DLAB clear: the same writes reach IER and THR
+        uart.lctl = 0x03;
+        uart.write_ier_or_brg_high(0x01);
+        uart.write_thr_or_brg_low(0x41);
+        assert_eq!(uart.brg_div, 0x1234);
+        assert_eq!(uart.read_ier_or_brg_high(), 0x01);
+        uart.apply_ticks(0);
+        assert_eq!(log.borrow().sent, vec![0x41]);
+    }
+
+    #[test]
+    fn test_inject_malformed_frame() {
+        let (mut uart, log) = test_uart();
+        uart.inject_malformed_frame(FrameErrors { framing: true, parity: true, ..Default::default() });
+        // nothing received yet
+        assert_eq!(uart.read_lsr() & 0x1f, 0);
+
+        log.borrow_mut().rx.extend([0x41, 0x42]);
+        assert_eq!(uart.read_lsr() & 0x1f, 0x01 | 0x04 | 0x08);
+        assert_eq!(uart.read_lsr() & 0x1f, 0x01);
+        assert_eq!(uart.receive_byte(), 0x41);
+
+        // only the one byte is affected
+        assert_eq!(uart.read_lsr() & 0x1f, 0x01);
+        assert_eq!(uart.receive_byte(), 0x42);
+    }
+
+    #[test]
+    fn test_send_panic() {
+        struct PanickingLink(Rc<RefCell<Vec<u8>>>);
+        impl SerialLink for PanickingLink {
+            fn send(&mut self, byte: u8) {
+                if byte == 0xff { panic!("host bridge failed") }
+                self.0.borrow_mut().push(byte);
+            }
+            fn recv(&mut self) -> Option<u8> { None }
+            fn read_clear_to_send(&mut self) -> bool { true }
+        }
+        let sent = Rc::new(RefCell::new(vec![]));
+        let mut uart = Uart::new(Box::new(PanickingLink(sent.clone())));
+        uart.write_fctl(FCTL_FIFOEN);
+        for b in [1, 0xff, 2] {
+            uart.send_byte(b);
+        }
+        uart.apply_ticks(uart.byte_transmit_cycles() * 3);
+        assert_eq!(*sent.borrow(), vec![1, 2]);
+        assert_eq!(uart.tx_send_failures(), 1);
+        assert!(uart.is_tx_fifo_empty());
+    }
+
+    #[test]
+    fn test_tx_fifo_full() {
+        let (mut uart, log) = test_uart();
+        uart.write_fctl(super::FCTL_FIFOEN);
+        for i in 0..16 {
+            assert!(!uart.tx_fifo_full());
+            uart.send_byte(i);
+        }
+        assert!(uart.tx_fifo_full());
+
+        // no taker: dropped and counted
+        uart.send_byte(16);
+        assert_eq!(uart.tx_dropped(), 1);
+
+        // the link reports back-pressure, and the byte is kept
+        log.borrow_mut().accept_overflow = true;
+        uart.send_byte(17);
+        assert_eq!(log.borrow().overflowed, vec![17]);
+        assert_eq!(uart.tx_dropped(), 1);
+
+        // draining makes room again, and nothing accepted was lost
+        let cooldown = uart.brg_div as i32 * 16 * 9;
+        uart.apply_ticks(0);
+        assert!(uart.tx_fifo_full());
+        uart.apply_ticks(cooldown);
+        assert!(!uart.tx_fifo_full());
+        for _ in 0..16 {
+            uart.apply_ticks(cooldown);
+        }
+        assert_eq!(log.borrow().sent, (0..16).chain(17..18).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_huge_brg_div() {
+        let (mut uart, log) = test_uart();
+        uart.brg_div = 0xffff;
+        for b in [1, 2, 3] {
+            uart.send_byte(b);
+            uart.apply_ticks(DEFAULT_MAX_TRANSMIT_COOLDOWN);
+        }
+        assert_eq!(log.borrow().sent, vec![1, 2, 3]);
+        assert_eq!(uart.read_lsr() & 0x40, 0x40);
+
+        uart.set_max_transmit_cooldown(100);
+        uart.send_byte(4);
+        uart.apply_ticks(100);
+        assert_eq!(uart.read_lsr() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn test_empty_rx_read() {
+        let (mut uart, log) = test_uart();
+        log.borrow_mut().rx.push_back(0x5a);
+        assert_eq!(uart.receive_byte(), 0x5a);
+        assert_eq!(uart.receive_byte(), 0);
+
+        uart.set_empty_rx_read(EmptyRxRead::LastByte);
+        assert_eq!(uart.receive_byte(), 0x5a);
+
+        uart.set_empty_rx_read(EmptyRxRead::Value(0xff));
+        assert_eq!(uart.receive_byte(), 0xff);
+        // data still wins when there is some
+        log.borrow_mut().rx.push_back(0x12);
+        assert_eq!(uart.receive_byte(), 0x12);
+        assert_eq!(uart.receive_byte(), 0xff);
+    }
 }
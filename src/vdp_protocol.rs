@@ -0,0 +1,198 @@
+//! Decoding the byte stream MOS sends the VDP (VDU codes) into typed
+//! commands, for front-ends emulating the VDP.
+//!
+//! VDU 23, 0 system commands vary in length by command; those not known
+//! here are decoded with no arguments, so any arguments that follow come
+//! out as further commands.
+
+/// A MOS-to-VDP command. Coordinates are as sent: signed 16-bit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VdpCommand {
+    /// Printable character (32-126, 128-255)
+    Char(u8),
+    Null,
+    SendToPrinter(u8),
+    PrinterOn,
+    PrinterOff,
+    TextAtTextCursor,
+    TextAtGraphicsCursor,
+    EnableScreen,
+    Bell,
+    CursorLeft,
+    CursorRight,
+    CursorDown,
+    CursorUp,
+    Cls,
+    CarriageReturn,
+    PagedModeOn,
+    PagedModeOff,
+    Clg,
+    Colour(u8),
+    GColour { mode: u8, colour: u8 },
+    DefineColour { logical: u8, physical: u8, r: u8, g: u8, b: u8 },
+    ResetColours,
+    DisableScreen,
+    SetMode(u8),
+    /// VDU 23, 0, command, args...
+    System { command: u8, args: Vec<u8> },
+    /// VDU 23, 1, n
+    CursorEnable(bool),
+    /// VDU 23, n, 8 bytes: redefine character n, or other VDU 23 commands
+    Vdu23 { n: u8, data: [u8; 8] },
+    GraphicsViewport { left: i16, bottom: i16, right: i16, top: i16 },
+    Plot { mode: u8, x: i16, y: i16 },
+    ResetViewports,
+    Escape(u8),
+    TextViewport { left: u8, bottom: u8, right: u8, top: u8 },
+    GraphicsOrigin { x: i16, y: i16 },
+    CursorHome,
+    TabTo { x: u8, y: u8 },
+    Delete,
+}
+
+// bytes after 23, 0, command, where known
+fn system_args_len(command: u8, rest: &[u8]) -> Option<usize> {
+    match command {
+        // general poll, keyboard locale
+        0x80 | 0x81 => Some(1),
+        // cursor position, mode info, terminal mode
+        0x82 | 0x86 | 0xff => Some(0),
+        // ascii code at / pixel at: x, y (16 bit)
+        0x83 | 0x84 => Some(4),
+        // RTC: 0 (read), or 1 and 6 bytes of time (set)
+        0x87 => rest.first().map(|mode| if *mode == 1 { 7 } else { 1 }),
+        // keyboard: delay (16 bit), rate (16 bit), LEDs
+        0x88 => Some(5),
+        _ => Some(0)
+    }
+}
+
+// the length of the packet at the start of `buf`, or None if more bytes
+// are needed to tell
+fn packet_len(buf: &[u8]) -> Option<usize> {
+    Some(match buf[0] {
+        1 | 17 | 22 | 27 => 2,
+        18 | 31 => 3,
+        28 | 29 => 5,
+        19 | 25 => 6,
+        24 => 9,
+        23 => match buf.get(1)? {
+            0 => 3 + system_args_len(*buf.get(2)?, &buf[3..])?,
+            1 => 3,
+            _ => 10,
+        }
+        _ => 1
+    })
+}
+
+fn i16_at(buf: &[u8], i: usize) -> i16 {
+    i16::from_le_bytes([buf[i], buf[i + 1]])
+}
+
+// a complete packet: as sized by packet_len
+fn parse(p: &[u8]) -> VdpCommand {
+    match p[0] {
+        0 => VdpCommand::Null,
+        1 => VdpCommand::SendToPrinter(p[1]),
+        2 => VdpCommand::PrinterOn,
+        3 => VdpCommand::PrinterOff,
+        4 => VdpCommand::TextAtTextCursor,
+        5 => VdpCommand::TextAtGraphicsCursor,
+        6 => VdpCommand::EnableScreen,
+        7 => VdpCommand::Bell,
+        8 => VdpCommand::CursorLeft,
+        9 => VdpCommand::CursorRight,
+        10 => VdpCommand::CursorDown,
+        11 => VdpCommand::CursorUp,
+        12 => VdpCommand::Cls,
+        13 => VdpCommand::CarriageReturn,
+        14 => VdpCommand::PagedModeOn,
+        15 => VdpCommand::PagedModeOff,
+        16 => VdpCommand::Clg,
+        17 => VdpCommand::Colour(p[1]),
+        18 => VdpCommand::GColour { mode: p[1], colour: p[2] },
+        19 => VdpCommand::DefineColour { logical: p[1], physical: p[2], r: p[3], g: p[4], b: p[5] },
+        20 => VdpCommand::ResetColours,
+        21 => VdpCommand::DisableScreen,
+        22 => VdpCommand::SetMode(p[1]),
+        23 => match p[1] {
+            0 => VdpCommand::System { command: p[2], args: p[3..].to_vec() },
+            1 => VdpCommand::CursorEnable(p[2] != 0),
+            n => VdpCommand::Vdu23 { n, data: p[2..10].try_into().unwrap() },
+        }
+        24 => VdpCommand::GraphicsViewport { left: i16_at(p, 1), bottom: i16_at(p, 3), right: i16_at(p, 5), top: i16_at(p, 7) },
+        25 => VdpCommand::Plot { mode: p[1], x: i16_at(p, 2), y: i16_at(p, 4) },
+        26 => VdpCommand::ResetViewports,
+        27 => VdpCommand::Escape(p[1]),
+        28 => VdpCommand::TextViewport { left: p[1], bottom: p[2], right: p[3], top: p[4] },
+        29 => VdpCommand::GraphicsOrigin { x: i16_at(p, 1), y: i16_at(p, 3) },
+        30 => VdpCommand::CursorHome,
+        31 => VdpCommand::TabTo { x: p[1], y: p[2] },
+        127 => VdpCommand::Delete,
+        c => VdpCommand::Char(c),
+    }
+}
+
+/// Turns the UART0 transmit stream into VdpCommands. Bytes of incomplete
+/// packets are held until the rest arrives.
+#[derive(Default)]
+pub struct VdpDecoder {
+    buf: Vec<u8>,
+}
+
+impl VdpDecoder {
+    pub fn new() -> Self {
+        VdpDecoder::default()
+    }
+
+    /// Add one byte, returning the command it completes, if any
+    pub fn push(&mut self, byte: u8) -> Option<VdpCommand> {
+        self.buf.push(byte);
+        match packet_len(&self.buf) {
+            Some(len) if self.buf.len() == len => {
+                let command = parse(&self.buf);
+                self.buf.clear();
+                Some(command)
+            }
+            _ => None
+        }
+    }
+
+    /// Add `bytes`, returning the commands they complete
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<VdpCommand> {
+        bytes.iter().filter_map(|b| self.push(*b)).collect()
+    }
+
+    /// Bytes of a partly received packet
+    pub fn pending(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = VdpDecoder::new();
+        assert_eq!(decoder.decode(&[22]), vec![]);
+        assert_eq!(decoder.pending(), &[22]);
+        assert_eq!(decoder.decode(&[3, b'A']), vec![VdpCommand::SetMode(3), VdpCommand::Char(b'A')]);
+        assert!(decoder.pending().is_empty());
+
+        // split mid-packet: plot 4, -1, 300; then an RTC read and set
+        assert_eq!(decoder.decode(&[25, 4, 0xff, 0xff]), vec![]);
+        assert_eq!(decoder.decode(&[0x2c, 0x01, 23, 0, 0x87, 0, 23, 0, 0x87, 1, 24, 1, 2, 3, 4, 5]), vec![
+            VdpCommand::Plot { mode: 4, x: -1, y: 300 },
+            VdpCommand::System { command: 0x87, args: vec![0] },
+            VdpCommand::System { command: 0x87, args: vec![1, 24, 1, 2, 3, 4, 5] },
+        ]);
+
+        assert_eq!(decoder.decode(&[23, 65, 1, 2, 3, 4, 5, 6, 7, 8, 13, 10]), vec![
+            VdpCommand::Vdu23 { n: 65, data: [1, 2, 3, 4, 5, 6, 7, 8] },
+            VdpCommand::CarriageReturn,
+            VdpCommand::CursorDown,
+        ]);
+    }
+}
@@ -0,0 +1,88 @@
+//! A headless stand-in for the VDP: answers the requests MOS makes while
+//! booting, and collects the text it prints.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use crate::uart::SerialLink;
+use crate::vdp_protocol::{ VdpCommand, VdpDecoder };
+
+pub struct VdpAutoResponder {
+    // text MOS has printed, shared with whoever is watching it
+    output: Rc<RefCell<Vec<u8>>>,
+    decoder: VdpDecoder,
+    responses: VecDeque<u8>,
+}
+
+impl VdpAutoResponder {
+    pub fn new(output: Rc<RefCell<Vec<u8>>>) -> Self {
+        VdpAutoResponder { output, decoder: VdpDecoder::new(), responses: VecDeque::new() }
+    }
+
+    /// Queue `bytes` to be received by MOS, after any pending responses:
+    /// eg keyboard packets, to type at it
+    pub fn queue_input(&mut self, bytes: &[u8]) {
+        self.responses.extend(bytes);
+    }
+
+    // answer the VDU 23, 0 system commands MOS waits on. others are ignored
+    fn system_command(&mut self, command: u8, args: &[u8]) {
+        match (command, args) {
+            // general poll. echo back the sent byte
+            (0x80, [n]) => self.respond(&[0x80, 1, *n]),
+            // video mode info: 640x400, 80x25 text, 1 colour
+            (0x86, []) => self.respond(&[0x86, 7, 0x80, 2, 0x90, 1, 80, 25, 1]),
+            // read RTC. all zeros
+            (0x87, [0]) => self.respond(&[0x87, 6, 0, 0, 0, 0, 0, 0]),
+            _ => {}
+        }
+    }
+
+    fn respond(&mut self, packet: &[u8]) {
+        self.responses.extend(packet);
+    }
+}
+
+impl SerialLink for VdpAutoResponder {
+    fn send(&mut self, byte: u8) {
+        match self.decoder.push(byte) {
+            Some(VdpCommand::Char(c)) => self.output.borrow_mut().push(c),
+            Some(VdpCommand::CarriageReturn) => self.output.borrow_mut().push(b'\r'),
+            Some(VdpCommand::CursorDown) => self.output.borrow_mut().push(b'\n'),
+            Some(VdpCommand::System { command, args }) => self.system_command(command, &args),
+            _ => {}
+        }
+    }
+
+    fn recv(&mut self) -> Option<u8> {
+        self.responses.pop_front()
+    }
+
+    fn read_clear_to_send(&mut self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vdp_auto_responder() {
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut vdp = VdpAutoResponder::new(output.clone());
+        // colour 7, "OK", general poll 0x55, cursor to 1,2
+        for b in [17, 7, b'O', b'K', 23, 0, 0x80, 0x55, 31, 1, 2, b'\r', b'\n'] {
+            vdp.send(b);
+        }
+        assert_eq!(*output.borrow(), b"OK\r\n");
+        assert_eq!([vdp.recv(), vdp.recv(), vdp.recv(), vdp.recv()], [Some(0x80), Some(1), Some(0x55), None]);
+
+        // arguments of redefine character, set RTC, VDU 1 and VDU 27 aren't text
+        output.borrow_mut().clear();
+        for b in [23, 65, b'a', b'b', b'c', b'd', b'e', b'f', b'g', b'h', 23, 0, 0x87, 1, b'2', b'4', 1, 2, 3, 4,
+                  1, b'p', 27, b'e', b'O', b'K'] {
+            vdp.send(b);
+        }
+        assert_eq!(*output.borrow(), b"OK");
+        assert_eq!(vdp.recv(), None);
+    }
+}